@@ -0,0 +1,63 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+
+use crate::Config;
+
+/// Service name under which values are stored in the OS secret store
+const KEYRING_SERVICE: &str = "verishda";
+
+/// A `Config` reading values from the OS secret store (Keychain on macOS,
+/// Credential Manager on Windows, Secret Service on Linux), so that secrets
+/// don't need to be exposed as environment variables.
+/// 
+/// Any key can be read, but only the keys given on construction can be
+/// set, which allows operators to seed secrets once. Secret stores are
+/// usually not available on headless machines, which is why this is only
+/// built with the `keyring` feature. Layer it above other configs using
+/// [crate::CompositeConfig]:
+/// ```ignore
+/// let config = CompositeConfig::from_configs(
+///     Box::new(KeyringConfig::with_settable_keys(&["PG_ADDRESS"])),
+///     Box::new(EnvConfig::from_env()),
+/// );
+/// ```
+#[derive(Clone)]
+pub struct KeyringConfig {
+    settable_keys: HashSet<String>,
+}
+
+impl KeyringConfig {
+    pub fn with_settable_keys(keys: &[&str]) -> KeyringConfig {
+        KeyringConfig {
+            settable_keys: keys.iter().map(|k|k.to_string()).collect(),
+        }
+    }
+}
+
+impl Config for KeyringConfig {
+    fn supported_settable_keys(&self) -> HashSet<&str> {
+        self.settable_keys.iter().map(String::as_str).collect()
+    }
+
+    fn get(&self, key: &str) -> Result<String> {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, key)?;
+        match entry.get_password() {
+            Ok(value) => Ok(value),
+            Err(keyring::Error::NoEntry) => Err(anyhow!("no such key {key} in keyring")),
+            Err(e) => Err(anyhow!("cannot read key {key} from keyring: {e}")),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        if !self.settable_keys.contains(key) {
+            return Err(anyhow!("key '{key}' can not be set in keyring config"));
+        }
+        keyring::Entry::new(KEYRING_SERVICE, key)?.set_password(value)?;
+        Ok(())
+    }
+
+    fn clone_box_dyn(&self) -> Box<dyn Config> {
+        Box::new(self.clone())
+    }
+}
@@ -1,9 +1,17 @@
 
 use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use anyhow::{Result, anyhow};
 use dotenv::*;
 
+#[cfg(feature = "keyring")]
+mod keyring_config;
+#[cfg(feature = "keyring")]
+pub use keyring_config::KeyringConfig;
+
 
 /// The `Config` trait allows access to process-wide configuration.
 /// Configuration items can be pulled from a mix of various sources:
@@ -33,6 +41,19 @@ pub trait Config: Send + Sync{
     }
 
     fn get(&self, key: &str) -> Result<String>;
+    /// Check that all of the given keys are configured, returning an
+    /// error listing every missing key otherwise
+    fn require_all(&self, keys: &[&str]) -> Result<()> {
+        let missing: Vec<&str> = keys.iter()
+            .filter(|key| self.get(key).is_err())
+            .copied()
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("missing required configuration: {}", missing.join(", ")))
+        }
+    }
     fn set(&mut self, _key: &str, _value: &str) -> Result<()> {
         panic!("set operation not implemented, check if setting config properties is supported via supported_settable_keys() and supports_setting_any_key() methods")
     }
@@ -46,9 +67,34 @@ pub trait Config: Send + Sync{
     fn set_as_bool(&mut self, key: &str, value: bool) -> Result<()> {
         self.set(key, &value.to_string())
     }
+    /// Read an integer, falling back to `default` if the key is missing
+    /// or its value is not an integer
+    fn get_as_int_or(&self, key: &str, default: i64) -> i64 {
+        self.get(key).ok().and_then(|s|s.trim().parse().ok()).unwrap_or(default)
+    }
+    /// Read a duration given in seconds (`300`) or with a unit suffix of
+    /// `s`, `m`, `h` or `d` (`5m`), falling back to `default` if the key 
+    /// is missing or its value is not a duration
+    fn get_as_duration_or(&self, key: &str, default: Duration) -> Duration {
+        self.get(key).ok().and_then(|s|parse_duration(&s)).unwrap_or(default)
+    }
 
 }
 
+/// Parse a duration like `300`, `300s`, `5m`, `2h` or `1d`
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (number, unit_secs) = match s.char_indices().last()? {
+        (i, 's') => (&s[..i], 1),
+        (i, 'm') => (&s[..i], 60),
+        (i, 'h') => (&s[..i], 60*60),
+        (i, 'd') => (&s[..i], 24*60*60),
+        _ => (s, 1),
+    };
+    let number = number.trim().parse::<u64>().ok()?;
+    Some(Duration::from_secs(number.checked_mul(unit_secs)?))
+}
+
 impl Clone for Box<dyn Config> {
     fn clone(&self) -> Self {
         self.clone_box_dyn()
@@ -121,8 +167,20 @@ impl Config for CompositeConfig {
 
 
 
+/// Prefix the verishda binaries use to namespace their environment
+/// variables, as in `VERISHDA_CLIENT_ID`. See [EnvConfig::with_prefix]
+pub const ENV_PREFIX: &str = "VERISHDA";
+
+/// A `Config` reading environment variables (including those loaded
+/// from a `.env` file). 
+/// 
+/// With a prefix, a key `KEY` is looked up as `{PREFIX}_KEY` first, 
+/// falling back to the bare `KEY`. This allows namespacing variables
+/// with generic names like `CLIENT_ID` in shared environments.
 #[derive(Clone)]
-pub struct EnvConfig;
+pub struct EnvConfig {
+    prefix: Option<String>,
+}
 
 impl EnvConfig {
     pub fn from_env() -> EnvConfig {
@@ -136,7 +194,15 @@ impl EnvConfig {
             }
         }
 
-        EnvConfig
+        EnvConfig { prefix: None }
+    }
+
+    /// Like [EnvConfig::from_env], but looking up `{prefix}_KEY` before `KEY`
+    pub fn with_prefix(prefix: &str) -> EnvConfig {
+        EnvConfig {
+            prefix: Some(prefix.to_string()),
+            ..Self::from_env()
+        }
     }
 }
 
@@ -160,14 +226,21 @@ pub fn default_config() -> impl Config {
 
 impl Config for EnvConfig{
     fn get(&self, key: &str) -> Result<String> {
+        if let Some(prefix) = &self.prefix {
+            if let Ok(value) = std::env::var(format!("{prefix}_{key}")) {
+                return Ok(value);
+            }
+        }
         std::env::var(key).map_err(|_| anyhow!("no such environment variable {key}"))
     }
     fn clone_box_dyn(&self) -> Box<dyn Config> {
-        Box::new(self.clone())
+        Box::new(EnvConfig {
+            prefix: self.prefix.clone(),
+        })
     }
 }
 
-struct HashMapConfig {
+pub struct HashMapConfig {
     map: HashMap<String,String>
 }
 
@@ -199,6 +272,144 @@ impl Config for HashMapConfig {
     }
 }
 
+/// A `Config` stored in a file using the same `KEY="value"` format as
+/// `.env` files. Unlike the other `Config` implementations, it supports
+/// setting arbitrary keys, which are written back to the file right away.
+/// 
+/// Clones share the same underlying values, so a key set through one
+/// clone is visible in all others.
+#[derive(Clone)]
+pub struct FileConfig {
+    path: PathBuf,
+    map: Arc<RwLock<HashMap<String,String>>>,
+}
+
+impl FileConfig {
+    /// Load the config from the given path. A missing file is not an 
+    /// error, it simply results in an empty config (the file will be
+    /// created on the first call to `set()`)
+    pub fn from_path(path: &Path) -> Result<FileConfig> {
+        let mut map = HashMap::new();
+        if path.exists() {
+            for item in from_path_iter(path)? {
+                let (k,v) = item?;
+                map.insert(k, v);
+            }
+        }
+        Ok(FileConfig {
+            path: path.to_path_buf(),
+            map: Arc::new(RwLock::new(map)),
+        })
+    }
+
+    fn save(&self, map: &HashMap<String,String>) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut keys = map.keys().collect::<Vec<_>>();
+        keys.sort();
+        let content = keys.iter()
+        .map(|k|{
+            let v = map[*k].replace('\\', "\\\\").replace('"', "\\\"");
+            format!("{k}=\"{v}\"\n")
+        })
+        .collect::<String>();
+        std::fs::write(&self.path, content)?;
+        Ok(())
+    }
+}
+
+impl Config for FileConfig {
+    fn supports_setting_any_key(&self) -> bool {
+        true
+    }
+
+    fn get(&self, key: &str) -> Result<String> {
+        self.map
+        .read()
+        .unwrap()
+        .get(key)
+        .map(String::clone)
+        .ok_or_else(||anyhow!("key '{key}' not found in {}", self.path.display()))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        map.insert(key.to_string(), value.to_string());
+        self.save(&map)
+    }
+
+    fn clone_box_dyn(&self) -> Box<dyn Config> {
+        Box::new(self.clone())
+    }
+}
+
+/// A read-only `Config` loaded from a file containing a JSON object,
+/// as emitted by tooling like Kubernetes ConfigMaps.
+/// 
+/// Nested objects are flattened into dotted keys, so 
+/// `{"db": {"max_connections": 5}}` yields the key `db.max_connections`.
+/// Numbers and booleans are stored as their JSON text, `null` values are
+/// ignored. Arrays can't be represented as a single value and are 
+/// rejected with an error naming the offending key.
+#[derive(Clone)]
+pub struct JsonConfig {
+    path: PathBuf,
+    map: HashMap<String,String>,
+}
+
+impl JsonConfig {
+    /// Load the config from the given path. Unlike [FileConfig::from_path],
+    /// a missing file is an error.
+    pub fn from_path(path: &Path) -> Result<JsonConfig> {
+        let content = std::fs::read_to_string(path)
+        .map_err(|e|anyhow!("could not read {}: {e}", path.display()))?;
+        let map = Self::parse(&content)
+        .map_err(|e|anyhow!("invalid JSON config {}: {e}", path.display()))?;
+        Ok(JsonConfig {
+            path: path.to_path_buf(),
+            map,
+        })
+    }
+
+    fn parse(content: &str) -> Result<HashMap<String,String>> {
+        let value: serde_json::Value = serde_json::from_str(content)?;
+        let serde_json::Value::Object(object) = value else {
+            return Err(anyhow!("top level value must be an object"));
+        };
+        let mut map = HashMap::new();
+        Self::flatten("", object, &mut map)?;
+        Ok(map)
+    }
+
+    fn flatten(prefix: &str, object: serde_json::Map<String,serde_json::Value>, map: &mut HashMap<String,String>) -> Result<()> {
+        for (k,v) in object {
+            let key = if prefix.is_empty() {k} else {format!("{prefix}.{k}")};
+            match v {
+                serde_json::Value::Null => (),
+                serde_json::Value::String(s) => {map.insert(key, s);}
+                serde_json::Value::Bool(_) | serde_json::Value::Number(_) => {map.insert(key, v.to_string());}
+                serde_json::Value::Object(o) => Self::flatten(&key, o, map)?,
+                serde_json::Value::Array(_) => return Err(anyhow!("arrays are not supported (key '{key}')")),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Config for JsonConfig {
+    fn get(&self, key: &str) -> Result<String> {
+        self.map
+        .get(key)
+        .map(String::clone)
+        .ok_or_else(||anyhow!("key '{key}' not found in {}", self.path.display()))
+    }
+
+    fn clone_box_dyn(&self) -> Box<dyn Config> {
+        Box::new(self.clone())
+    }
+}
+
 #[test]
 fn test_default_composite_config() {
 
@@ -210,3 +421,96 @@ fn test_default_composite_config() {
     assert_eq!(config.get("ISSUER_URL").unwrap(), PUBLIC_ISSUER_URL);
     assert_eq!(config.get("CLIENT_ID").unwrap(), "test-client");
 }
+
+#[test]
+fn test_file_config_roundtrip() {
+    let path = std::env::temp_dir().join(format!("verishda-config-test-{}.env", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut config = FileConfig::from_path(&path).unwrap();
+    assert!(config.get("SOME_KEY").is_err());
+    config.set("SOME_KEY", "some \"quoted\" value").unwrap();
+    config.set("OTHER_KEY", "42").unwrap();
+
+    let config = FileConfig::from_path(&path).unwrap();
+    assert_eq!(config.get("SOME_KEY").unwrap(), "some \"quoted\" value");
+    assert_eq!(config.get("OTHER_KEY").unwrap(), "42");
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_typed_accessors() {
+    let config = HashMapConfig::from(HashMap::from([
+        ("INT".to_string(), " 42 ".to_string()),
+        ("SECS".to_string(), "300".to_string()),
+        ("MINUTES".to_string(), "5m".to_string()),
+        ("INVALID".to_string(), "often".to_string()),
+        ("NEGATIVE".to_string(), "-5".to_string()),
+        ("ZERO".to_string(), "0".to_string()),
+    ]));
+
+    assert_eq!(config.get_as_int_or("INT", 0), 42);
+    assert_eq!(config.get_as_int_or("INVALID", 7), 7);
+    assert_eq!(config.get_as_int_or("MISSING", -1), -1);
+
+    let default = Duration::from_secs(1);
+    assert_eq!(config.get_as_duration_or("SECS", default), Duration::from_secs(300));
+    assert_eq!(config.get_as_duration_or("MINUTES", default), Duration::from_secs(300));
+    assert_eq!(config.get_as_duration_or("INVALID", default), default);
+    assert_eq!(config.get_as_duration_or("MISSING", default), default);
+    assert_eq!(config.get_as_duration_or("NEGATIVE", default), default);
+    assert_eq!(config.get_as_duration_or("ZERO", default), Duration::ZERO);
+
+    assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+    assert_eq!(parse_duration("1d"), Some(Duration::from_secs(86400)));
+    assert_eq!(parse_duration("10 s"), Some(Duration::from_secs(10)));
+    assert_eq!(parse_duration("m"), None);
+    assert_eq!(parse_duration("-5"), None);
+}
+
+#[test]
+fn test_require_all() {
+    let config = HashMapConfig::from(HashMap::from([
+        ("ISSUER_URL".to_string(), "https://example.com".to_string()),
+    ]));
+
+    assert!(config.require_all(&["ISSUER_URL"]).is_ok());
+    let error = config.require_all(&["ISSUER_URL", "CLIENT_ID", "PG_ADDRESS"]).unwrap_err();
+    assert_eq!(error.to_string(), "missing required configuration: CLIENT_ID, PG_ADDRESS");
+}
+
+#[test]
+fn test_json_config() {
+    let map = JsonConfig::parse(r#"{
+        "CLIENT_ID": "verishda",
+        "DEV_MODE": true,
+        "DB_MAX_CONNECTIONS": 5,
+        "AUDIENCE": null,
+        "db": {"pool": {"size": 3}}
+    }"#).unwrap();
+    assert_eq!(map.len(), 4);
+    assert_eq!(map["CLIENT_ID"], "verishda");
+    assert_eq!(map["DEV_MODE"], "true");
+    assert_eq!(map["DB_MAX_CONNECTIONS"], "5");
+    assert_eq!(map["db.pool.size"], "3");
+
+    let error = JsonConfig::parse(r#"{"ADMIN_SUBJECTS": ["a", "b"]}"#).unwrap_err();
+    assert_eq!(error.to_string(), "arrays are not supported (key 'ADMIN_SUBJECTS')");
+    assert!(JsonConfig::parse("[]").is_err());
+}
+
+#[test]
+fn test_env_config_prefix() {
+    std::env::set_var("VERISHDA_TEST_PREFIXED_KEY", "prefixed");
+    std::env::set_var("TEST_PREFIXED_KEY", "bare");
+    std::env::set_var("TEST_BARE_KEY", "bare");
+
+    let config = EnvConfig::with_prefix("VERISHDA").clone_box_dyn();
+    assert_eq!(config.get("TEST_PREFIXED_KEY").unwrap(), "prefixed");
+    assert_eq!(config.get("TEST_BARE_KEY").unwrap(), "bare");
+    assert!(config.get("TEST_MISSING_KEY").is_err());
+
+    let config = EnvConfig::from_env();
+    assert_eq!(config.get("TEST_PREFIXED_KEY").unwrap(), "bare");
+}
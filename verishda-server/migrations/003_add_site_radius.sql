@@ -0,0 +1,2 @@
+ALTER TABLE sites ADD COLUMN radius REAL;
+UPDATE sites SET radius = 100 WHERE radius IS NULL;
@@ -0,0 +1,6 @@
+CREATE TABLE presence_history (
+    user_id CHAR(36),
+    site_id CHAR(36),
+    seen_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX idx_presence_history_user_id_seen_at ON presence_history (user_id, seen_at);
@@ -0,0 +1,9 @@
+-- announcements are replaced as a whole on each announce, so changes are
+-- tracked per user and site rather than on the announcement rows
+CREATE TABLE presence_updates (
+    user_id CHAR(36),
+    site_id CHAR(36),
+    updated_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (user_id, site_id)
+);
+CREATE INDEX idx_presence_updates_site_id_updated_at ON presence_updates (site_id, updated_at);
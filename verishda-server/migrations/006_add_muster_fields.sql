@@ -0,0 +1,2 @@
+ALTER TABLE user_info ADD COLUMN email VARCHAR(255);
+ALTER TABLE logged_into_site ADD COLUMN source VARCHAR(31);
@@ -0,0 +1,6 @@
+-- presence history entries become sessions spanning from started_at to the
+-- last hello in seen_at
+ALTER TABLE presence_history ADD COLUMN id BIGSERIAL PRIMARY KEY;
+ALTER TABLE presence_history ADD COLUMN started_at TIMESTAMPTZ;
+UPDATE presence_history SET started_at = seen_at;
+ALTER TABLE presence_history ALTER COLUMN started_at SET NOT NULL;
@@ -0,0 +1,2 @@
+-- last date on which a recurring announcement applies, NULL for recurring forever
+ALTER TABLE user_announcements ADD COLUMN recurring_until DATE;
@@ -0,0 +1 @@
+ALTER TABLE logged_into_site ADD COLUMN platform VARCHAR(31);
@@ -0,0 +1,3 @@
+-- corners of the site's geofence as alternating latitudes and longitudes;
+-- if set, the polygon takes precedence over the radius
+ALTER TABLE sites ADD COLUMN polygon REAL[];
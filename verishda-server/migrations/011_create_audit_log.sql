@@ -0,0 +1,10 @@
+-- append-only record of check-ins and announcements, for compliance
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    user_id CHAR(36) NOT NULL,
+    action VARCHAR(31) NOT NULL,
+    site_id CHAR(36),
+    detail TEXT
+);
+CREATE INDEX idx_audit_log_recorded_at ON audit_log (recorded_at);
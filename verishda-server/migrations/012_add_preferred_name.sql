@@ -0,0 +1,3 @@
+-- name the user chose to be shown with, taking precedence over the name
+-- from their token in logged_as_name
+ALTER TABLE user_info ADD COLUMN preferred_name VARCHAR(127);
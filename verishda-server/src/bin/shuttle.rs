@@ -35,7 +35,8 @@ async fn axum(
     // NOTE: We do not initialize logging on shuttle, because the
     // shuttle runtime itself does this for us.
     log::info!("starting up verishda on shuttle");
+    config.require_all(verishda::REQUIRED_CONFIG_KEYS)?;
 
-    let pool = verishda::connect_db(&pg_url).await?;
+    let pool = verishda::connect_db(&pg_url, &config).await?;
     Ok(verishda::build_router(pool, config).into())
 }
@@ -1,6 +1,6 @@
 
 use anyhow::*;
-use verishda_config::{default_config, CompositeConfig, EnvConfig};
+use verishda_config::{default_config, CompositeConfig, Config, EnvConfig, JsonConfig, ENV_PREFIX};
 
 
 #[tokio::main]
@@ -8,26 +8,123 @@ async fn main(){
     let executable_name = std::env::args().next().unwrap_or_else(||"unknown".to_string());
     println!("starting {executable_name}...");
 
+    let env_config = EnvConfig::with_prefix(ENV_PREFIX);
+    let fallback_config: Box<dyn Config> = match env_config.get("CONFIG_JSON").ok() {
+        Some(path) => {
+            let json_config = JsonConfig::from_path(path.as_ref()).unwrap_or_else(|e|{
+                eprintln!("{e}");
+                std::process::exit(1);
+            });
+            Box::new(CompositeConfig::from_configs(Box::new(json_config), Box::new(default_config())))
+        }
+        None => Box::new(default_config()),
+    };
     let config = CompositeConfig::from_configs(
-        Box::new(EnvConfig::from_env()),
-        Box::new(default_config())
+        Box::new(env_config),
+        fallback_config
+    );
+    #[cfg(feature = "keyring")]
+    let config = CompositeConfig::from_configs(
+        Box::new(verishda_config::KeyringConfig::with_settable_keys(&["PG_ADDRESS"])),
+        Box::new(config)
     );
     verishda::init_logging(&config);
 
+    let required_keys = [verishda::REQUIRED_CONFIG_KEYS, &["PG_ADDRESS"][..]].concat();
+    if let Err(e) = config.require_all(&required_keys) {
+        log::error!("{e}");
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+
     log::debug!("connecting to database...");
-    let pg_address = std::env::var("PG_ADDRESS")
-    .expect("no postgres database connection configured, set PG_ADDRESS variable");
-    let pool = verishda::connect_db(&pg_address).await.expect(&format!("could not connect to database {pg_address}"));
+    let pg_address = config.get("PG_ADDRESS").unwrap();
+    let pool = verishda::connect_db(&pg_address, &config).await.expect(&format!("could not connect to database {pg_address}"));
     log::debug!("connected.");
     
     let router = verishda::build_router(pool, config.clone());
     
-    let bind_address = std::env::var("BIND_ADDRESS")
+    let bind_address = config.get("BIND_ADDRESS")
     .unwrap_or_else(|_|"127.0.0.1:3000".to_string());
 
-    let listener = tokio::net::TcpListener::bind(&bind_address).await.unwrap();
-    log::info!("binding, server available under http://{bind_address}");
-    axum::serve(listener, router.into_make_service())
-    .await
-    .unwrap();
+    if let Some(socket_path) = bind_address.strip_prefix("unix:") {
+        serve_unix(socket_path, router).await.unwrap();
+    } else {
+        let listener = tokio::net::TcpListener::bind(&bind_address).await.unwrap();
+        log::info!("binding, server available under http://{bind_address}");
+        axum::serve(listener, router.into_make_service())
+        .with_graceful_shutdown(verishda::shutdown_signal())
+        .await
+        .unwrap();
+    }
+    log::info!("server shut down");
+}
+
+/// Permissions of the socket file, allowing the server's user and group
+/// (like a reverse proxy added to it) to connect
+#[cfg(unix)]
+const SOCKET_MODE: u32 = 0o660;
+
+/// Serve on a Unix domain socket at the given path, for a reverse proxy
+/// on the same host. `axum::serve` only supports TCP, so connections are
+/// served with hyper directly. Like with TCP, no new connections are 
+/// accepted on shutdown, and open ones are waited for.
+#[cfg(unix)]
+async fn serve_unix(socket_path: &str, router: axum::Router) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::graceful::GracefulShutdown;
+    use hyper_util::service::TowerToHyperService;
+
+    // a socket left behind by a previous run would make binding fail
+    if std::fs::symlink_metadata(socket_path).is_ok_and(|m|m.file_type().is_socket()) {
+        std::fs::remove_file(socket_path)
+        .with_context(||format!("cannot remove stale socket {socket_path}"))?;
+    }
+    // the socket is created with the restricted permissions right away, as 
+    // restricting them after binding would let others connect meanwhile
+    let previous_umask = unsafe { libc::umask((!SOCKET_MODE & 0o777) as libc::mode_t) };
+    let listener = tokio::net::UnixListener::bind(socket_path);
+    unsafe { libc::umask(previous_umask) };
+    let listener = listener.with_context(||format!("cannot bind socket {socket_path}"))?;
+    log::info!("binding, server available under unix:{socket_path}");
+
+    let server = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    let shutdown = verishda::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    std::result::Result::Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::error!("cannot accept connection on {socket_path}: {e}");
+                        continue;
+                    }
+                };
+                let service = TowerToHyperService::new(router.clone());
+                let connection = server.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                let connection = graceful.watch(connection.into_owned());
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        log::debug!("failed to serve connection: {e}");
+                    }
+                });
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // stop accepting connections, then let the open ones finish their requests
+    drop(listener);
+    std::fs::remove_file(socket_path)?;
+    log::debug!("waiting for open connections to close...");
+    graceful.shutdown().await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(socket_path: &str, _router: axum::Router) -> Result<()> {
+    Err(anyhow!("cannot bind unix:{socket_path}, Unix domain sockets are not supported on this platform"))
 }
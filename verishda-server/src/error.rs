@@ -1,15 +1,50 @@
+use axum::extract::FromRequest;
+use axum::extract::rejection::JsonRejection;
 use axum::response::{IntoResponse, Response};
+use axum::Json;
 use http::StatusCode;
 
 
-pub struct HandlerError(anyhow::Error)
-where Self: Send
-;
+/// Error returned by handlers. Responds with a JSON body of the form
+/// `{"error": "<code>", "message": "<message>"}`, where the code is derived 
+/// from the status. Errors converted via `?` become `500`s, whose details
+/// are only logged, not sent to the client.
+pub struct HandlerError {
+    status: StatusCode,
+    error: anyhow::Error,
+}
+
+impl HandlerError {
+    /// Error responding with the given status. Unless it's a server error,
+    /// the error's message is sent to the client.
+    pub fn with_status(status: StatusCode, error: impl Into<anyhow::Error>) -> Self {
+        Self { status, error: error.into() }
+    }
+}
+
+/// Machine readable error code for the given status, like `not_found`
+fn error_code(status: StatusCode) -> String {
+    if status == StatusCode::INTERNAL_SERVER_ERROR {
+        return "internal_error".to_string();
+    }
+    status.canonical_reason()
+    .unwrap_or("error")
+    .to_lowercase()
+    .replace([' ', '-'], "_")
+}
 
 impl IntoResponse for HandlerError {
     fn into_response(self) -> Response {
-        let error = self.0;
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{error}")).into_response()
+        let message = if self.status.is_server_error() {
+            log::error!("request failed: {:#}", self.error);
+            self.status.canonical_reason().unwrap_or("server error").to_lowercase()
+        } else {
+            format!("{}", self.error)
+        };
+        (self.status, Json(serde_json::json!({
+            "error": error_code(self.status),
+            "message": message,
+        }))).into_response()
     }
 }
 
@@ -18,6 +53,86 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        Self::with_status(StatusCode::INTERNAL_SERVER_ERROR, err)
+    }
+}
+
+/// Response with a JSON body of the form `{"error": "<message>"}`
+pub(crate) fn json_error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(serde_json::json!({"error": message}))).into_response()
+}
+
+/// Drop-in replacement for `axum::Json` as a request body extractor, which
+/// responds to bodies that cannot be parsed with a JSON error naming the 
+/// cause, instead of axum's plain text rejection.
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(JsonBodyError))]
+pub(crate) struct JsonBody<T>(pub T);
+
+pub(crate) struct JsonBodyError(JsonRejection);
+
+impl From<JsonRejection> for JsonBodyError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self(rejection)
+    }
+}
+
+impl IntoResponse for JsonBodyError {
+    fn into_response(self) -> Response {
+        let status = match &self.0 {
+            JsonRejection::MissingJsonContentType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        json_error_response(status, &self.0.body_text())
+    }
+}
+
+#[tokio::test]
+async fn test_malformed_json_body_is_rejected_with_json_error() {
+    use axum::body::Body;
+    use crate::verishda_dto::types::PresenceAnnouncement;
+
+    async fn reject(body: &'static str) -> (StatusCode, serde_json::Value) {
+        let request = http::Request::builder()
+            .method("PUT")
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        let rejection = match JsonBody::<Vec<PresenceAnnouncement>>::from_request(request, &()).await {
+            Ok(_) => panic!("malformed body was accepted"),
+            Err(rejection) => rejection.into_response(),
+        };
+        let status = rejection.status();
+        let body = axum::body::to_bytes(rejection.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
     }
-}
\ No newline at end of file
+
+    let (status, error) = reject("[{\"date\": ").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(error["error"].is_string());
+
+    let (status, error) = reject("[{\"date\": \"tomorrow\", \"kind\": \"SingularAnnouncement\"}]").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(error["error"].as_str().unwrap().contains("date"));
+}
+
+#[tokio::test]
+async fn test_handler_error_responds_with_json() {
+    async fn respond(error: HandlerError) -> (StatusCode, serde_json::Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    let (status, error) = respond(anyhow::anyhow!("connection to 10.0.0.1 refused").into()).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(error["error"], "internal_error");
+    // internal details are not leaked
+    assert_eq!(error["message"], "internal server error");
+
+    let (status, error) = respond(HandlerError::with_status(StatusCode::NOT_FOUND, anyhow::anyhow!("no such site"))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error["error"], "not_found");
+    assert_eq!(error["message"], "no such site");
+}
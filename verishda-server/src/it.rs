@@ -0,0 +1,720 @@
+//! Integration tests of the `site` module's queries against a Postgres
+//! started in a container. Requires docker and is only compiled with the
+//! `it` feature: `cargo test -p verishda --features it`.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use serde_json::json;
+use sqlx::{postgres::PgRow, PgConnection, PgPool, Row};
+use testcontainers_modules::{postgres::Postgres, testcontainers::{runners::AsyncRunner, ContainerAsync}};
+use tokio::sync::mpsc;
+
+use crate::site;
+use crate::verishda_dto::types::{Presence, PresenceAnnouncement, PresenceAnnouncementKind, Site};
+
+const PRESENCE_TTL: Duration = Duration::from_secs(300);
+const SESSION_MERGE_GAP: Duration = Duration::from_secs(600);
+const SITE_ID: &str = "00000000-0000-0000-0000-0000000000a1";
+const OTHER_SITE_ID: &str = "00000000-0000-0000-0000-0000000000a2";
+const SELF_ID: &str = "00000000-0000-0000-0000-000000000001";
+const OTHER_ID: &str = "00000000-0000-0000-0000-000000000002";
+
+/// A migrated database; the container is removed when this is dropped
+struct TestDb {
+    _container: ContainerAsync<Postgres>,
+    pool: PgPool,
+}
+
+/// Start a database with the site `SITE_ID`
+async fn start_db() -> Result<TestDb> {
+    let container = Postgres::default().start().await?;
+    let url = format!(
+        "postgres://postgres:postgres@{}:{}/postgres",
+        container.get_host().await?,
+        container.get_host_port_ipv4(5432).await?
+    );
+    let pool = PgPool::connect(&url).await?;
+    crate::migrate_db(&pool).await?;
+    insert_site(&mut *pool.acquire().await?, SITE_ID, "Test Site").await?;
+    Ok(TestDb { _container: container, pool })
+}
+
+async fn insert_site(con: &mut PgConnection, site_id: &str, name: &str) -> Result<()> {
+    site::upsert_site(con, &Site {
+        id: site_id.to_string(),
+        name: name.to_string(),
+        longitude: 9.2,
+        latitude: 48.5,
+        radius: 100.,
+        polygon: vec![],
+    }).await
+}
+
+async fn hello(con: &mut PgConnection, user_id: &str, name: &str) -> Result<()> {
+    hello_at(con, user_id, name, SITE_ID).await
+}
+
+async fn hello_at(con: &mut PgConnection, user_id: &str, name: &str, site_id: &str) -> Result<()> {
+    site::hello_site(con, user_id, name, None, site_id, None, None, SESSION_MERGE_GAP, PRESENCE_TTL).await
+}
+
+/// Let the user's last hello lie well beyond `PRESENCE_TTL`
+async fn make_stale(con: &mut PgConnection, user_id: &str) -> Result<()> {
+    sqlx::query("UPDATE logged_into_site SET last_seen = now() - interval '1 hour' WHERE user_id=$1")
+    .bind(user_id)
+    .execute(con).await?;
+    Ok(())
+}
+
+/// The current date in the database, which may differ from the local one
+async fn db_today(con: &mut PgConnection) -> Result<NaiveDate> {
+    Ok(sqlx::query("SELECT (now() AT TIME ZONE 'UTC')::date")
+    .map(|r: PgRow|r.get(0))
+    .fetch_one(con).await?)
+}
+
+fn singular(date: NaiveDate) -> PresenceAnnouncement {
+    PresenceAnnouncement { date, kind: PresenceAnnouncementKind::SingularAnnouncement, recurring_until: None }
+}
+
+fn weekly(date: NaiveDate, recurring_until: Option<NaiveDate>) -> PresenceAnnouncement {
+    PresenceAnnouncement { date, kind: PresenceAnnouncementKind::RecurringAnnouncement, recurring_until }
+}
+
+async fn presences(con: &mut PgConnection, range: std::ops::Range<i32>, term: Option<&str>, favorites_only: bool) -> Result<Vec<Presence>> {
+    site::get_presence_on_site(con, SELF_ID, "Self User", SITE_ID, range, term, favorites_only, false, None, PRESENCE_TTL).await
+}
+
+fn names(presences: &[Presence]) -> Vec<&str> {
+    presences.iter().map(|p|p.logged_as_name.as_str()).collect()
+}
+
+/// Announcements by date, as they're returned in no particular order
+fn dates_and_kinds(announcements: &[PresenceAnnouncement]) -> Vec<(NaiveDate, PresenceAnnouncementKind)> {
+    let mut dates_and_kinds: Vec<_> = announcements.iter().map(|a|(a.date, a.kind)).collect();
+    dates_and_kinds.sort_by_key(|(date, _)|*date);
+    dates_and_kinds
+}
+
+#[tokio::test]
+async fn test_self_user_is_listed_first() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    for (user_id, name) in [
+        ("00000000-0000-0000-0000-000000000002", "Anna"),
+        ("00000000-0000-0000-0000-000000000003", "Bert"),
+        ("00000000-0000-0000-0000-000000000004", "Zora"),
+    ] {
+        hello(&mut con, user_id, name).await?;
+    }
+
+    // the self user is synthesized before saying hello anywhere
+    let all = presences(&mut con, 0..i32::MAX, None, false).await?;
+    assert_eq!(names(&all), vec!["Self User", "Anna", "Bert", "Zora"]);
+    assert!(all[0].is_self && !all[0].currently_present);
+    assert!(all[1..].iter().all(|p|!p.is_self && p.currently_present));
+
+    // the self user takes up the first slot of the first page only
+    assert_eq!(names(&presences(&mut con, 0..2, None, false).await?), vec!["Self User", "Anna"]);
+    assert_eq!(names(&presences(&mut con, 2..4, None, false).await?), vec!["Bert", "Zora"]);
+    assert_eq!(site::count_presence_on_site(&mut con, SELF_ID, SITE_ID, None, false, false, None, PRESENCE_TTL).await?, 4);
+
+    // with a search term, the self user is only listed if matching
+    assert_eq!(names(&presences(&mut con, 0..i32::MAX, Some("er"), false).await?), vec!["Bert"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_favorites() -> Result<()> {
+    const FAVORITE_ID: &str = "00000000-0000-0000-0000-000000000002";
+    const OTHER_ID: &str = "00000000-0000-0000-0000-000000000003";
+
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    hello(&mut con, SELF_ID, "Self User").await?;
+    hello(&mut con, FAVORITE_ID, "Favorite").await?;
+    hello(&mut con, OTHER_ID, "Other").await?;
+    site::add_favorite(&mut con, SELF_ID, FAVORITE_ID).await?;
+    // favorites of other users don't count
+    site::add_favorite(&mut con, OTHER_ID, OTHER_ID).await?;
+
+    let all = presences(&mut con, 0..i32::MAX, None, false).await?;
+    let favorites = all.iter().map(|p|(p.logged_as_name.as_str(), p.is_favorite)).collect::<Vec<_>>();
+    assert_eq!(favorites, vec![("Self User", false), ("Favorite", true), ("Other", false)]);
+
+    let favorites_only = presences(&mut con, 0..i32::MAX, None, true).await?;
+    assert_eq!(names(&favorites_only), vec!["Self User", "Favorite"]);
+
+    site::remove_favorite(&mut con, SELF_ID, FAVORITE_ID).await?;
+    let favorites_only = presences(&mut con, 0..i32::MAX, None, true).await?;
+    assert_eq!(names(&favorites_only), vec!["Self User"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_announcements_are_returned_with_presences() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    let announcements = vec![
+        singular(NaiveDate::from_ymd_opt(2030, 1, 7).unwrap()),
+        weekly(NaiveDate::from_ymd_opt(2030, 1, 8).unwrap(), None),
+    ];
+    site::announce_presence_on_site(&mut con, OTHER_ID, SITE_ID, "Other", &announcements).await?;
+
+    let all = presences(&mut con, 0..i32::MAX, None, false).await?;
+    assert_eq!(names(&all), vec!["Self User", "Other"]);
+    assert!(all[0].announcements.is_empty());
+    // announcing doesn't make present
+    assert!(!all[1].currently_present);
+    assert_eq!(dates_and_kinds(&all[1].announcements), dates_and_kinds(&announcements));
+
+    // announcing again replaces the previous announcements
+    site::announce_presence_on_site(&mut con, OTHER_ID, SITE_ID, "Other", &announcements[..1]).await?;
+    let all = presences(&mut con, 0..i32::MAX, None, false).await?;
+    assert_eq!(dates_and_kinds(&all[1].announcements), dates_and_kinds(&announcements[..1]));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_present_only() -> Result<()> {
+    const PRESENT_ID: &str = "00000000-0000-0000-0000-000000000002";
+    const ANNOUNCED_ID: &str = "00000000-0000-0000-0000-000000000003";
+
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    hello(&mut con, PRESENT_ID, "Present").await?;
+    site::announce_presence_on_site(&mut con, ANNOUNCED_ID, SITE_ID, "Announced", &[singular(NaiveDate::from_ymd_opt(2030, 1, 7).unwrap())]).await?;
+
+    async fn present_only(con: &mut PgConnection, favorites_only: bool) -> Result<Vec<Presence>> {
+        site::get_presence_on_site(con, SELF_ID, "Self User", SITE_ID, 0..i32::MAX, None, favorites_only, true, None, PRESENCE_TTL).await
+    }
+    // the self user isn't listed first, but only if present
+    assert_eq!(names(&present_only(&mut con, false).await?), vec!["Present"]);
+    assert_eq!(site::count_presence_on_site(&mut con, SELF_ID, SITE_ID, None, false, true, None, PRESENCE_TTL).await?, 1);
+    hello(&mut con, SELF_ID, "Self User").await?;
+    assert_eq!(names(&present_only(&mut con, false).await?), vec!["Present", "Self User"]);
+    assert_eq!(site::count_presence_on_site(&mut con, SELF_ID, SITE_ID, None, false, true, None, PRESENCE_TTL).await?, 2);
+
+    site::add_favorite(&mut con, SELF_ID, ANNOUNCED_ID).await?;
+    assert!(present_only(&mut con, true).await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_export_user_data_contains_only_own_data() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    let date = NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+    for (user_id, name) in [(SELF_ID, "Self User"), (OTHER_ID, "Other User")] {
+        hello(&mut con, user_id, name).await?;
+        site::announce_presence_on_site(&mut con, user_id, SITE_ID, name, &[singular(date)]).await?;
+    }
+    site::add_favorite(&mut con, SELF_ID, OTHER_ID).await?;
+    site::add_favorite(&mut con, OTHER_ID, SELF_ID).await?;
+
+    let (tx, mut rx) = mpsc::channel(16);
+    let export = tokio::spawn(async move {
+        site::export_user_data(&mut con, SELF_ID, &tx).await
+    });
+    let mut document = Vec::new();
+    while let Some(chunk) = rx.recv().await {
+        document.extend_from_slice(&chunk);
+    }
+    export.await??;
+
+    let document: serde_json::Value = serde_json::from_slice(&document)?;
+    assert_eq!(document["user_info"]["user_id"], SELF_ID);
+    assert_eq!(document["user_info"]["logged_as_name"], "Self User");
+    assert_eq!(document["favorites"], json!([OTHER_ID]));
+    assert_eq!(document["announcements"], json!([{"site_id": SITE_ID, "date": "2030-01-01", "recurring": false, "recurring_until": null}]));
+    assert_eq!(document["presences"].as_array().unwrap().len(), 1);
+    assert_eq!(document["presences"][0]["logged_as_name"], "Self User");
+    assert_eq!(document["presence_history"].as_array().unwrap().len(), 1);
+    assert!(!document.to_string().contains("Other User"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_clear_noshow_announcements() -> Result<()> {
+    const PRESENT_ID: &str = SELF_ID;
+    const ABSENT_ID: &str = OTHER_ID;
+
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    let yesterday = db_today(&mut con).await? - TimeDelta::days(1);
+
+    // inserted directly, as announcing rejects past dates
+    for (user_id, recurring) in [(PRESENT_ID, false), (ABSENT_ID, false), (ABSENT_ID, true)] {
+        sqlx::query("INSERT INTO user_announcements (user_id, site_id, present_on, recurring) VALUES ($1, $2, $3, $4)")
+        .bind(user_id)
+        .bind(SITE_ID)
+        .bind(yesterday)
+        .bind(recurring)
+        .execute(&mut *con).await?;
+    }
+    sqlx::query("INSERT INTO presence_history (user_id, site_id, started_at, seen_at) VALUES ($1, $2, ($3::date + time '09:00') AT TIME ZONE 'UTC', ($3::date + time '12:00') AT TIME ZONE 'UTC')")
+    .bind(PRESENT_ID)
+    .bind(SITE_ID)
+    .bind(yesterday)
+    .execute(&mut *con).await?;
+
+    assert_eq!(site::clear_noshow_announcements(&mut con, "UTC").await?, 1);
+
+    let remaining: Vec<(String, bool)> = sqlx::query("SELECT user_id, recurring FROM user_announcements ORDER BY user_id, recurring")
+    .map(|r: PgRow|(r.get::<String,_>(0), r.get(1)))
+    .fetch_all(&mut *con).await?;
+    assert_eq!(remaining, vec![(PRESENT_ID.to_string(), false), (ABSENT_ID.to_string(), true)]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_presence_changes_on_site() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    insert_site(&mut con, OTHER_SITE_ID, "Other Site").await?;
+
+    hello(&mut con, SELF_ID, "Self User").await?;
+    let (since, presences) = site::get_presence_changes_on_site(&mut con, SELF_ID, SITE_ID, DateTime::<Utc>::MIN_UTC, PRESENCE_TTL).await?;
+    assert_eq!(presences.len(), 1);
+    assert!(presences[0].currently_present);
+    assert!(presences[0].last_seen.is_some_and(|t|t <= Utc::now()));
+
+    // nothing changed since the last poll, keepalive hellos don't count
+    hello(&mut con, SELF_ID, "Self User").await?;
+    let (_, presences) = site::get_presence_changes_on_site(&mut con, SELF_ID, SITE_ID, since, PRESENCE_TTL).await?;
+    assert!(presences.is_empty());
+
+    // announcements of the other user are reported, changes at other sites aren't
+    site::announce_presence_on_site(&mut con, OTHER_ID, SITE_ID, "Other User", &[singular(NaiveDate::from_ymd_opt(2030, 1, 1).unwrap())]).await?;
+    site::announce_presence_on_site(&mut con, SELF_ID, OTHER_SITE_ID, "Self User", &[]).await?;
+    let (since, presences) = site::get_presence_changes_on_site(&mut con, SELF_ID, SITE_ID, since, PRESENCE_TTL).await?;
+    assert_eq!(presences.len(), 1);
+    assert_eq!(presences[0].user_id.trim_end(), OTHER_ID);
+    assert_eq!(presences[0].announcements.len(), 1);
+
+    // moving to another site is a change at the site left
+    hello_at(&mut con, SELF_ID, "Self User", OTHER_SITE_ID).await?;
+    let (_, presences) = site::get_presence_changes_on_site(&mut con, SELF_ID, SITE_ID, since, PRESENCE_TTL).await?;
+    assert_eq!(presences.len(), 1);
+    assert_eq!(presences[0].user_id.trim_end(), SELF_ID);
+    assert!(!presences[0].currently_present);
+
+    // returning after the presence expired is a change, too
+    let (since, _) = site::get_presence_changes_on_site(&mut con, SELF_ID, OTHER_SITE_ID, since, PRESENCE_TTL).await?;
+    make_stale(&mut con, SELF_ID).await?;
+    hello_at(&mut con, SELF_ID, "Self User", OTHER_SITE_ID).await?;
+    let (_, presences) = site::get_presence_changes_on_site(&mut con, SELF_ID, OTHER_SITE_ID, since, PRESENCE_TTL).await?;
+    assert_eq!(presences.len(), 1);
+    assert!(presences[0].currently_present);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_site() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    insert_site(&mut con, OTHER_SITE_ID, "Other Site").await?;
+    for site_id in [SITE_ID, OTHER_SITE_ID] {
+        site::announce_presence_on_site(&mut con, SELF_ID, site_id, "Self User", &[weekly(NaiveDate::from_ymd_opt(2030, 1, 1).unwrap(), None)]).await?;
+    }
+    hello(&mut con, SELF_ID, "Self User").await?;
+
+    assert!(site::delete_site(&mut con, SITE_ID).await?);
+    assert!(!site::delete_site(&mut con, SITE_ID).await?);
+
+    let announced_sites: Vec<String> = sqlx::query("SELECT site_id FROM user_announcements")
+    .map(|r: PgRow|r.get(0))
+    .fetch_all(&mut *con).await?;
+    assert_eq!(announced_sites, vec![OTHER_SITE_ID.to_string()]);
+
+    let presences: i64 = sqlx::query("SELECT count(*) FROM logged_into_site")
+    .map(|r: PgRow|r.get(0))
+    .fetch_one(&mut *con).await?;
+    assert_eq!(presences, 0);
+
+    let site_ids = site::get_sites(&mut con, 0..i32::MAX, None).await?.into_iter().map(|s|s.id).collect::<Vec<_>>();
+    assert_eq!(site_ids, vec![OTHER_SITE_ID.to_string()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_muster_contains_only_present_users() -> Result<()> {
+    const PRESENT_ID: &str = "00000000-0000-0000-0000-000000000002";
+    const LEFT_ID: &str = "00000000-0000-0000-0000-000000000003";
+    const ELSEWHERE_ID: &str = "00000000-0000-0000-0000-000000000004";
+    const ANNOUNCED_ID: &str = "00000000-0000-0000-0000-000000000005";
+
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    insert_site(&mut con, OTHER_SITE_ID, "Other Site").await?;
+
+    site::hello_site(&mut con, PRESENT_ID, "Present User", Some("present@example.com"), SITE_ID, Some(site::PRESENCE_SOURCE_GEOFENCE), None, SESSION_MERGE_GAP, PRESENCE_TTL).await?;
+    hello(&mut con, LEFT_ID, "Left User").await?;
+    make_stale(&mut con, LEFT_ID).await?;
+    hello_at(&mut con, ELSEWHERE_ID, "Elsewhere User", OTHER_SITE_ID).await?;
+    let today = db_today(&mut con).await?;
+    site::announce_presence_on_site(&mut con, ANNOUNCED_ID, SITE_ID, "Announced User", &[singular(today)]).await?;
+
+    let muster = site::get_muster(&mut con, SITE_ID, PRESENCE_TTL).await?;
+    assert_eq!(muster.len(), 1);
+    assert_eq!(muster[0].user_id, PRESENT_ID);
+    assert_eq!(muster[0].name.as_deref(), Some("Present User"));
+    assert_eq!(muster[0].email.as_deref(), Some("present@example.com"));
+    assert_eq!(muster[0].source, site::PRESENCE_SOURCE_GEOFENCE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_record_presence_history_merges_sessions() -> Result<()> {
+    let start = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap().and_hms_opt(8, 0, 0).unwrap().and_utc();
+
+    // hello offsets in minutes, merge gap in minutes, expected number of sessions
+    let cases: [(&[i64], u64, i64); 4] = [
+        (&[0, 5, 10, 15], 5, 1),
+        (&[0, 5, 20, 25], 5, 2),
+        (&[0, 5, 20, 25], 15, 1),
+        (&[0, 30, 60, 90], 15, 4),
+    ];
+
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    async fn sessions(con: &mut PgConnection, user_id: &str) -> Result<i64> {
+        Ok(sqlx::query("SELECT count(*) FROM presence_history WHERE user_id=$1")
+        .bind(user_id)
+        .map(|r: PgRow|r.get(0))
+        .fetch_one(con).await?)
+    }
+
+    for (n, (offsets, gap_minutes, expected_sessions)) in cases.into_iter().enumerate() {
+        let user_id = format!("00000000-0000-0000-0000-{:012}", n + 1);
+        for offset in offsets {
+            site::record_presence_history(&mut con, &user_id, SITE_ID, start + TimeDelta::minutes(*offset), Duration::from_secs(gap_minutes * 60)).await?;
+        }
+        assert_eq!(sessions(&mut con, &user_id).await?, expected_sessions, "hellos at {offsets:?} with gap {gap_minutes}");
+    }
+
+    // visiting another site in between opens a new session, even within the gap
+    const USER_ID: &str = "00000000-0000-0000-0000-0000000000ff";
+    let gap = Duration::from_secs(15 * 60);
+    site::record_presence_history(&mut con, USER_ID, SITE_ID, start, gap).await?;
+    site::record_presence_history(&mut con, USER_ID, OTHER_SITE_ID, start + TimeDelta::minutes(1), gap).await?;
+    site::record_presence_history(&mut con, USER_ID, SITE_ID, start + TimeDelta::minutes(2), gap).await?;
+    assert_eq!(sessions(&mut con, USER_ID).await?, 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prune_stale_presences() -> Result<()> {
+    const STALE_ID: &str = OTHER_ID;
+
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    hello(&mut con, SELF_ID, "Self User").await?;
+    hello(&mut con, STALE_ID, "Stale User").await?;
+    make_stale(&mut con, STALE_ID).await?;
+
+    assert_eq!(site::prune_stale_presences(&mut con, PRESENCE_TTL).await?, 1);
+    let remaining: Vec<String> = sqlx::query("SELECT user_id FROM logged_into_site")
+    .map(|r: PgRow|r.get(0))
+    .fetch_all(&mut *con).await?;
+    assert_eq!(remaining, vec![SELF_ID.to_string()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_expired_recurring_announcements_are_omitted() -> Result<()> {
+    const EXPIRED_ID: &str = "00000000-0000-0000-0000-000000000002";
+    const ONGOING_ID: &str = "00000000-0000-0000-0000-000000000003";
+
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    let today = db_today(&mut con).await?;
+    for (user_id, recurring_until) in [(EXPIRED_ID, today - TimeDelta::days(1)), (ONGOING_ID, today + TimeDelta::days(30))] {
+        site::announce_presence_on_site(&mut con, user_id, SITE_ID, "Test User", &[weekly(today - TimeDelta::days(14), Some(recurring_until))]).await?;
+    }
+
+    let all = presences(&mut con, 0..i32::MAX, None, false).await?;
+    let announcements = |user_id: &str| all.iter()
+        .find(|p| p.user_id == user_id)
+        .map(|p| p.announcements.clone())
+        .unwrap_or_default();
+    assert!(announcements(EXPIRED_ID).is_empty());
+    assert_eq!(announcements(ONGOING_ID)[0].recurring_until, Some(today + TimeDelta::days(30)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_clear_announcements() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    insert_site(&mut con, OTHER_SITE_ID, "Other Site").await?;
+    let announcements = [singular(NaiveDate::from_ymd_opt(2030, 1, 1).unwrap())];
+    for user_id in [SELF_ID, OTHER_ID] {
+        for site_id in [SITE_ID, OTHER_SITE_ID] {
+            site::announce_presence_on_site(&mut con, user_id, site_id, "Test User", &announcements).await?;
+        }
+    }
+    async fn count(con: &mut PgConnection, user_id: &str) -> Result<i64> {
+        Ok(sqlx::query("SELECT count(*) FROM user_announcements WHERE user_id=$1")
+        .bind(user_id)
+        .map(|r: PgRow|r.get(0))
+        .fetch_one(con).await?)
+    }
+
+    site::clear_announcements(&mut con, SELF_ID, Some(SITE_ID)).await?;
+    assert_eq!(count(&mut con, SELF_ID).await?, 1);
+
+    site::clear_announcements(&mut con, SELF_ID, None).await?;
+    assert_eq!(count(&mut con, SELF_ID).await?, 0);
+    assert_eq!(count(&mut con, OTHER_ID).await?, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_favorites_presence_spans_sites() -> Result<()> {
+    const AT_SITE_ID: &str = "00000000-0000-0000-0000-000000000002";
+    const AT_OTHER_SITE_ID: &str = "00000000-0000-0000-0000-000000000003";
+    const AWAY_ID: &str = "00000000-0000-0000-0000-000000000004";
+    const NO_FAVORITE_ID: &str = "00000000-0000-0000-0000-000000000005";
+
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    insert_site(&mut con, OTHER_SITE_ID, "Other Site").await?;
+
+    hello(&mut con, SELF_ID, "Self User").await?;
+    hello(&mut con, AT_SITE_ID, "A At Site").await?;
+    hello_at(&mut con, AT_OTHER_SITE_ID, "B At Other Site", OTHER_SITE_ID).await?;
+    hello(&mut con, AWAY_ID, "C Away").await?;
+    make_stale(&mut con, AWAY_ID).await?;
+    hello(&mut con, NO_FAVORITE_ID, "D No Favorite").await?;
+    for favorite_id in [AT_SITE_ID, AT_OTHER_SITE_ID, AWAY_ID] {
+        site::add_favorite(&mut con, SELF_ID, favorite_id).await?;
+    }
+
+    let favorites = site::get_favorites_presence(&mut con, SELF_ID, PRESENCE_TTL).await?
+    .into_iter()
+    .map(|f|(f.user_id, f.currently_present, f.site_id))
+    .collect::<Vec<_>>();
+    assert_eq!(favorites, vec![
+        (AT_SITE_ID.to_string(), true, Some(SITE_ID.to_string())),
+        (AT_OTHER_SITE_ID.to_string(), true, Some(OTHER_SITE_ID.to_string())),
+        (AWAY_ID.to_string(), false, None),
+    ]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_announce_presence_stores_date_with_leading_zeros() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    let date = NaiveDate::from_ymd_opt(2030, 2, 3).unwrap();
+    site::announce_presence_on_site(&mut con, SELF_ID, SITE_ID, "Self User", &[singular(date)]).await?;
+
+    let present_on = sqlx::query("SELECT present_on FROM user_announcements WHERE user_id=$1 AND site_id=$2")
+    .bind(SELF_ID)
+    .bind(SITE_ID)
+    .map(|r: PgRow|r.get::<NaiveDate,_>(0))
+    .fetch_all(&mut *con).await?;
+    assert_eq!(present_on, vec![date]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_sites_search_and_range() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    insert_site(&mut con, SITE_ID, "Stuttgart").await?;
+    insert_site(&mut con, OTHER_SITE_ID, "Berlin").await?;
+    insert_site(&mut con, "00000000-0000-0000-0000-0000000000a3", "Stuttgart West").await?;
+    async fn names(con: &mut PgConnection, range: std::ops::Range<i32>, term: Option<&str>) -> Result<Vec<String>> {
+        Ok(site::get_sites(con, range, term).await?.into_iter().map(|s|s.name).collect())
+    }
+
+    assert_eq!(names(&mut con, 0..i32::MAX, None).await?, vec!["Berlin", "Stuttgart", "Stuttgart West"]);
+    assert_eq!(names(&mut con, 0..i32::MAX, Some("stutt")).await?, vec!["Stuttgart", "Stuttgart West"]);
+    assert_eq!(names(&mut con, 1..2, None).await?, vec!["Stuttgart"]);
+    assert!(names(&mut con, 0..0, None).await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_goodbye_site() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    insert_site(&mut con, OTHER_SITE_ID, "Other Site").await?;
+
+    hello(&mut con, SELF_ID, "Self User").await?;
+    assert_eq!(site::count_present_on_site(&mut con, SITE_ID, PRESENCE_TTL).await?, 1);
+
+    // saying goodbye at another site doesn't check the user out
+    site::goodbye_site(&mut con, SELF_ID, OTHER_SITE_ID).await?;
+    assert_eq!(site::count_present_on_site(&mut con, SITE_ID, PRESENCE_TTL).await?, 1);
+
+    site::goodbye_site(&mut con, SELF_ID, SITE_ID).await?;
+    assert_eq!(site::count_present_on_site(&mut con, SITE_ID, PRESENCE_TTL).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_user_announcements() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    insert_site(&mut con, OTHER_SITE_ID, "Other Site").await?;
+    let monday = NaiveDate::from_ymd_opt(2030, 1, 7).unwrap();
+    site::announce_presence_on_site(&mut con, SELF_ID, SITE_ID, "Self User", &[singular(monday + TimeDelta::days(1))]).await?;
+    site::announce_presence_on_site(&mut con, SELF_ID, OTHER_SITE_ID, "Self User", &[weekly(monday, None)]).await?;
+    site::announce_presence_on_site(&mut con, OTHER_ID, SITE_ID, "Other User", &[singular(monday)]).await?;
+
+    let announcements = site::get_user_announcements(&mut con, SELF_ID).await?.into_iter()
+        .map(|a|(a.site_id, a.date, a.kind))
+        .collect::<Vec<_>>();
+    assert_eq!(announcements, vec![
+        (OTHER_SITE_ID.to_string(), monday, PresenceAnnouncementKind::RecurringAnnouncement),
+        (SITE_ID.to_string(), monday + TimeDelta::days(1), PresenceAnnouncementKind::SingularAnnouncement),
+    ]);
+
+    assert!(!site::is_favorite(&mut con, SELF_ID, OTHER_ID).await?);
+    hello(&mut con, SELF_ID, "Self User").await?;
+    site::add_favorite(&mut con, SELF_ID, OTHER_ID).await?;
+    assert!(site::is_favorite(&mut con, SELF_ID, OTHER_ID).await?);
+    assert!(!site::is_favorite(&mut con, OTHER_ID, SELF_ID).await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_audit_log() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    let start = Utc::now() - TimeDelta::seconds(1);
+    site::hello_site(&mut con, SELF_ID, "Self User", None, SITE_ID, Some(site::PRESENCE_SOURCE_GEOFENCE), None, SESSION_MERGE_GAP, PRESENCE_TTL).await?;
+    // keepalive hellos aren't logged
+    hello(&mut con, SELF_ID, "Self User").await?;
+    site::announce_presence_on_site(&mut con, SELF_ID, SITE_ID, "Self User", &[]).await?;
+
+    let entries = site::get_audit_log(&mut con, Some(start), 0..i32::MAX).await?.into_iter()
+        .map(|e|(e.user_id, e.action, e.site_id, e.detail))
+        .collect::<Vec<_>>();
+    assert_eq!(entries, vec![
+        (SELF_ID.to_string(), site::AUDIT_ACTION_HELLO.to_string(), Some(SITE_ID.to_string()), Some("source: geofence".to_string())),
+        (SELF_ID.to_string(), site::AUDIT_ACTION_ANNOUNCE.to_string(), Some(SITE_ID.to_string()), None),
+    ]);
+
+    assert_eq!(site::get_audit_log(&mut con, Some(start), 1..2).await?.len(), 1);
+    assert!(site::get_audit_log(&mut con, Some(Utc::now() + TimeDelta::hours(1)), 0..i32::MAX).await?.is_empty());
+
+    sqlx::query("UPDATE audit_log SET recorded_at = now() - interval '31 days' WHERE action=$1")
+    .bind(site::AUDIT_ACTION_HELLO)
+    .execute(&mut *con).await?;
+    assert_eq!(site::prune_audit_log(&mut con, 30).await?, 1);
+    assert_eq!(site::get_audit_log(&mut con, None, 0..i32::MAX).await?.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_preferred_name() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    hello(&mut con, SELF_ID, "Self User").await?;
+    site::set_preferred_name(&mut con, OTHER_ID, "Other U.", Some("Other User")).await?;
+    // neither hello nor announcing replaces the preferred name
+    hello(&mut con, OTHER_ID, "Other U.").await?;
+    site::announce_presence_on_site(&mut con, OTHER_ID, SITE_ID, "Other U.", &[]).await?;
+    assert_eq!(site::get_preferred_name(&mut con, OTHER_ID).await?.as_deref(), Some("Other User"));
+    assert_eq!(site::get_preferred_name(&mut con, SELF_ID).await?, None);
+
+    assert_eq!(names(&presences(&mut con, 0..i32::MAX, None, false).await?), vec!["Self User", "Other User"]);
+    assert_eq!(names(&presences(&mut con, 0..i32::MAX, Some("other user"), false).await?), vec!["Other User"]);
+
+    site::set_preferred_name(&mut con, OTHER_ID, "Other U.", None).await?;
+    assert_eq!(names(&presences(&mut con, 0..i32::MAX, None, false).await?), vec!["Self User", "Other U."]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prune_old_announcements() -> Result<()> {
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    let today = db_today(&mut con).await?;
+
+    // inserted directly, as announcing rejects past dates
+    let announcements = [
+        (today - TimeDelta::days(31), false, None),
+        (today - TimeDelta::days(30), false, None),
+        (today - TimeDelta::days(60), true, None),
+        (today - TimeDelta::days(60), true, Some(today - TimeDelta::days(31))),
+        (today - TimeDelta::days(60), true, Some(today - TimeDelta::days(30))),
+    ];
+    for (present_on, recurring, recurring_until) in announcements {
+        sqlx::query("INSERT INTO user_announcements (user_id, site_id, present_on, recurring, recurring_until) VALUES ($1, $2, $3, $4, $5)")
+        .bind(SELF_ID)
+        .bind(SITE_ID)
+        .bind(present_on)
+        .bind(recurring)
+        .bind(recurring_until)
+        .execute(&mut *con).await?;
+    }
+
+    assert_eq!(site::prune_old_announcements(&mut con, "UTC", 30).await?, 2);
+
+    let remaining: Vec<(NaiveDate, bool, Option<NaiveDate>)> = sqlx::query("SELECT present_on, recurring, recurring_until FROM user_announcements ORDER BY recurring, recurring_until NULLS FIRST")
+    .map(|r: PgRow|(r.get(0), r.get(1), r.get(2)))
+    .fetch_all(&mut *con).await?;
+    assert_eq!(remaining, vec![announcements[1], announcements[2], announcements[4]]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_presence_on_date() -> Result<()> {
+    const SINGULAR_ID: &str = "00000000-0000-0000-0000-000000000002";
+    const WEEKLY_ID: &str = "00000000-0000-0000-0000-000000000003";
+    const ENDED_ID: &str = "00000000-0000-0000-0000-000000000004";
+    let monday = NaiveDate::from_ymd_opt(2030, 1, 7).unwrap();
+    let next_monday = monday + TimeDelta::days(7);
+
+    let db = start_db().await?;
+    let mut con = db.pool.acquire().await?;
+    hello(&mut con, SELF_ID, "Self User").await?;
+    for (user_id, name, announcement) in [
+        (SINGULAR_ID, "Singular User", singular(next_monday)),
+        (WEEKLY_ID, "Weekly User", weekly(monday, None)),
+        (ENDED_ID, "Ended User", weekly(monday, Some(monday + TimeDelta::days(6)))),
+    ] {
+        site::announce_presence_on_site(&mut con, user_id, SITE_ID, name, &[announcement]).await?;
+    }
+
+    for (date, expected) in [
+        (monday, vec!["Ended User", "Weekly User"]),
+        (next_monday, vec!["Singular User", "Weekly User"]),
+        (next_monday + TimeDelta::days(1), vec![]),
+        (monday - TimeDelta::days(7), vec![]),
+    ] {
+        let on_date = site::get_presence_on_site(&mut con, SELF_ID, "Self User", SITE_ID, 0..i32::MAX, None, false, false, Some(date), PRESENCE_TTL).await?;
+        assert_eq!(names(&on_date), expected, "on {date}");
+        assert_eq!(site::count_presence_on_site(&mut con, SELF_ID, SITE_ID, None, false, false, Some(date), PRESENCE_TTL).await?, expected.len() as i64);
+    }
+
+    Ok(())
+}
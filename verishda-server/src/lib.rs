@@ -2,6 +2,7 @@ use std::cell::OnceCell;
 use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use axum::body::Body;
@@ -15,18 +16,26 @@ use axum_extra::typed_header::TypedHeaderRejectionReason;
 use bytes::Bytes;
 use verishda_config::Config;
 use dashmap::DashMap;
-use error::HandlerError;
-use http::{StatusCode, request::Parts};
+use error::{HandlerError, JsonBody};
+use http::{header, HeaderName, HeaderValue, Method, StatusCode, request::Parts};
 use memory_store::MemoryStore;
+use store::SharedStore;
 
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
-use verishda_dto::types::{PresenceAnnouncement, Site, Presence};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
+use tracing_subscriber::EnvFilter;
+use chrono_tz::Tz;
+use verishda_dto::types::{AnnouncementValidation, AuditEntry, DisplayName, FavoritePresence, Identity, PresenceAnnouncement, PresenceChanges, Site, Presence, UserAnnouncement};
 use log::{trace, error};
 use sqlx::pool::PoolConnection;
 use sqlx::{Pool, Postgres};
+use sqlx::postgres::PgPoolOptions;
 
-use crate::oidc_cache::MetadataCache;
+use crate::oidc_cache::{MetadataCache, METADATA_REFRESH_INTERVAL};
+use crate::pending_login::{PendingLogin, PendingLogins, DEFAULT_LOGIN_TIMEOUT};
 use crate::scheme::Scheme;
 
 
@@ -37,17 +46,25 @@ struct AuthInfo {
     subject: String,
     given_name: Option<String>,
     family_name: Option<String>,
+    email: Option<String>,
+    scopes: Vec<String>,
 }
 
 mod site;
 mod oidc;
 mod store;
 mod memory_store;
+mod redis_store;
 mod oidc_cache;
 mod error;
 mod scheme;
+mod metrics;
+mod pending_login;
+mod rate_limit;
 mod datamodel;
 mod verishda_dto;
+#[cfg(all(test, feature = "it"))]
+mod it;
 
 const SWAGGER_SPEC_URL: &str = "/api/public/openapi.yaml";
 
@@ -56,7 +73,8 @@ where Self: Send + Sync + Clone
 {
     pool: Pool<Postgres>,
     config: Box<dyn Config>,
-    pending_logins: Arc<DashMap<String,oneshot::Sender<String>>>,
+    pending_logins: PendingLogins,
+    rate_limiter: Option<rate_limit::RateLimiter>,
 }
 impl Clone for VerishdaState {
     fn clone(&self) -> Self {
@@ -64,20 +82,26 @@ impl Clone for VerishdaState {
             pool: self.pool.clone(),
             config: self.config.clone_box_dyn(),
             pending_logins: self.pending_logins.clone(),
+            rate_limiter: self.rate_limiter.clone(),
         }
     }
 }
 
 pub fn init_logging(cfg: &impl verishda_config::Config) {
-    let rust_log_config = cfg.get("RUST_LOG").ok();
-    let mut logger_builder = env_logger::builder();
-    if let Some(rust_log) = rust_log_config {
-        logger_builder.parse_filters(&rust_log);
-    } else {
-        logger_builder.filter_level(log::LevelFilter::Info);
-    }
-    logger_builder.init();
-    println!("max logging level is: {}.", log::max_level());
+    let filter = cfg.get("RUST_LOG").ok()
+    .and_then(|rust_log| match EnvFilter::try_new(&rust_log) {
+        Ok(filter) => Some(filter),
+        Err(e) => {
+            eprintln!("ignoring invalid RUST_LOG '{rust_log}': {e}");
+            None
+        }
+    })
+    .unwrap_or_else(|| EnvFilter::new("info"));
+    println!("logging filter is: {filter}.");
+    // also installs a logger forwarding `log` records to tracing
+    tracing_subscriber::fmt()
+    .with_env_filter(filter)
+    .init();
     println!("Use RUST_LOG environment variable to set one of the levels, e.g. RUST_LOG=error");
 }
 
@@ -134,48 +158,448 @@ async fn migrate_db(pool: &sqlx::PgPool) -> Result<()> {
 }
 
 
-pub async fn connect_db(url: &str) -> Result<Pool<Postgres>> {
+/// Connection pool defaults, configurable via `DB_MAX_CONNECTIONS`,
+/// `DB_ACQUIRE_TIMEOUT_SECS` and `DB_IDLE_TIMEOUT_SECS`
+const DEFAULT_DB_MAX_CONNECTIONS: u32 = 10;
+const DEFAULT_DB_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_DB_IDLE_TIMEOUT: Duration = Duration::from_secs(10*60);
+
+pub async fn connect_db(url: &str, config: &impl Config) -> Result<Pool<Postgres>> {
+    let max_connections = u32::try_from(config.get_as_int_or("DB_MAX_CONNECTIONS", DEFAULT_DB_MAX_CONNECTIONS as i64))
+    .ok()
+    .filter(|max|*max > 0)
+    .unwrap_or(DEFAULT_DB_MAX_CONNECTIONS);
+
     // provide connection pool
-    let pool = Pool::connect(&url).await?;
+    let pool = PgPoolOptions::new()
+    .max_connections(max_connections)
+    .acquire_timeout(config.get_as_duration_or("DB_ACQUIRE_TIMEOUT_SECS", DEFAULT_DB_ACQUIRE_TIMEOUT))
+    .idle_timeout(config.get_as_duration_or("DB_IDLE_TIMEOUT_SECS", DEFAULT_DB_IDLE_TIMEOUT))
+    .connect(url).await?;
 
     migrate_db(&pool).await?;
 
     Ok(pool)
 }
 
+/// Completes when the process is asked to terminate, either by Ctrl-C or
+/// (on Unix) by SIGTERM. Use with `axum::serve(...).with_graceful_shutdown()`.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("cannot listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("cannot listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    log::info!("shutdown signal received");
+}
+
+/// Timezone determining day boundaries: the one configured in `TIMEZONE`,
+/// or the system's timezone. Falls back to UTC if neither is known.
+fn timezone(config: &dyn Config) -> Tz {
+    let name = match config.get("TIMEZONE") {
+        Ok(name) => name,
+        Err(_) => match iana_time_zone::get_timezone() {
+            Ok(name) => name,
+            Err(e) => {
+                log::warn!("cannot determine the system's timezone, using UTC: {e}");
+                return Tz::UTC;
+            }
+        }
+    };
+    name.trim().parse::<Tz>().unwrap_or_else(|e| {
+        log::warn!("unknown timezone '{name}', using UTC: {e}");
+        Tz::UTC
+    })
+}
+
+/// How often the no-show cleanup runs. It only ever touches the previous
+/// day, so running it more often than daily is harmless and ensures it 
+/// runs soon after midnight in any timezone.
+const NOSHOW_CLEANUP_INTERVAL: Duration = Duration::from_secs(60*60);
+
+/// Spawn a task that periodically deletes singular announcements of users
+/// that didn't show up on the announced day
+fn spawn_noshow_cleanup(pool: Pool<Postgres>, timezone: String) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(NOSHOW_CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let result = match pool.acquire().await {
+                Ok(mut con) => site::clear_noshow_announcements(&mut con, &timezone).await,
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(count) => log::info!("cleared {count} announcement(s) of users that didn't show up"),
+                Err(e) => error!("failed to clear announcements of users that didn't show up: {e}"),
+            }
+        }
+    })
+}
+
+/// How often old announcements are pruned if `ANNOUNCE_RETENTION_DAYS` is 
+/// set. Like the no-show cleanup, it works on whole days.
+const ANNOUNCEMENT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60*60);
+
+/// Spawn a task that periodically deletes announcements for days more than
+/// `retention_days` ago, keeping the announcements table bounded
+fn spawn_announcement_cleanup(pool: Pool<Postgres>, timezone: String, retention_days: i32) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ANNOUNCEMENT_CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let result = match pool.acquire().await {
+                Ok(mut con) => site::prune_old_announcements(&mut con, &timezone, retention_days).await,
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(count) => log::info!("pruned {count} announcement(s) older than {retention_days} day(s)"),
+                Err(e) => error!("failed to prune old announcements: {e}"),
+            }
+        }
+    })
+}
+
+/// How often audit log entries past `AUDIT_RETENTION_DAYS` are pruned
+const AUDIT_LOG_CLEANUP_INTERVAL: Duration = Duration::from_secs(60*60);
+
+/// Spawn a task that periodically deletes audit log entries older than
+/// `retention_days`, keeping the audit log bounded
+fn spawn_audit_log_cleanup(pool: Pool<Postgres>, retention_days: i32) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(AUDIT_LOG_CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let result = match pool.acquire().await {
+                Ok(mut con) => site::prune_audit_log(&mut con, retention_days).await,
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(count) => log::info!("pruned {count} audit log entries older than {retention_days} day(s)"),
+                Err(e) => error!("failed to prune the audit log: {e}"),
+            }
+        }
+    })
+}
+
+/// Default for `PRESENCE_CLEANUP_SECS`. Configured values are raised to the
+/// minimum, as the interval must not be zero.
+const DEFAULT_PRESENCE_CLEANUP_INTERVAL: Duration = Duration::from_secs(10*60);
+const MIN_PRESENCE_CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Spawn a task that periodically deletes check-ins older than the
+/// presence TTL, keeping the presence table bounded
+fn spawn_presence_cleanup(pool: Pool<Postgres>, interval: Duration, presence_ttl: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let result = match pool.acquire().await {
+                Ok(mut con) => site::prune_stale_presences(&mut con, presence_ttl).await,
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(count) => log::info!("pruned {count} stale presence(s)"),
+                Err(e) => error!("failed to prune stale presences: {e}"),
+            }
+        }
+    })
+}
+
+/// Configuration keys the server can't run without, checked on startup
+pub const REQUIRED_CONFIG_KEYS: &[&str] = &["ISSUER_URL", "CLIENT_ID"];
+
+/// Retries of OIDC discovery unless configured otherwise via 
+/// `OIDC_DISCOVERY_RETRIES` and `OIDC_DISCOVERY_RETRY_DELAY_MS`. Discovery 
+/// may happen while handling a request, so the delays are kept short.
+const DEFAULT_OIDC_DISCOVERY_RETRIES: u32 = 2;
+const DEFAULT_OIDC_DISCOVERY_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+fn discovery_retry(config: &dyn Config) -> oidc::DiscoveryRetry {
+    oidc::DiscoveryRetry {
+        retries: config.get_as_int_or("OIDC_DISCOVERY_RETRIES", DEFAULT_OIDC_DISCOVERY_RETRIES.into()).clamp(0, 10) as u32,
+        base_delay: config.get("OIDC_DISCOVERY_RETRY_DELAY_MS").ok()
+        .and_then(|ms| ms.trim().parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_OIDC_DISCOVERY_RETRY_DELAY),
+    }
+}
+
 pub fn build_router(pool: Pool<Postgres>, config: impl verishda_config::Config) -> Router
 {
-    let pending_logins = Arc::new(DashMap::with_capacity(127));
-    let state = VerishdaState { pool, config: config.clone_box_dyn(), pending_logins };
-    return Router::new()
-    .route(SWAGGER_SPEC_URL, get(handle_get_swagger_spec))
-    .route("/api/public/swagger-ui/:path", get(handle_get_swagger_ui))
+    if config.get_as_bool_or("DEV_MODE", false) {
+        log::warn!("**************************************************************");
+        log::warn!("DEV_MODE is enabled, THIS SERVER IS INSECURE:");
+        log::warn!("issuers served via plain HTTP are accepted and token audiences");
+        log::warn!("are not validated. Never enable DEV_MODE in production.");
+        log::warn!("**************************************************************");
+    }
+
+    let pending_logins: PendingLogins = Arc::new(DashMap::with_capacity(127));
+
+    pending_login::spawn_sweeper(pending_logins.clone(), login_timeout(&config));
+
+    // on shutdown, drop pending logins so that their websockets are closed
+    // instead of keeping a graceful shutdown waiting for them
+    let pending_logins_clone = pending_logins.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        log::info!("closing {} pending login(s)", pending_logins_clone.len());
+        pending_logins_clone.clear();
+    });
+
+    if config.get_as_bool_or("CLEAR_NOSHOW_ANNOUNCEMENTS", false) {
+        spawn_noshow_cleanup(pool.clone(), timezone(&config).name().to_string());
+    }
+
+    let announce_retention_days = config.get_as_int_or("ANNOUNCE_RETENTION_DAYS", -1);
+    if announce_retention_days >= 0 {
+        let retention_days = announce_retention_days.min(i32::MAX.into()) as i32;
+        spawn_announcement_cleanup(pool.clone(), timezone(&config).name().to_string(), retention_days);
+    }
+
+    // the audit log is kept forever unless a retention period is configured
+    let audit_retention_days = config.get_as_int_or("AUDIT_RETENTION_DAYS", -1);
+    if audit_retention_days >= 0 {
+        let retention_days = audit_retention_days.min(i32::MAX.into()) as i32;
+        spawn_audit_log_cleanup(pool.clone(), retention_days);
+    }
+
+    let presence_cleanup_interval = config.get_as_duration_or("PRESENCE_CLEANUP_SECS", DEFAULT_PRESENCE_CLEANUP_INTERVAL)
+    .max(MIN_PRESENCE_CLEANUP_INTERVAL);
+    spawn_presence_cleanup(pool.clone(), presence_cleanup_interval, presence_ttl(&config));
+
+    let rate_limiter = rate_limit::RateLimiter::from_config(&config);
+    if let Some(rate_limiter) = &rate_limiter {
+        rate_limiter.spawn_sweeper();
+    }
+    let state = VerishdaState { pool, config: config.clone_box_dyn(), pending_logins, rate_limiter };
+    let store = match config.get("REDIS_URL") {
+        Ok(redis_url) => match redis_store::RedisStore::open(&redis_url) {
+            Ok(redis_store) => SharedStore::Redis(redis_store),
+            Err(e) => {
+                error!("cannot use Redis at REDIS_URL, caching locally instead: {e}");
+                SharedStore::Memory(MemoryStore::new())
+            }
+        },
+        Err(_) => SharedStore::Memory(MemoryStore::new()),
+    };
+    if let Ok(issuer_url) = config.get("ISSUER_URL") {
+        MetadataCache::new(store.clone()).spawn_refresher(issuer_url, METADATA_REFRESH_INTERVAL, discovery_retry(&config));
+    }
+    let router = Router::new()
+    .route("/healthz", get(handle_get_healthz))
+    .route("/metrics", get(handle_get_metrics));
+    let router = if swagger_enabled(&config) {
+        router
+        .route(SWAGGER_SPEC_URL, get(handle_get_swagger_spec))
+        .route("/api/public/swagger-ui/:path", get(handle_get_swagger_ui))
+    } else {
+        router
+    };
+    let router = router
     .route("/api/public/oidc/login-requests/:login_id", get(handle_get_login_request))
     .route("/api/public/oidc/login-target", get(handle_get_login_target))
+    .route("/api/public/widget/:siteId/count", get(handle_get_public_widget_count))
     .route("/api/sites", get(handle_get_sites))
+    .route("/api/sites", post(handle_post_sites))
+    .route("/api/sites/:siteId", put(handle_put_sites_siteid))
+    .route("/api/sites/:siteId", delete(handle_delete_sites_siteid))
     .route("/api/sites/:siteId/presence", get(handle_get_sites_siteid_presence))
+    .route("/api/sites/:siteId/presence/changes", get(handle_get_sites_siteid_presence_changes))
+    .route("/api/sites/:siteId/muster", get(handle_get_sites_siteid_muster))
+    .route("/api/sites/:siteId/presence.csv", get(handle_get_sites_siteid_presence_csv))
     .route("/api/sites/:siteId/hello", post(handle_post_sites_siteid_hello))
+    .route("/api/sites/:siteId/goodbye", post(handle_post_sites_siteid_goodbye))
     .route("/api/sites/:siteId/announce", put(handle_put_announce))
+    .route("/api/sites/:siteId/announce", delete(handle_delete_announce))
+    .route("/api/sites/:siteId/announce/validate", post(handle_post_announce_validate))
+    .route("/api/announce", delete(handle_delete_all_announcements))
+    .route("/api/presence/hello", post(handle_post_presence_hello))
+    .route("/api/me", get(handle_get_me))
+    .route("/api/me/display-name", put(handle_put_me_display_name))
+    .route("/api/me/export", get(handle_get_me_export))
+    .route("/api/favorites/presence", get(handle_get_favorites_presence))
+    .route("/api/users/:userId/announcements", get(handle_get_user_announcements))
+    .route("/api/audit", get(handle_get_audit))
     .route("/api/self/favorites/:userId", put(handle_put_favorite))
     .route("/api/self/favorites/:userId", delete(handle_delete_favorite))
     .route("/", get(handle_get_fallback))
     .route("/*path", get(handle_get_fallback))
-    .layer(Extension(MemoryStore::new()))
+    .route_layer(axum::middleware::from_fn(metrics::track_requests))
+    .layer(Extension(store))
     .with_state(state)
+    // each request gets a span with its id, which is taken from the 
+    // `x-request-id` header if the caller sent one, and returned in the response
+    .layer(TraceLayer::new_for_http().make_span_with(request_span))
+    .layer(PropagateRequestIdLayer::x_request_id())
+    .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+    // applied to the whole router so that preflight requests are answered
+    // even though there are no OPTIONS routes
+    match cors_layer(&config) {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
+}
 
+/// Span of a request, which log lines written while handling it are
+/// attributed to
+fn request_span(request: &http::Request<Body>) -> tracing::Span {
+    let request_id = request.extensions().get::<RequestId>()
+    .and_then(|id| id.header_value().to_str().ok())
+    .unwrap_or_default();
+    tracing::info_span!("request", request_id, method = %request.method(), path = request.uri().path())
+}
+
+/// CORS configuration allowing browser apps served from the origins listed
+/// in `ALLOWED_ORIGINS` (comma separated, or `*` for any) to call the API.
+/// Without `ALLOWED_ORIGINS`, no CORS headers are sent.
+fn cors_layer(config: &dyn Config) -> Option<CorsLayer> {
+    let allowed_origins = config.get("ALLOWED_ORIGINS").ok()?;
+    let allow_origin = if allowed_origins.trim() == "*" {
+        AllowOrigin::any()
+    } else {
+        let origins = allowed_origins.split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .filter_map(|o| match HeaderValue::from_str(o) {
+            Ok(origin) => Some(origin),
+            Err(e) => {
+                error!("ignoring invalid origin '{o}' in ALLOWED_ORIGINS: {e}");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+        AllowOrigin::list(origins)
+    };
+    Some(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::ACCEPT, header::IF_NONE_MATCH])
+        .expose_headers([HeaderName::from_static("x-total-count"), HeaderName::from_static("x-request-id"), header::ETAG]))
+}
+
+/// Whether swagger-ui and the OpenAPI spec are served, which is configured
+/// by `SWAGGER_ENABLED`. The client's login doesn't depend on them.
+fn swagger_enabled(config: &dyn Config) -> bool {
+    config.get_as_bool_or("SWAGGER_ENABLED", true)
 }
 
 #[debug_handler(state=VerishdaState)]
-async fn handle_get_fallback(Scheme(scheme): Scheme, Host(host): Host, OriginalUri(path): OriginalUri) -> Result<Redirect, HandlerError> {
+async fn handle_get_fallback(State(state): State<VerishdaState>, Scheme(scheme): Scheme, Host(host): Host, OriginalUri(path): OriginalUri) -> Result<Response, HandlerError> {
     let full_url = format!("{scheme}://{host}{path}");
     trace!("full_url: {full_url}");
+    let full_url = http::Uri::try_from(full_url)?;
 
-    let mut redirect_url = http::Uri::try_from(full_url)?.into_parts();
+    if let Ok(root_redirect_url) = state.config.get("ROOT_REDIRECT_URL") {
+        return Ok(Redirect::temporary(&absolute_url(&full_url, &root_redirect_url)?).into_response());
+    }
+    if !swagger_enabled(&*state.config) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let mut redirect_url = full_url.into_parts();
     redirect_url.path_and_query = Some(http::uri::PathAndQuery::from_static("/api/public/swagger-ui/oauth2-redirect.html"));
     let redirect_url = http::Uri::from_parts(redirect_url)?.to_string();
     
     let swagger_ui_url = format!("/api/public/swagger-ui/index.html?url={SWAGGER_SPEC_URL}&oauth2RedirectUrl={redirect_url}");
-    Ok(Redirect::temporary(&swagger_ui_url))
+    Ok(Redirect::temporary(&swagger_ui_url).into_response())
+}
+
+/// The given URL, or if it is just a path, that path on the host of `base`
+fn absolute_url(base: &http::Uri, url: &str) -> Result<String> {
+    if !url.starts_with('/') {
+        return Ok(url.to_string());
+    }
+    let mut parts = base.clone().into_parts();
+    parts.path_and_query = Some(http::uri::PathAndQuery::try_from(url)?);
+    Ok(http::Uri::from_parts(parts)?.to_string())
+}
+
+/// Health check for load balancers and container orchestration. Not authenticated.
+#[debug_handler(state=VerishdaState)]
+async fn handle_get_healthz(State(pool): State<ConnectionPool>) -> (StatusCode, Json<serde_json::Value>) {
+    let db_result = async {
+        let mut con = pool.acquire().await?;
+        sqlx::query("SELECT 1").execute(&mut *con).await?;
+        Ok::<_, sqlx::Error>(())
+    }.await;
+
+    match db_result {
+        Ok(()) => (StatusCode::OK, Json(serde_json::json!({"status": "ok", "db": "ok"}))),
+        Err(e) => {
+            error!("health check failed, database unavailable: {e}");
+            (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"db": "down"})))
+        }
+    }
+}
+
+/// Whether the given site is listed in the comma separated 
+/// `PUBLIC_WIDGET_SITES` config value
+fn is_public_widget_site(config: &dyn Config, site_id: &str) -> bool {
+    config.get("PUBLIC_WIDGET_SITES")
+    .map(|sites| sites.split(',').any(|s| s.trim() == site_id))
+    .unwrap_or(false)
+}
+
+/// Number of users currently present at a site, for embedding in other 
+/// pages. Not authenticated, so only available for sites listed in
+/// `PUBLIC_WIDGET_SITES`, and allows any origin to fetch it.
+#[debug_handler(state=VerishdaState)]
+async fn handle_get_public_widget_count(DbCon(mut con): DbCon, State(state): State<VerishdaState>, Path(site_id): Path<String>) -> Result<Response<Body>, HandlerError> {
+    if !is_public_widget_site(&*state.config, &site_id) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())?);
+    }
+
+    let count = site::count_present_on_site(&mut con, &site_id, presence_ttl(&*state.config)).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(serde_json::json!({"count": count}).to_string()))?)
+}
+
+/// Metrics in Prometheus text format. Not authenticated, but can be disabled
+/// by setting `METRICS_ENABLED` to `false`.
+#[debug_handler(state=VerishdaState)]
+async fn handle_get_metrics(DbCon(mut con): DbCon, State(state): State<VerishdaState>) -> Result<Response<Body>, HandlerError> {
+    if !state.config.get_as_bool_or("METRICS_ENABLED", true) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())?);
+    }
+
+    let metrics = metrics::metrics();
+    metrics.active_presences.set(site::count_active_presences(&mut con, presence_ttl(&*state.config)).await?);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", prometheus::TEXT_FORMAT)
+        .body(Body::from(metrics.encode()?))?)
 }
 
 async fn handle_get_swagger_spec() -> Result<Response<Body>, HandlerError> {
@@ -215,27 +639,79 @@ struct CodeAndStateParams {
 
 
 
+/// How long a login may take before its websocket is closed, configured
+/// via `LOGIN_TIMEOUT_SECS`
+fn login_timeout(config: &dyn Config) -> Duration {
+    config.get("LOGIN_TIMEOUT_SECS").ok()
+    .and_then(|t| t.parse::<u64>().ok())
+    .map(Duration::from_secs)
+    .unwrap_or(DEFAULT_LOGIN_TIMEOUT)
+}
+
 #[debug_handler]
 async fn handle_get_login_request(State(state): State<VerishdaState>, Path(login_id): Path<String>, ws: WebSocketUpgrade) -> impl IntoResponse {
 
     let (tx, rx) = oneshot::channel::<String>();
 
-    let prev = state.pending_logins.insert(login_id, tx);
+    let prev = state.pending_logins.insert(login_id.clone(), PendingLogin::new(tx));
     if let Some(_) = prev {
         return Err(Response::builder().status(409).body("login request already exists, terminating both".to_string()).unwrap());
     };
 
-    Ok(ws.on_upgrade(|socket|handle_login_request_ws(socket, rx)))
+    let timeout = login_timeout(&*state.config);
+    let pending_logins = state.pending_logins.clone();
+    Ok(ws.on_upgrade(move |socket|handle_login_request_ws(socket, rx, timeout, login_id, pending_logins)))
 }
 
-async fn handle_login_request_ws(mut socket: WebSocket, pending_login: oneshot::Receiver<String>) {
-    let code = match pending_login.await {
-        Ok(code) => code,
-        Err(e) => {
-            // we simply return on Err, there does not seem to be a way to distinguish between
-            // a closed oneshot and other errors
-            log::debug!("oneshot ended without receiving code: {e}");
-            return;
+/// Interval in which the login web socket is pinged while waiting for the
+/// code, so that proxies don't close it as idle
+const LOGIN_PING_INTERVAL: Duration = Duration::from_secs(20);
+
+async fn handle_login_request_ws(mut socket: WebSocket, mut pending_login: oneshot::Receiver<String>, timeout: Duration, login_id: String, pending_logins: PendingLogins) {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut ping_interval = tokio::time::interval_at(tokio::time::Instant::now() + LOGIN_PING_INTERVAL, LOGIN_PING_INTERVAL);
+    let code = loop {
+        tokio::select! {
+            code = &mut pending_login => match code {
+                Ok(code) => break code,
+                Err(e) => {
+                    // we simply return on Err, there does not seem to be a way to distinguish between
+                    // a closed oneshot and other errors
+                    log::debug!("oneshot ended without receiving code: {e}");
+                    if let Err(e) = socket.send(ws::Message::Close(None)).await {
+                        log::debug!("failed to close web socket: {e}");
+                    }
+                    return;
+                }
+            },
+            _ = tokio::time::sleep_until(deadline) => {
+                log::debug!("login {login_id} timed out");
+                pending_logins.remove(&login_id);
+                let close_frame = ws::CloseFrame {
+                    code: ws::close_code::NORMAL,
+                    reason: "login timed out".into(),
+                };
+                if let Err(e) = socket.send(ws::Message::Close(Some(close_frame))).await {
+                    log::debug!("failed to close web socket: {e}");
+                }
+                return;
+            }
+            _ = ping_interval.tick() => {
+                if let Err(e) = socket.send(ws::Message::Ping(Vec::new())).await {
+                    log::debug!("failed to ping web socket, abandoning login {login_id}: {e}");
+                    pending_logins.remove(&login_id);
+                    return;
+                }
+            }
+            // reading answers the client's pings and notices when it goes away
+            msg = socket.recv() => match msg {
+                Some(Ok(ws::Message::Close(_))) | Some(Err(_)) | None => {
+                    log::debug!("web socket of login {login_id} closed by client");
+                    pending_logins.remove(&login_id);
+                    return;
+                }
+                Some(Ok(_)) => (),
+            }
         }
     };
 
@@ -248,9 +724,9 @@ async fn handle_login_request_ws(mut socket: WebSocket, pending_login: oneshot::
 #[debug_handler]
 async fn handle_get_login_target(State(state): State<VerishdaState>, Query(code_state): Query<CodeAndStateParams>) -> Result<(), Response<String>> {
     match state.pending_logins.remove(&code_state.state) {
-        Some((_,tx)) => {
+        Some((_,pending_login)) => {
             let code = code_state.code.clone();
-            if let Err(e) = tx.send(code) {
+            if let Err(e) = pending_login.sender.send(code) {
                 return Err(Response::builder().status(404).body("login terminated before code could be sent".to_string()).unwrap())
             }
             Ok(())
@@ -262,28 +738,101 @@ async fn handle_get_login_target(State(state): State<VerishdaState>, Query(code_
     }
 }
 
+/// Default for `PRESENCE_TTL_SECS`
+const DEFAULT_PRESENCE_TTL: Duration = Duration::from_secs(300);
+
+/// How long after their last hello a user still counts as present
+fn presence_ttl(config: &dyn Config) -> Duration {
+    config.get("PRESENCE_TTL_SECS").ok()
+    .and_then(|t| t.parse::<u64>().ok())
+    .map(Duration::from_secs)
+    .unwrap_or(DEFAULT_PRESENCE_TTL)
+}
+
+/// Maximum gap between hellos that still extends a presence history
+/// session, configured via `SESSION_MERGE_GAP_MINUTES`. Defaults to the
+/// presence TTL.
+fn session_merge_gap(config: &dyn Config) -> Duration {
+    config.get("SESSION_MERGE_GAP_MINUTES").ok()
+    .and_then(|t| t.parse::<u64>().ok())
+    .map(|m| Duration::from_secs(m * 60))
+    .unwrap_or_else(|| presence_ttl(config))
+}
+
 fn range_from(offset: Option<i32>, limit: Option<i32>) -> std::ops::Range<i32> {
     let start = if let Some(offset) = offset { offset } else {0};
     let end = if let Some(limit) = limit {start + limit} else {i32::MAX};
     std::ops::Range {start, end}
 }
 
+#[derive(Deserialize)]
+struct SitesQueryParams {
+    term: Option<String>,
+    offset: Option<i32>,
+    limit: Option<i32>
+}
+
 #[derive(Deserialize)]
 struct PresenceQueryParams {
     term: Option<String>,
     favorites_only: Option<bool>,
+    present_only: Option<bool>,
+    on_date: Option<chrono::NaiveDate>,
     offset: Option<i32>,
     limit: Option<i32>
 }
 
+/// Presences at a site, or with `on_date` the users announced at the site
+/// for that date. The response carries a weak ETag, so that clients
+/// polling it get a `304 Not Modified` without the list if it didn't change.
 #[debug_handler]
-async fn handle_get_sites_siteid_presence(DbCon(mut con): DbCon, _: State<VerishdaState>, auth_info: AuthInfo, Path(site_id): Path<String>, Query(query): Query<PresenceQueryParams>) -> Result<Json<Vec<Presence>>, HandlerError> 
+async fn handle_get_sites_siteid_presence(DbCon(mut con): DbCon, State(state): State<VerishdaState>, auth_info: AuthInfo, Path(site_id): Path<String>, headers: http::HeaderMap, Query(query): Query<PresenceQueryParams>) -> Result<Response<Body>, HandlerError> 
 {   
     let term = query.term.as_ref().map(|s|s.as_str());
     let favorites_only = query.favorites_only.unwrap_or(false);
     let range = range_from(query.offset, query.limit);
-    let presences = site::get_presence_on_site(&mut con, &auth_info.subject, &to_logged_as_name(&auth_info), &site_id, range, term, favorites_only).await?;
-    Ok(Json(presences))
+    let present_only = query.present_only.unwrap_or(false);
+    let on_date = query.on_date;
+    let presence_ttl = presence_ttl(&*state.config);
+    let presences = site::get_presence_on_site(&mut con, &auth_info.subject, &to_logged_as_name(&auth_info), &site_id, range, term, favorites_only, present_only, on_date, presence_ttl).await?;
+    let total_count = site::count_presence_on_site(&mut con, &auth_info.subject, &site_id, term, favorites_only, present_only, on_date, presence_ttl).await?;
+
+    let body = serde_json::to_vec(&presences)?;
+    let etag = weak_etag(&body, total_count);
+    let if_none_match = headers.get(header::IF_NONE_MATCH)
+    .and_then(|v|v.to_str().ok());
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header("X-Total-Count", total_count.to_string());
+    if etag_matches(if_none_match, &etag) {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())?)
+    }
+    Ok(response
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))?)
+}
+
+/// A weak ETag for a response body along with its total count. The hash
+/// only needs to be stable within a server version, as changing it just 
+/// makes clients fetch the body once more.
+fn weak_etag(body: &[u8], total_count: i64) -> String {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    total_count.hash(&mut hasher);
+    format!("W/\"{:016x}\"", hasher.finish())
+}
+
+/// Whether an `If-None-Match` header value lists the given ETag, comparing
+/// weakly as required for `GET` (RFC 9110)
+fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+    .map(|tags| tags.trim() == "*" || tags.split(',').any(|tag| opaque(tag) == opaque(etag)))
+    .unwrap_or(false)
 }
 
 
@@ -296,17 +845,53 @@ fn to_logged_as_name(auth_info: &AuthInfo) -> String {
     .to_string()
 }
 
+#[derive(Deserialize)]
+struct PresenceChangesQueryParams {
+    since: chrono::DateTime<chrono::Utc>,
+}
+
+#[debug_handler]
+async fn handle_get_sites_siteid_presence_changes(DbCon(mut con): DbCon, State(state): State<VerishdaState>, auth_info: AuthInfo, Path(site_id): Path<String>, Query(query): Query<PresenceChangesQueryParams>) -> Result<Json<PresenceChanges>, HandlerError> 
+{
+    let (timestamp, presences) = site::get_presence_changes_on_site(&mut con, &auth_info.subject, &site_id, query.since, presence_ttl(&*state.config)).await?;
+    Ok(Json(PresenceChanges { timestamp, presences }))
+}
+
+#[derive(Deserialize)]
+struct HelloQueryParams {
+    source: Option<String>,
+    platform: Option<String>,
+}
+
 #[debug_handler(state=VerishdaState)]
-async fn handle_post_sites_siteid_hello(mut dbcon: DbCon, _: State<VerishdaState>, auth_info: AuthInfo, Path(site_id): Path<String>, _: State<ConnectionPool>) -> Result<StatusCode, HandlerError> {
+async fn handle_post_sites_siteid_hello(mut dbcon: DbCon, State(state): State<VerishdaState>, RequireScope(auth_info, _): RequireScope<PresenceWrite>, Path(site_id): Path<String>, Query(query): Query<HelloQueryParams>) -> Result<StatusCode, HandlerError> {
 
     let logged_as_name = to_logged_as_name(&auth_info);
-    site::hello_site(&mut dbcon.0, &auth_info.subject, &logged_as_name, &site_id).await?;
+    site::hello_site(&mut dbcon.0, &auth_info.subject, &logged_as_name, auth_info.email.as_deref(), &site_id, query.source.as_deref(), query.platform.as_deref(), session_merge_gap(&*state.config), presence_ttl(&*state.config)).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[debug_handler(state=VerishdaState)]
+async fn handle_post_sites_siteid_goodbye(DbCon(mut con): DbCon, RequireScope(auth_info, _): RequireScope<PresenceWrite>, Path(site_id): Path<String>) -> Result<StatusCode, HandlerError> {
+    site::goodbye_site(&mut con, &auth_info.subject, &site_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state=VerishdaState)]
+async fn handle_post_presence_hello(mut dbcon: DbCon, State(state): State<VerishdaState>, RequireScope(auth_info, _): RequireScope<PresenceWrite>, Query(query): Query<HelloQueryParams>, JsonBody(site_ids): JsonBody<Vec<String>>) -> Result<StatusCode, HandlerError> {
+
+    let logged_as_name = to_logged_as_name(&auth_info);
+    site::hello_sites(&mut dbcon.0, &auth_info.subject, &logged_as_name, auth_info.email.as_deref(), &site_ids, query.source.as_deref(), query.platform.as_deref(), session_merge_gap(&*state.config), presence_ttl(&*state.config)).await?;
     Ok(StatusCode::ACCEPTED)
 }
 
 #[debug_handler]
-async fn handle_put_announce(DbCon(mut con): DbCon, _: State<VerishdaState>, auth_info: AuthInfo, Path(site_id): Path<String>, Json(announcements): Json<Vec<PresenceAnnouncement>>) -> Result<impl IntoResponse, HandlerError> {
+async fn handle_put_announce(DbCon(mut con): DbCon, State(state): State<VerishdaState>, RequireScope(auth_info, _): RequireScope<AnnounceWrite>, Path(site_id): Path<String>, JsonBody(announcements): JsonBody<Vec<PresenceAnnouncement>>) -> Result<impl IntoResponse, HandlerError> {
 
+    let today = chrono::Utc::now().with_timezone(&timezone(&*state.config)).date_naive();
+    if let Some(error) = site::validate_announcements(&announcements, today).into_iter().find_map(|v|v.error) {
+        return Err(HandlerError::with_status(StatusCode::BAD_REQUEST, anyhow!(error)));
+    }
     site::announce_presence_on_site(&mut con, &auth_info.subject, &site_id, &to_logged_as_name(&auth_info), &announcements).await?;
 
     Ok(Response::builder()
@@ -315,24 +900,329 @@ async fn handle_put_announce(DbCon(mut con): DbCon, _: State<VerishdaState>, aut
     )
 }
 
+/// Validate announcements like `handle_put_announce` does, without storing
+/// them
+#[debug_handler]
+async fn handle_post_announce_validate(State(state): State<VerishdaState>, _auth_info: AuthInfo, Path(_site_id): Path<String>, JsonBody(announcements): JsonBody<Vec<PresenceAnnouncement>>) -> Result<Json<Vec<AnnouncementValidation>>, HandlerError> {
+    let today = chrono::Utc::now().with_timezone(&timezone(&*state.config)).date_naive();
+    Ok(Json(site::validate_announcements(&announcements, today)))
+}
+
+#[debug_handler]
+async fn handle_delete_announce(DbCon(mut con): DbCon, _: State<VerishdaState>, RequireScope(auth_info, _): RequireScope<AnnounceWrite>, Path(site_id): Path<String>) -> Result<StatusCode, HandlerError> {
+    site::clear_announcements(&mut con, &auth_info.subject, Some(&site_id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler]
+async fn handle_delete_all_announcements(DbCon(mut con): DbCon, _: State<VerishdaState>, RequireScope(auth_info, _): RequireScope<AnnounceWrite>) -> Result<StatusCode, HandlerError> {
+    site::clear_announcements(&mut con, &auth_info.subject, None).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 #[debug_handler]
-async fn handle_put_favorite(DbCon(mut con): DbCon, _: State<VerishdaState>, auth_info: AuthInfo, Path(user_id): Path<String>) -> Result<impl IntoResponse, HandlerError> {
+async fn handle_get_favorites_presence(DbCon(mut con): DbCon, State(state): State<VerishdaState>, auth_info: AuthInfo) -> Result<Json<Vec<FavoritePresence>>, HandlerError> {
+    let favorites = site::get_favorites_presence(&mut con, &auth_info.subject, presence_ttl(&*state.config)).await?;
+    Ok(Json(favorites))
+}
+
+/// Announcements of the given user across all sites, which only the user 
+/// themself and those having them as favorite may see
+#[debug_handler]
+async fn handle_get_user_announcements(DbCon(mut con): DbCon, _: State<VerishdaState>, auth_info: AuthInfo, Path(user_id): Path<String>) -> Result<Json<Vec<UserAnnouncement>>, HandlerError> {
+    if user_id != auth_info.subject && !site::is_favorite(&mut con, &auth_info.subject, &user_id).await? {
+        return Err(HandlerError::with_status(StatusCode::FORBIDDEN, anyhow!("announcements of {user_id} are only visible to their favorites")));
+    }
+    Ok(Json(site::get_user_announcements(&mut con, &user_id).await?))
+}
+
+#[derive(Deserialize)]
+struct AuditQueryParams {
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    offset: Option<i32>,
+    limit: Option<i32>,
+}
+
+#[debug_handler]
+async fn handle_get_audit(DbCon(mut con): DbCon, _: State<VerishdaState>, AdminAuthInfo(admin): AdminAuthInfo, Query(query): Query<AuditQueryParams>) -> Result<Json<Vec<AuditEntry>>, HandlerError> {
+    log::info!("audit log requested by {}", admin.subject);
+    let range = range_from(query.offset, query.limit);
+    Ok(Json(site::get_audit_log(&mut con, query.since, range).await?))
+}
+
+#[debug_handler]
+async fn handle_put_favorite(DbCon(mut con): DbCon, _: State<VerishdaState>, RequireScope(auth_info, _): RequireScope<FavoritesWrite>, Path(user_id): Path<String>) -> Result<impl IntoResponse, HandlerError> {
+    if user_id == auth_info.subject {
+        return Err(HandlerError::with_status(StatusCode::BAD_REQUEST, anyhow!("cannot add yourself as favorite")));
+    }
     site::add_favorite(&mut con, &auth_info.subject, &user_id).await?;
     Ok(())
 }
 
 #[debug_handler]
-async fn handle_delete_favorite(DbCon(mut con): DbCon, _: State<VerishdaState>, auth_info: AuthInfo, Path(user_id): Path<String>) -> Result<impl IntoResponse, HandlerError> {
+async fn handle_delete_favorite(DbCon(mut con): DbCon, _: State<VerishdaState>, RequireScope(auth_info, _): RequireScope<FavoritesWrite>, Path(user_id): Path<String>) -> Result<impl IntoResponse, HandlerError> {
     site::remove_favorite(&mut con, &auth_info.subject, &user_id).await?;
     Ok(())
 }
 
+#[debug_handler(state=VerishdaState)]
+async fn handle_get_me(DbCon(mut con): DbCon, auth_info: AuthInfo) -> Result<Json<Identity>, HandlerError> {
+    let preferred_name = site::get_preferred_name(&mut con, &auth_info.subject).await?;
+    let logged_as_name = preferred_name.clone().unwrap_or_else(||to_logged_as_name(&auth_info));
+    Ok(Json(Identity {
+        subject: auth_info.subject,
+        given_name: auth_info.given_name,
+        family_name: auth_info.family_name,
+        logged_as_name,
+        preferred_name,
+    }))
+}
+
+#[debug_handler(state=VerishdaState)]
+async fn handle_put_me_display_name(DbCon(mut con): DbCon, auth_info: AuthInfo, JsonBody(display_name): JsonBody<DisplayName>) -> Result<StatusCode, HandlerError> {
+    let preferred_name = display_name.preferred_name.as_deref()
+    .map(str::trim)
+    .filter(|n|!n.is_empty());
+    if preferred_name.is_some_and(|n|n.chars().count() > site::MAX_PREFERRED_NAME_LEN) {
+        return Err(HandlerError::with_status(StatusCode::BAD_REQUEST, anyhow!("display name must not be longer than {} characters", site::MAX_PREFERRED_NAME_LEN)));
+    }
+    site::set_preferred_name(&mut con, &auth_info.subject, &to_logged_as_name(&auth_info), preferred_name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state=VerishdaState)]
+async fn handle_get_me_export(DbCon(mut con): DbCon, auth_info: AuthInfo) -> Result<Response<Body>, HandlerError> {
+    let body = streamed_body("user data export", move |tx| async move {
+        site::export_user_data(&mut con, &auth_info.subject, &tx).await
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Content-Disposition", "attachment; filename=\"verishda-export.json\"")
+        .body(body)?)
+}
+
+/// Streams a CSV of the users present or announced at a site, along with 
+/// their upcoming announcements (administrators only)
 #[debug_handler]
-async fn handle_get_sites(DbCon(mut con): DbCon, State(_state): State<VerishdaState>, _auth_info: AuthInfo) -> Result<Json<Vec<Site>>, HandlerError> {
-    let sites = site::get_sites(&mut con).await?;
+async fn handle_get_sites_siteid_presence_csv(DbCon(mut con): DbCon, State(state): State<VerishdaState>, AdminAuthInfo(admin): AdminAuthInfo, Path(site_id): Path<String>) -> Result<Response<Body>, HandlerError> {
+    log::info!("presence export of site {site_id} requested by {}", admin.subject);
+
+    let today = chrono::Utc::now().with_timezone(&timezone(&*state.config)).date_naive();
+    let presence_ttl = presence_ttl(&*state.config);
+    let filename = format!("presence-{}.csv", site_id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_"));
+    let body = streamed_body("presence export", move |tx| async move {
+        site::export_presence_csv(&mut con, &site_id, today, presence_ttl, &tx).await
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/csv")
+        .header("Content-Disposition", format!("attachment; filename=\"{filename}\""))
+        .body(body)?)
+}
+
+/// Body streamed from a separate task writing it chunk by chunk
+fn streamed_body<F, Fut>(what: &'static str, write: F) -> Body
+where
+    F: FnOnce(tokio::sync::mpsc::Sender<Bytes>) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::channel::<Bytes>(16);
+    let (error_tx, error_rx) = oneshot::channel::<anyhow::Error>();
+    let writing = write(tx);
+    tokio::spawn(async move {
+        if let Err(e) = writing.await {
+            error!("{what} failed: {e}");
+            let _ = error_tx.send(e);
+        }
+    });
+
+    // end the body with an error if the export failed, so that the client 
+    // doesn't mistake a truncated document for a complete one
+    let chunks = futures_util::stream::unfold((rx, Some(error_rx)), |(mut rx, error_rx)| async move {
+        match rx.recv().await {
+            Some(chunk) => Some((Ok(chunk), (rx, error_rx))),
+            None => match error_rx?.await {
+                Ok(e) => Some((Err(e), (rx, None))),
+                Err(_) => None,
+            }
+        }
+    });
+
+    Body::from_stream(chunks)
+}
+
+#[debug_handler]
+async fn handle_get_sites(DbCon(mut con): DbCon, State(_state): State<VerishdaState>, _auth_info: AuthInfo, Query(query): Query<SitesQueryParams>) -> Result<Json<Vec<Site>>, HandlerError> {
+    let range = range_from(query.offset, query.limit);
+    let sites = site::get_sites(&mut con, range, query.term.as_deref()).await?;
     Ok(Json(sites))
 }
 
+#[debug_handler]
+async fn handle_post_sites(DbCon(mut con): DbCon, State(_state): State<VerishdaState>, _admin: AdminAuthInfo, JsonBody(site): JsonBody<Site>) -> Result<StatusCode, HandlerError> {
+    check_site_polygon(&site)?;
+    site::upsert_site(&mut con, &site).await?;
+    Ok(StatusCode::CREATED)
+}
+
+#[debug_handler]
+async fn handle_put_sites_siteid(DbCon(mut con): DbCon, State(_state): State<VerishdaState>, _admin: AdminAuthInfo, Path(site_id): Path<String>, JsonBody(site): JsonBody<Site>) -> Result<StatusCode, HandlerError> {
+    // the path determines which site is updated
+    let site = Site { id: site_id, ..site };
+    check_site_polygon(&site)?;
+    site::upsert_site(&mut con, &site).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A site's polygon is either absent or has at least three corners
+fn check_site_polygon(site: &Site) -> Result<(), HandlerError> {
+    match site.polygon.len() {
+        1 | 2 => Err(HandlerError::with_status(StatusCode::BAD_REQUEST, anyhow!("a site's polygon needs at least three corners"))),
+        _ => Ok(()),
+    }
+}
+
+#[debug_handler]
+async fn handle_delete_sites_siteid(DbCon(mut con): DbCon, State(_state): State<VerishdaState>, _admin: AdminAuthInfo, Path(site_id): Path<String>) -> Result<StatusCode, HandlerError> {
+    if site::delete_site(&mut con, &site_id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(HandlerError::with_status(StatusCode::NOT_FOUND, anyhow!("no site with id '{site_id}'")))
+    }
+}
+
+/// Everyone currently present at a site, for emergencies. Responds with 
+/// CSV if the `Accept` header asks for `text/csv`, otherwise with JSON.
+#[debug_handler]
+async fn handle_get_sites_siteid_muster(DbCon(mut con): DbCon, State(state): State<VerishdaState>, AdminAuthInfo(admin): AdminAuthInfo, Path(site_id): Path<String>, headers: http::HeaderMap) -> Result<Response<Body>, HandlerError> {
+    log::info!("muster of site {site_id} requested by {}", admin.subject);
+
+    let entries = site::get_muster(&mut con, &site_id, presence_ttl(&*state.config)).await?;
+
+    let wants_csv = headers.get(http::header::ACCEPT)
+    .and_then(|accept| accept.to_str().ok())
+    .map(|accept| accept.contains("text/csv"))
+    .unwrap_or(false);
+
+    let (content_type, body) = if wants_csv {
+        let mut csv = "user_id,name,email,last_seen,source\r\n".to_string();
+        for e in &entries {
+            let fields = [
+                e.user_id.as_str(),
+                e.name.as_deref().unwrap_or(""),
+                e.email.as_deref().unwrap_or(""),
+                &e.last_seen.and_utc().to_rfc3339(),
+                e.source.as_str(),
+            ];
+            csv += &fields.map(csv_field).join(",");
+            csv += "\r\n";
+        }
+        ("text/csv", csv)
+    } else {
+        let entries = entries.iter()
+        .map(|e| serde_json::json!({
+            "user_id": e.user_id,
+            "name": e.name,
+            "email": e.email,
+            "last_seen": e.last_seen.and_utc().to_rfc3339(),
+            "source": e.source,
+        }))
+        .collect::<Vec<_>>();
+        ("application/json", serde_json::to_string(&entries)?)
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .body(Body::from(body))?)
+}
+
+/// Quote a CSV field if needed (RFC 4180)
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Whether the given subject is listed in the comma separated 
+/// `ADMIN_SUBJECTS` config value
+fn is_admin(config: &dyn Config, subject: &str) -> bool {
+    config.get("ADMIN_SUBJECTS")
+    .map(|subjects| subjects.split(',').any(|s| s.trim() == subject))
+    .unwrap_or(false)
+}
+
+/// An OAuth scope a token must have to call an endpoint
+trait Scope {
+    const NAME: &'static str;
+}
+
+/// Scope for reporting one's presence at a site
+struct PresenceWrite;
+impl Scope for PresenceWrite {
+    const NAME: &'static str = "presence:write";
+}
+
+/// Scope for announcing future presences
+struct AnnounceWrite;
+impl Scope for AnnounceWrite {
+    const NAME: &'static str = "announce:write";
+}
+
+/// Scope for changing one's favorites
+struct FavoritesWrite;
+impl Scope for FavoritesWrite {
+    const NAME: &'static str = "favorites:write";
+}
+
+/// Whether the scope is required to be granted: only if `ENFORCE_SCOPES`
+/// is set, so that deployments not issuing these scopes are unaffected
+fn check_scope(config: &dyn Config, auth_info: &AuthInfo, scope: &str) -> Result<(), AuthError> {
+    if config.get_as_bool_or("ENFORCE_SCOPES", false) && !auth_info.scopes.iter().any(|s| s == scope) {
+        log::info!("denying access to {}, token lacks scope {scope}", auth_info.subject);
+        return Err(AuthError::Forbidden);
+    }
+    Ok(())
+}
+
+/// Authentication info of a user whose token grants the scope `S`
+struct RequireScope<S: Scope>(AuthInfo, std::marker::PhantomData<S>);
+
+#[async_trait]
+impl<S: Scope> FromRequestParts<VerishdaState> for RequireScope<S>
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &VerishdaState) -> Result<Self, Self::Rejection> {
+        let auth_info = AuthInfo::from_request_parts(parts, state).await?;
+        check_scope(&*state.config, &auth_info, S::NAME)?;
+        Ok(RequireScope(auth_info, std::marker::PhantomData))
+    }
+}
+
+/// Authentication info of a user that is allowed to administrate sites
+struct AdminAuthInfo(AuthInfo);
+
+#[async_trait]
+impl FromRequestParts<VerishdaState> for AdminAuthInfo
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &VerishdaState) -> Result<Self, Self::Rejection> {
+        let auth_info = AuthInfo::from_request_parts(parts, state).await?;
+        if !is_admin(&*state.config, &auth_info.subject) {
+            log::info!("denying admin access to {}", auth_info.subject);
+            return Err(AuthError::Forbidden);
+        }
+        Ok(AdminAuthInfo(auth_info))
+    }
+}
+
 #[async_trait]
 impl FromRequestParts<VerishdaState> for AuthInfo
 where
@@ -345,9 +1235,12 @@ where
         
         let mut ox = oidc::OidcExtension::default();
         let issuer_url = state.config.get("ISSUER_URL").or(Err(AuthError::ConfigurationError(anyhow!("ISSUER_URL not defined. Use a URL that can serve as a base URL for OIDC discovery"))))?;
-        let store = parts.extensions.get::<MemoryStore>().expect("memory store not set");
+        let client_id = state.config.get("CLIENT_ID").or(Err(AuthError::ConfigurationError(anyhow!("CLIENT_ID not defined"))))?;
+        let audience = state.config.get("AUDIENCE").ok();
+        let dev_mode = state.config.get_as_bool_or("DEV_MODE", false);
+        let store = parts.extensions.get::<SharedStore>().expect("store not set");
         let cache = MetadataCache::new(store.clone());
-        if let Err(e) = ox.init(cache, &issuer_url).await {
+        if let Err(e) = ox.init(cache, &issuer_url, &client_id, audience, dev_mode, discovery_retry(&*state.config)).await {
             return Err(AuthError::ConfigurationError(e))
         }
             // Extract the token from the authorization header
@@ -362,9 +1255,20 @@ where
         let auth_info_opt = ox.check_auth_token(bearer.token());
         trace!("auth_info {auth_info_opt:?}");
         match auth_info_opt {
-            Ok(auth_info) => Ok(auth_info),
+            Ok(auth_info) => {
+                // public routes don't authenticate, so only authenticated
+                // requests are rate limited
+                if let Some(rate_limiter) = &state.rate_limiter {
+                    if let Err(retry_after) = rate_limiter.check(&auth_info.subject, std::time::Instant::now()) {
+                        log::info!("rate limiting {}", auth_info.subject);
+                        return Err(AuthError::RateLimited(retry_after));
+                    }
+                }
+                Ok(auth_info)
+            }
             Err(e) => {
                 error!("auth error: {e}");
+                metrics::metrics().token_validation_failures_total.inc();
                 Err(AuthError::InvalidToken)
             }
         }
@@ -377,6 +1281,9 @@ enum AuthError {
     TokenMissing,
     TokenExpired,
     InvalidToken,
+    Forbidden,
+    /// too many requests, retry after the given time
+    RateLimited(Duration),
     ConfigurationError(anyhow::Error),
 }
 
@@ -394,9 +1301,124 @@ impl IntoResponse for AuthError
                 StatusCode::INTERNAL_SERVER_ERROR,
                 &format!("<h1>Authorization Configuration Error</h1><p>{error}</p>")
             ),
+            AuthError::Forbidden => status_html_of(StatusCode::FORBIDDEN, "<h1>Forbidden</h1>"),
+            AuthError::RateLimited(retry_after) => {
+                let mut resp = status_html_of(StatusCode::TOO_MANY_REQUESTS, "<h1>Too Many Requests</h1>");
+                // Retry-After is given in whole seconds
+                let retry_after_secs = retry_after.as_secs_f64().ceil() as u64;
+                resp.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs.max(1)));
+                resp
+            }
             _ => status_html_of(StatusCode::UNAUTHORIZED, "<h1>Unauthorized</h1>"),
         }
     }
 }
 
+#[test]
+fn test_is_admin() {
+    let config = verishda_config::HashMapConfig::new();
+    assert!(!is_admin(&config, "alice"));
+
+    let config = verishda_config::HashMapConfig::from(std::collections::HashMap::from([
+        ("ADMIN_SUBJECTS".to_string(), "alice, bob".to_string())
+    ]));
+    assert!(is_admin(&config, "alice"));
+    assert!(is_admin(&config, "bob"));
+    assert!(!is_admin(&config, "carol"));
+    assert!(!is_admin(&config, ""));
+}
+
+#[test]
+fn test_is_public_widget_site() {
+    let config = verishda_config::HashMapConfig::new();
+    assert!(!is_public_widget_site(&config, "hq"));
+
+    let config = verishda_config::HashMapConfig::from(std::collections::HashMap::from([
+        ("PUBLIC_WIDGET_SITES".to_string(), "hq,lab".to_string())
+    ]));
+    assert!(is_public_widget_site(&config, "hq"));
+    assert!(is_public_widget_site(&config, "lab"));
+    assert!(!is_public_widget_site(&config, "branch"));
+}
+
+#[test]
+fn test_check_scope() {
+    let auth_info = |scopes: &[&str]| AuthInfo {
+        subject: "alice".to_string(),
+        given_name: None,
+        family_name: None,
+        email: None,
+        scopes: scopes.iter().map(|s| s.to_string()).collect(),
+    };
+    let with_scope = auth_info(&["openid", PresenceWrite::NAME]);
+    let without_scope = auth_info(&["openid"]);
+
+    // scopes are not checked unless enforced
+    let config = verishda_config::HashMapConfig::new();
+    assert!(check_scope(&config, &with_scope, PresenceWrite::NAME).is_ok());
+    assert!(check_scope(&config, &without_scope, PresenceWrite::NAME).is_ok());
+
+    let config = verishda_config::HashMapConfig::from(std::collections::HashMap::from([
+        ("ENFORCE_SCOPES".to_string(), "true".to_string())
+    ]));
+    assert!(check_scope(&config, &with_scope, PresenceWrite::NAME).is_ok());
+    assert!(matches!(check_scope(&config, &without_scope, PresenceWrite::NAME), Err(AuthError::Forbidden)));
+    assert!(matches!(check_scope(&config, &with_scope, AnnounceWrite::NAME), Err(AuthError::Forbidden)));
+}
+
+#[test]
+fn test_absolute_url() {
+    let base = http::Uri::from_static("https://verishda.example.com/some/path?query");
+    assert_eq!(absolute_url(&base, "https://app.example.com/").unwrap(), "https://app.example.com/");
+    assert_eq!(absolute_url(&base, "/app/index.html").unwrap(), "https://verishda.example.com/app/index.html");
+}
+
+#[test]
+fn test_etag_matches() {
+    let etag = weak_etag(b"[]", 1);
+    assert!(etag.starts_with("W/\""));
+    assert_ne!(etag, weak_etag(b"[]", 2));
+    assert!(etag_matches(Some(&etag), &etag));
+    assert!(etag_matches(Some(&format!("\"other\", {}", etag.trim_start_matches("W/"))), &etag));
+    assert!(etag_matches(Some("*"), &etag));
+    assert!(!etag_matches(Some("W/\"other\""), &etag));
+    assert!(!etag_matches(None, &etag));
+}
+
+#[tokio::test]
+async fn test_cors_preflight_is_answered() {
+    use tower::ServiceExt;
+
+    let config = verishda_config::HashMapConfig::from(std::collections::HashMap::from([
+        ("ALLOWED_ORIGINS".to_string(), "https://app.example.com".to_string())
+    ]));
+    let router = Router::new()
+        .route("/api/sites/:siteId", put(|| async { StatusCode::NO_CONTENT }))
+        .layer(cors_layer(&config).unwrap());
+
+    let preflight = |origin: &'static str| http::Request::builder()
+        .method(Method::OPTIONS)
+        .uri("/api/sites/hq")
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = router.clone().oneshot(preflight("https://app.example.com")).await.unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+    assert!(response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap().contains("PUT"));
+
+    let response = router.oneshot(preflight("https://evil.example.com")).await.unwrap();
+    assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+    assert!(cors_layer(&verishda_config::HashMapConfig::new()).is_none());
+}
 
+#[test]
+fn test_rate_limited_response() {
+    let response = AuthError::RateLimited(Duration::from_millis(1500)).into_response();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+}
@@ -1,15 +1,16 @@
+use std::{sync::{Arc, RwLock}, collections::HashMap, time::{Duration, Instant}};
 
-
-use std::{sync::{Arc, RwLock}, collections::HashMap};
+use axum::async_trait;
 
 use crate::store::KeyByteValueStore;
 
 use anyhow::anyhow;
 
 /// A `KeyByteValueStore` implementation a HashMap.
+/// Expired entries are not returned anymore, and removed when overwritten.
 #[derive(Default, Clone)]
 pub struct MemoryStore {
-    store: Arc<RwLock<HashMap<String,Vec<u8>>>>
+    store: Arc<RwLock<HashMap<String,(Vec<u8>, Option<Instant>)>>>
 }
 impl MemoryStore {
     pub fn new() -> Self {
@@ -17,23 +18,47 @@ impl MemoryStore {
     }
 }
 
+#[async_trait]
 impl KeyByteValueStore for MemoryStore
 where 
 {
-    fn get(&self, key: &str) -> Result<Vec<u8>, anyhow::Error> {
+    async fn get(&self, key: &str) -> Result<Vec<u8>, anyhow::Error> {
         match self.store.read().unwrap().get(key) {
-            Some(v) => Ok(v.clone()),
+            Some((_, Some(expires_at))) if Instant::now() >= *expires_at => Err(anyhow!("entry expired")),
+            Some((v, _)) => Ok(v.clone()),
             None => Err(anyhow!("no entry found")),
         }
     }
 
-    fn set(&mut self, key: &str, value: Vec<u8>) -> Result<(), anyhow::Error> {
-        self.store.write().unwrap().insert(key.to_string(), value);
+    async fn set(&mut self, key: &str, value: Vec<u8>) -> Result<(), anyhow::Error> {
+        self.store.write().unwrap().insert(key.to_string(), (value, None));
         Ok(())
     }
 
-    fn delete(&mut self, key: &str) -> Result<(), anyhow::Error> {
+    async fn set_with_ttl(&mut self, key: &str, value: Vec<u8>, ttl: Duration) -> Result<(), anyhow::Error> {
+        let expires_at = Instant::now() + ttl;
+        self.store.write().unwrap().insert(key.to_string(), (value, Some(expires_at)));
+        Ok(())
+    }
+
+    async fn delete(&mut self, key: &str) -> Result<(), anyhow::Error> {
         self.store.write().unwrap().remove(key);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_memory_store_ttl() {
+    let mut store = MemoryStore::new();
+    store.set("forever", b"value".to_vec()).await.unwrap();
+    store.set_with_ttl("long", b"value".to_vec(), Duration::from_secs(3600)).await.unwrap();
+    store.set_with_ttl("expired", b"value".to_vec(), Duration::ZERO).await.unwrap();
+
+    assert_eq!(store.get("forever").await.unwrap(), b"value");
+    assert_eq!(store.get("long").await.unwrap(), b"value");
+    assert!(store.get("expired").await.is_err());
+
+    // overwriting without TTL keeps the value again
+    store.set("expired", b"value".to_vec()).await.unwrap();
+    assert_eq!(store.get("expired").await.unwrap(), b"value");
+}
@@ -0,0 +1,85 @@
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+/// Prometheus metrics collected by the server
+pub(crate) struct Metrics {
+    registry: Registry,
+    requests_total: IntCounterVec,
+    request_duration_seconds: HistogramVec,
+    pub token_validation_failures_total: IntCounter,
+    pub active_presences: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("verishda".to_string()), None)?;
+
+        let requests_total = IntCounterVec::new(
+            Opts::new("requests_total", "Number of handled HTTP requests"),
+            &["path", "method", "status"]
+        )?;
+        let request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("request_duration_seconds", "HTTP request latency"),
+            &["path", "method"]
+        )?;
+        let token_validation_failures_total = IntCounter::new(
+            "token_validation_failures_total", "Number of rejected access tokens"
+        )?;
+        let active_presences = IntGauge::new(
+            "active_presences", "Number of users currently present at any site"
+        )?;
+
+        registry.register(Box::new(requests_total.clone()))?;
+        registry.register(Box::new(request_duration_seconds.clone()))?;
+        registry.register(Box::new(token_validation_failures_total.clone()))?;
+        registry.register(Box::new(active_presences.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests_total,
+            request_duration_seconds,
+            token_validation_failures_total,
+            active_presences,
+        })
+    }
+
+    /// Render all metrics in the Prometheus text format
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+pub(crate) fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("metrics definitions are valid"))
+}
+
+/// Middleware recording request count and latency per route
+pub(crate) async fn track_requests(matched_path: Option<MatchedPath>, request: Request, next: Next) -> Response {
+    let path = matched_path
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let status = response.status().as_u16().to_string();
+    let metrics = metrics();
+    metrics.requests_total
+        .with_label_values(&[&path, &method, &status])
+        .inc();
+    metrics.request_duration_seconds
+        .with_label_values(&[&path, &method])
+        .observe(elapsed);
+
+    response
+}
@@ -2,14 +2,20 @@
 //mod client_impl;
 
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use crate::store::Cache;
 use openidconnect::reqwest::async_http_client;
 
 
 use openidconnect::{
+    AdditionalClaims,
+    Audience,
+    HttpRequest,
+    HttpResponse,
     ClientId,
-    ClientSecret,
+    IdToken,
     Nonce,
     IssuerUrl,
     RedirectUrl,
@@ -17,15 +23,45 @@ use openidconnect::{
 };
 use openidconnect::core::{
   CoreClient,
+  CoreGenderClaim,
+  CoreJsonWebKeyType,
+  CoreJweContentEncryptionAlgorithm,
+  CoreJwsSigningAlgorithm,
   CoreProviderMetadata,
-  CoreIdToken,
 };
+use serde::{Deserialize, Serialize};
 
 use crate::AuthInfo;
 
-use log::{trace, error};
+use anyhow::anyhow;
+use log::{trace, error, warn};
 
 
+/// Claims carrying the token's scopes, which IdPs name either `scope` 
+/// (a space separated string, RFC 8693) or `scp` (often an array)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ScopeClaims {
+    scope: Option<serde_json::Value>,
+    scp: Option<serde_json::Value>,
+}
+impl AdditionalClaims for ScopeClaims {}
+
+type AccessToken = IdToken<ScopeClaims, CoreGenderClaim, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm, CoreJsonWebKeyType>;
+
+/// Scopes contained in a `scope` or `scp` claim value, which may be a
+/// space separated string or an array of strings
+fn parse_scopes(value: &serde_json::Value) -> Vec<String> {
+    match value {
+        serde_json::Value::String(scopes) => scopes.split_whitespace().map(str::to_string).collect(),
+        serde_json::Value::Array(scopes) => scopes.iter()
+            .filter_map(|s| s.as_str())
+            .flat_map(str::split_whitespace)
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
 #[derive(Default)]
 pub struct OidcExtension {
     config: Option<OidcConfig>,
@@ -34,15 +70,88 @@ pub struct OidcExtension {
 struct OidcConfig {
     _provider_metadata: CoreProviderMetadata,
     client: CoreClient,
+    audience: String,
+    dev_mode: bool,
+}
+
+/// Outside of dev mode, the issuer must be reached via HTTPS, as the keys
+/// verifying tokens are fetched from it
+fn check_issuer_url(issuer_url: &str, dev_mode: bool) -> anyhow::Result<()> {
+    let url = openidconnect::url::Url::parse(issuer_url)?;
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if dev_mode => Ok(()),
+        scheme => Err(anyhow!("issuer URL {issuer_url} uses '{scheme}', only 'https' is allowed unless DEV_MODE is enabled")),
+    }
 }
 
 
-async fn fetch_metadata(issuer_url: &str) -> Result<CoreProviderMetadata, anyhow::Error> {
+/// Parse the `max-age` directive from a `Cache-Control` header value
+fn parse_max_age(cache_control: &str) -> Option<Duration> {
+    cache_control
+    .split(',')
+    .filter_map(|directive|directive.trim().strip_prefix("max-age="))
+    .find_map(|secs|secs.trim_matches('"').parse::<u64>().ok())
+    .map(Duration::from_secs)
+}
+
+fn max_age_of(response: &HttpResponse) -> Option<Duration> {
+    response.headers
+    .get_all("cache-control")
+    .iter()
+    .filter_map(|v|v.to_str().ok())
+    .find_map(parse_max_age)
+}
+
+/// How often OIDC discovery is retried when it fails. The delay between
+/// retries starts with `base_delay` and doubles with each attempt.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DiscoveryRetry {
+    pub retries: u32,
+    pub base_delay: Duration,
+}
+
+/// Fetch provider metadata (including the JWKS) via OIDC discovery, retrying
+/// as configured by `retry`. Along with the metadata, this returns the 
+/// shortest `max-age` the provider declared in the `Cache-Control` headers 
+/// of its responses, if any.
+pub(crate) async fn fetch_metadata(issuer_url: &str, retry: DiscoveryRetry) -> Result<(CoreProviderMetadata, Option<Duration>), anyhow::Error> {
+    let mut delay = retry.base_delay;
+    let mut attempt = 0;
+    loop {
+        match discover(issuer_url).await {
+            Ok(result) => return Ok(result),
+            Err(e) if attempt < retry.retries => {
+                attempt += 1;
+                warn!("OIDC discovery failed ({e}), retry {attempt}/{} in {delay:?}", retry.retries);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn discover(issuer_url: &str) -> Result<(CoreProviderMetadata, Option<Duration>), anyhow::Error> {
     trace!("acquiring provider metadata via OIDC discovery...");
     let issuer_url = IssuerUrl::new(issuer_url.to_string())?;
+
+    // discovery requests both the discovery document and the JWKS, so we 
+    // collect the max-age of all responses
+    let max_ages = Arc::new(Mutex::new(Vec::new()));
+    let http_client = |request: HttpRequest| {
+        let max_ages = max_ages.clone();
+        async move {
+            let result = async_http_client(request).await;
+            if let Ok(response) = &result {
+                max_ages.lock().unwrap().push(max_age_of(response));
+            }
+            result
+        }
+    };
     let provider_metadata_result = CoreProviderMetadata::discover_async(
         issuer_url,
-        async_http_client,
+        http_client,
     ).await;
     trace!("discovery result received.");
     let provider_metadata = match provider_metadata_result {
@@ -54,39 +163,61 @@ async fn fetch_metadata(issuer_url: &str) -> Result<CoreProviderMetadata, anyhow
     };
     trace!("provider metadata loaded successfully: {provider_metadata:?}");
 
-    Ok(provider_metadata)
+    let max_age = max_ages.lock().unwrap().iter().flatten().min().cloned();
+    Ok((provider_metadata, max_age))
 }
 
-const OIDC_METADATA_KEY: &str = "oidc_metadata";
+pub(crate) const OIDC_METADATA_KEY: &str = "oidc_metadata";
 
 impl OidcExtension {
-    pub async fn init(&mut self, mut cache: impl Cache<str, CoreProviderMetadata>, issuer_url: &str) -> anyhow::Result<()> {
+    /// Initialize the extension for the given issuer. Tokens are verified against
+    /// the provider's keys, and must list the `audience` (or the `client_id` if 
+    /// no audience is given) in their `aud` claim. 
+    /// 
+    /// `dev_mode` is for local development only: it allows issuers served via
+    /// plain HTTP and skips the audience check.
+    /// 
+    /// If the metadata isn't cached and discovery fails even after retrying,
+    /// stale metadata is used if the cache still has some.
+    pub(crate) async fn init(&mut self, mut cache: impl Cache<str, CoreProviderMetadata>, issuer_url: &str, client_id: &str, audience: Option<String>, dev_mode: bool, retry: DiscoveryRetry) -> anyhow::Result<()> {
         if self.config.is_none() {
+            check_issuer_url(issuer_url, dev_mode)?;
             trace!("having no OIDC config, initializing..");
-            let provider_metadata = match cache.get(OIDC_METADATA_KEY) {
+            let provider_metadata = match cache.get(OIDC_METADATA_KEY).await {
                 Some(m) => m,
-                None => {
-                    let m = fetch_metadata(issuer_url).await?;
-                    cache.set(OIDC_METADATA_KEY, m.clone())?;
-                    m
+                None => match fetch_metadata(issuer_url, retry).await {
+                    Ok((m, max_age)) => {
+                        cache.set(OIDC_METADATA_KEY, m.clone(), max_age).await?;
+                        m
+                    }
+                    Err(e) => match cache.get_stale(OIDC_METADATA_KEY).await {
+                        Some(m) => {
+                            warn!("OIDC discovery failed, using stale provider metadata: {e}");
+                            m
+                        }
+                        None => return Err(e),
+                    }
                 }
             };
 
             trace!("OIDC provider metadata: {provider_metadata:?}");
 
-            // Create an OpenID Connect client by specifying the client ID, client secret, authorization URL
-            // and token URL.
+            // Create an OpenID Connect client by specifying the client ID, authorization URL
+            // and token URL. We don't use a client secret: the server only verifies
+            // tokens, and a secret would also make the verifier accept HMAC signed 
+            // tokens keyed with it.
             let client =
             CoreClient::from_provider_metadata(
                 provider_metadata.clone(),
-                ClientId::new("account".to_string()),
-                Some(ClientSecret::new("client_secret".to_string())),
+                ClientId::new(client_id.to_string()),
+                None,
             )
             // Set the URL the user will be redirected to after the authorization process.
             .set_redirect_uri(RedirectUrl::new("http://redirect".to_string())?);
             trace!("OIDC client created successfully from provider metadata");
 
-            self.config = Some(OidcConfig { _provider_metadata: provider_metadata, client });
+            let audience = audience.unwrap_or_else(|| client_id.to_string());
+            self.config = Some(OidcConfig { _provider_metadata: provider_metadata, client, audience, dev_mode });
         };
         Ok(())
     }
@@ -94,9 +225,20 @@ impl OidcExtension {
     pub(crate) fn check_auth_token(&self, token_str: &str) -> anyhow::Result<AuthInfo> {
 
         // at this point we assume the access token is a JWT (like Keycloak and probably other IDPs encode their access tokens)
-        let token = CoreIdToken::from_str(token_str)?;
+        let token = AccessToken::from_str(token_str)?;
         let config = &self.config.as_ref().unwrap();
-        let claims = token.claims(&config.client.id_token_verifier(), WaiveNonceVerifier{})?;
+
+        // The audience of access tokens is not necessarily our client id (Keycloak
+        // for instance uses 'account' by default), so the verifier's built-in 
+        // audience check is replaced by our own check against the configured audience.
+        let verifier = config.client.id_token_verifier()
+        .require_audience_match(false);
+        let claims = token.claims(&verifier, WaiveNonceVerifier{})?;
+        if config.dev_mode {
+            trace!("DEV_MODE: skipping audience validation");
+        } else {
+            check_audience(claims.audiences(), &config.audience)?;
+        }
         Ok(AuthInfo{
             subject: claims.subject().to_string(),
             given_name: claims.given_name()
@@ -105,10 +247,31 @@ impl OidcExtension {
             family_name: claims.family_name()
             .and_then(|lc|lc.get(None))
             .map(|n|n.to_string()),
+            email: claims.email()
+            .map(|e|e.to_string()),
+            scopes: [&claims.additional_claims().scope, &claims.additional_claims().scp]
+            .into_iter()
+            .flatten()
+            .flat_map(parse_scopes)
+            .collect(),
         })
     }
 }
 
+/// Tokens must be issued for us, not just for any client of the same IdP
+fn check_audience(audiences: &[Audience], expected: &str) -> anyhow::Result<()> {
+    if !audiences.iter().any(|aud|aud.as_str() == expected) {
+        return Err(anyhow!("token audience {audiences:?} does not contain expected audience '{expected}'"));
+    }
+    Ok(())
+}
+
+/// Nonce verifier accepting any (or no) nonce. 
+/// 
+/// Nonces protect ID tokens against replay during the authorization flow. The
+/// tokens we verify are access tokens presented as bearer tokens, which don't
+/// carry a nonce that we could check, so waiving the check is safe here (and
+/// only here).
 struct WaiveNonceVerifier{}
 
 impl NonceVerifier for WaiveNonceVerifier {
@@ -116,3 +279,35 @@ impl NonceVerifier for WaiveNonceVerifier {
         Ok(())
     }
 }
+
+#[test]
+fn test_parse_max_age() {
+    assert_eq!(parse_max_age("max-age=3600"), Some(Duration::from_secs(3600)));
+    assert_eq!(parse_max_age("public, max-age=600, must-revalidate"), Some(Duration::from_secs(600)));
+    assert_eq!(parse_max_age("no-cache"), None);
+    assert_eq!(parse_max_age("max-age=soon"), None);
+}
+
+#[test]
+fn test_parse_scopes() {
+    assert_eq!(parse_scopes(&serde_json::json!("openid presence:write")), vec!["openid", "presence:write"]);
+    assert_eq!(parse_scopes(&serde_json::json!(["openid", "announce:write"])), vec!["openid", "announce:write"]);
+    assert_eq!(parse_scopes(&serde_json::json!("")), Vec::<String>::new());
+    assert_eq!(parse_scopes(&serde_json::json!(42)), Vec::<String>::new());
+}
+
+#[test]
+fn test_check_issuer_url() {
+    assert!(check_issuer_url("https://idp.example.com/realms/verishda", false).is_ok());
+    assert!(check_issuer_url("http://localhost:8080/realms/verishda", false).is_err());
+    assert!(check_issuer_url("http://localhost:8080/realms/verishda", true).is_ok());
+    assert!(check_issuer_url("ftp://idp.example.com", true).is_err());
+}
+
+#[test]
+fn test_check_audience() {
+    let audiences = vec![Audience::new("account".to_string()), Audience::new("verishda".to_string())];
+    assert!(check_audience(&audiences, "verishda").is_ok());
+    assert!(check_audience(&audiences, "other-client").is_err());
+    assert!(check_audience(&[], "verishda").is_err());
+}
@@ -1,21 +1,30 @@
-use std::ops::Add;
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::time::Duration;
 
-use log::trace;
+use axum::async_trait;
+use log::{error, trace};
 use openidconnect::core::CoreJsonWebKeySet;
 
 use openidconnect::core::CoreProviderMetadata;
 use serde::Deserialize;
 use serde::Serialize;
 
-use anyhow::anyhow;
-
-
 use crate::store::KeyByteValueStore;
 
 use crate::store::Cache;
+use crate::oidc::{fetch_metadata, DiscoveryRetry, OIDC_METADATA_KEY};
 
+/// cache expiry used when the provider doesn't declare a `max-age`
 const CACHE_EXPIRY_DURATION: std::time::Duration = std::time::Duration::from_secs(300);
+/// bounds for provider declared expiry durations
+const MIN_CACHE_EXPIRY_DURATION: std::time::Duration = std::time::Duration::from_secs(60);
+const MAX_CACHE_EXPIRY_DURATION: std::time::Duration = std::time::Duration::from_secs(24*60*60);
+/// how long metadata is kept after it expired, as a fallback for when the
+/// provider can't be reached
+const STALE_EXPIRY_DURATION: std::time::Duration = std::time::Duration::from_secs(7*24*60*60);
+
+/// interval for proactively refreshing cached metadata; shorter than 
+/// `CACHE_EXPIRY_DURATION` so that entries are replaced before they expire
+pub const METADATA_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(240);
 
 /// specific `Cache` implementation storing OIDC metadata
 pub struct MetadataCache<S>
@@ -31,54 +40,81 @@ where S: KeyByteValueStore {
     }
 }
 
+impl <S> MetadataCache<S>
+where S: KeyByteValueStore + Send + Sync + 'static {
+    /// Spawn a background task fetching the provider metadata (including the 
+    /// JWKS) of the given issuer every `interval` and writing it into the
+    /// store, so that `get()` nearly always finds a warm entry and requests 
+    /// don't have to wait for discovery.
+    /// 
+    /// When fetching fails, the previously cached value is kept.
+    pub(crate) fn spawn_refresher(mut self, issuer_url: String, interval: Duration, retry: DiscoveryRetry) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut refresh_interval = tokio::time::interval(interval);
+            refresh_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                refresh_interval.tick().await;
+                trace!("refreshing OIDC metadata in background");
+                match fetch_metadata(&issuer_url, retry).await {
+                    Ok((metadata, max_age)) => {
+                        if let Err(e) = self.set(OIDC_METADATA_KEY, metadata, max_age).await {
+                            error!("failed to store refreshed OIDC metadata: {e}");
+                        }
+                    }
+                    Err(e) => error!("failed to refresh OIDC metadata, keeping previous metadata: {e}"),
+                }
+            }
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct CacheItem
 {
     metadata: CoreProviderMetadata,
     keys: CoreJsonWebKeySet,
-    expires_at_secs: u64,
 }
 
 
+/// key under which a copy of the entry with the given key is kept for longer
+fn stale_key(key: &str) -> String {
+    format!("{key}.stale")
+}
+
+impl <S> MetadataCache<S>
+where S: KeyByteValueStore {
+    async fn get_item(&self, key: &str) -> Option<CoreProviderMetadata> {
+        let raw = self.store.get(key).await.ok()?;
+        let cache_item = serde_json::from_slice::<CacheItem>(&raw).ok()?;
+        Some(cache_item.metadata.set_jwks(cache_item.keys))
+    }
+}
+
+#[async_trait]
 impl <S> Cache<str,CoreProviderMetadata> for MetadataCache<S> 
 where 
-    S: KeyByteValueStore
+    S: KeyByteValueStore + Send + Sync
     {
     
-    fn get(&self, key: &str) -> Option<CoreProviderMetadata>{
+    async fn get(&self, key: &str) -> Option<CoreProviderMetadata>{
         trace!("retrieving entry from spin KVS");
-        let raw_result = self.store.get(key);
-        let now = SystemTime::now();
-        if let Ok(raw) = raw_result {
-            if let Ok(cache_item) = serde_json::from_slice::<CacheItem>(&raw) {
-                let exp = UNIX_EPOCH.add(Duration::from_secs(cache_item.expires_at_secs));
-                if now < exp {
-                    trace!("return cached metadata instead of retrieving it from source");
-                    // we hit the cache and return the content
-                    let meta = cache_item.metadata.set_jwks(cache_item.keys);
-                    return Some(meta)
-                } 
-            }            
-        }
-
-        return None;
-
+        let metadata = self.get_item(key).await?;
+        trace!("return cached metadata instead of retrieving it from source");
+        Some(metadata)
     }
-    fn set(&mut self, key: &str, v: CoreProviderMetadata) -> anyhow::Result<()> {
-        let now = SystemTime::now();
-        let exp = now.add(CACHE_EXPIRY_DURATION);
-        if let Ok(expires_at) = exp.duration_since(UNIX_EPOCH){
-            let item = CacheItem{
-                expires_at_secs: expires_at.as_secs(),
-                keys: v.jwks().clone(),
-                metadata: v.clone()
-            };
-            self.store.set(&key, serde_json::to_vec(&item).unwrap())
-            ?;
-            Ok(())
-        } else {
-            Err(anyhow!(""))
-        }
-
+    async fn get_stale(&self, key: &str) -> Option<CoreProviderMetadata> {
+        self.get_item(&stale_key(key)).await
+    }
+    async fn set(&mut self, key: &str, v: CoreProviderMetadata, max_age: Option<Duration>) -> anyhow::Result<()> {
+        let expiry_duration = max_age
+            .map(|d|d.clamp(MIN_CACHE_EXPIRY_DURATION, MAX_CACHE_EXPIRY_DURATION))
+            .unwrap_or(CACHE_EXPIRY_DURATION);
+        let item = CacheItem{
+            keys: v.jwks().clone(),
+            metadata: v
+        };
+        let item = serde_json::to_vec(&item)?;
+        self.store.set_with_ttl(&stale_key(key), item.clone(), STALE_EXPIRY_DURATION).await?;
+        self.store.set_with_ttl(&key, item, expiry_duration).await
     }
 }
\ No newline at end of file
@@ -0,0 +1,64 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::sync::oneshot;
+
+/// Default for `LOGIN_TIMEOUT_SECS`
+pub(crate) const DEFAULT_LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Pending logins, keyed by the state parameter of the login request
+pub(crate) type PendingLogins = Arc<DashMap<String, PendingLogin>>;
+
+/// A login waiting for the authorization code to be delivered by the
+/// browser. Dropping it closes the websocket of the login request.
+pub(crate) struct PendingLogin {
+    pub sender: oneshot::Sender<String>,
+    pub created: Instant,
+}
+
+impl PendingLogin {
+    pub fn new(sender: oneshot::Sender<String>) -> Self {
+        Self { sender, created: Instant::now() }
+    }
+}
+
+/// Drop all pending logins that were created more than `timeout` before `now`
+fn sweep_stale_logins(pending_logins: &DashMap<String, PendingLogin>, timeout: Duration, now: Instant) {
+    let before = pending_logins.len();
+    pending_logins.retain(|_, login| now.saturating_duration_since(login.created) < timeout);
+    let removed = before.saturating_sub(pending_logins.len());
+    if removed > 0 {
+        log::debug!("dropped {removed} stale pending login(s)");
+    }
+}
+
+/// Spawn a task periodically dropping pending logins that were not
+/// completed within `timeout`, so that abandoned logins don't pile up
+pub(crate) fn spawn_sweeper(pending_logins: PendingLogins, timeout: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval((timeout / 2).max(Duration::from_secs(1)));
+        loop {
+            interval.tick().await;
+            sweep_stale_logins(&pending_logins, timeout, Instant::now());
+        }
+    })
+}
+
+#[test]
+fn test_sweep_stale_logins() {
+    let pending_logins = DashMap::new();
+    let (tx, mut rx) = oneshot::channel();
+    pending_logins.insert("abc".to_string(), PendingLogin::new(tx));
+
+    let timeout = Duration::from_secs(300);
+    let created = pending_logins.get("abc").unwrap().created;
+
+    sweep_stale_logins(&pending_logins, timeout, created + Duration::from_secs(299));
+    assert!(pending_logins.contains_key("abc"));
+
+    sweep_stale_logins(&pending_logins, timeout, created + Duration::from_secs(301));
+    assert!(pending_logins.is_empty());
+    // the websocket waiting on the receiver gets notified
+    assert!(rx.try_recv().is_err());
+}
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use verishda_config::Config;
+
+/// Token bucket of a single subject
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Per-subject token bucket rate limiter. Each subject may make `burst`
+/// requests at once, with tokens refilling at `rate` per second.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    buckets: Arc<DashMap<String, Bucket>>,
+    rate: f64,
+    burst: f64,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            buckets: Arc::new(DashMap::new()),
+            rate,
+            burst: burst.max(1) as f64,
+        }
+    }
+
+    /// Rate limiter configured via `RATE_LIMIT_RPS` and `RATE_LIMIT_BURST`,
+    /// or `None` if rate limiting is not configured
+    pub fn from_config(config: &dyn Config) -> Option<Self> {
+        let rate = config.get("RATE_LIMIT_RPS").ok()
+            .and_then(|r| r.parse::<f64>().ok())
+            .filter(|r| *r > 0.)?;
+        let burst = config.get("RATE_LIMIT_BURST").ok()
+            .and_then(|b| b.parse::<u32>().ok())
+            .unwrap_or(rate.ceil() as u32);
+        Some(Self::new(rate, burst))
+    }
+
+    /// Take a token from the subject's bucket. If the bucket is empty, 
+    /// returns the time after which the next token is available.
+    pub fn check(&self, subject: &str, now: Instant) -> Result<(), Duration> {
+        let mut bucket = self.buckets.entry(subject.to_string()).or_insert_with(|| Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1. - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Drop the buckets that have refilled completely by `now`. They're
+    /// recreated full when needed, so this only keeps the map bounded.
+    fn sweep_full_buckets(&self, now: Instant) {
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+    }
+
+    /// Spawn a task periodically dropping full buckets, so that the buckets
+    /// of subjects no longer making requests don't pile up
+    pub fn spawn_sweeper(&self) -> tokio::task::JoinHandle<()> {
+        let limiter = self.clone();
+        let refill_time = Duration::from_secs_f64(self.burst / self.rate);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refill_time.max(Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                limiter.sweep_full_buckets(Instant::now());
+            }
+        })
+    }
+}
+
+#[test]
+fn test_rate_limit_per_subject() {
+    let limiter = RateLimiter::new(1., 3);
+    let start = Instant::now();
+
+    for _ in 0..3 {
+        assert!(limiter.check("alice", start).is_ok());
+    }
+    let retry_after = limiter.check("alice", start).unwrap_err();
+    assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+
+    // other subjects have their own bucket
+    assert!(limiter.check("bob", start).is_ok());
+
+    // tokens refill over time
+    assert!(limiter.check("alice", start + Duration::from_secs(1)).is_ok());
+    assert!(limiter.check("alice", start + Duration::from_secs(1)).is_err());
+}
+
+#[test]
+fn test_sweep_full_buckets() {
+    let limiter = RateLimiter::new(1., 3);
+    let start = Instant::now();
+    assert!(limiter.check("alice", start).is_ok());
+    assert!(limiter.check("bob", start + Duration::from_secs(2)).is_ok());
+
+    // alice's bucket is full again after 1 second, bob's isn't yet
+    limiter.sweep_full_buckets(start + Duration::from_secs(2));
+    assert!(!limiter.buckets.contains_key("alice"));
+    assert!(limiter.buckets.contains_key("bob"));
+
+    limiter.sweep_full_buckets(start + Duration::from_secs(3));
+    assert!(limiter.buckets.is_empty());
+}
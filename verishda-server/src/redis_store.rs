@@ -0,0 +1,85 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::async_trait;
+use redis::{aio::{ConnectionManager, ConnectionManagerConfig}, AsyncCommands};
+use tokio::sync::OnceCell;
+
+use crate::store::KeyByteValueStore;
+
+use anyhow::anyhow;
+
+/// prefix for all keys, so that the Redis instance can be shared with 
+/// other applications
+const KEY_PREFIX: &str = "verishda:";
+
+/// timeout for connecting to Redis and for each command, so that a slow 
+/// Redis can't stall request handling for long
+const REDIS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A `KeyByteValueStore` implementation backed by Redis, allowing several
+/// server instances to share their cached values.
+/// 
+/// Clones share the same connection, which concurrent requests use without
+/// waiting for each other. It is established on first use and re-established 
+/// by the connection manager after failures.
+#[derive(Clone)]
+pub struct RedisStore {
+    client: redis::Client,
+    connection: Arc<OnceCell<ConnectionManager>>,
+}
+
+impl RedisStore {
+    pub fn open(url: &str) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            connection: Arc::new(OnceCell::new()),
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, anyhow::Error> {
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(REDIS_TIMEOUT)
+            .set_response_timeout(REDIS_TIMEOUT)
+            .set_number_of_retries(1);
+        let connection = self.connection
+            .get_or_try_init(|| ConnectionManager::new_with_config(self.client.clone(), config))
+            .await?;
+        // the manager is a cheap handle on the shared connection
+        Ok(connection.clone())
+    }
+}
+
+#[async_trait]
+impl KeyByteValueStore for RedisStore {
+    async fn get(&self, key: &str) -> Result<Vec<u8>, anyhow::Error> {
+        let value: Option<Vec<u8>> = self.connection().await?.get(format!("{KEY_PREFIX}{key}")).await?;
+        value.ok_or_else(|| anyhow!("no entry found"))
+    }
+
+    async fn set(&mut self, key: &str, value: Vec<u8>) -> Result<(), anyhow::Error> {
+        Ok(self.connection().await?.set(format!("{KEY_PREFIX}{key}"), value).await?)
+    }
+
+    async fn set_with_ttl(&mut self, key: &str, value: Vec<u8>, ttl: Duration) -> Result<(), anyhow::Error> {
+        // Redis expects at least one second
+        let ttl_secs = ttl.as_secs().max(1);
+        Ok(self.connection().await?.set_ex(format!("{KEY_PREFIX}{key}"), value, ttl_secs).await?)
+    }
+
+    async fn delete(&mut self, key: &str) -> Result<(), anyhow::Error> {
+        Ok(self.connection().await?.del(format!("{KEY_PREFIX}{key}")).await?)
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires a Redis server configured in REDIS_URL"]
+async fn test_redis_store_roundtrip() {
+    let url = std::env::var("REDIS_URL").unwrap();
+    let mut store = RedisStore::open(&url).unwrap();
+    let key = format!("test-{}", std::process::id());
+
+    store.set(&key, b"value".to_vec()).await.unwrap();
+    assert_eq!(store.clone().get(&key).await.unwrap(), b"value");
+    store.delete(&key).await.unwrap();
+    assert!(store.get(&key).await.is_err());
+}
@@ -5,8 +5,9 @@ use crate::VerishdaState;
 
 /// Extractor which resolves the URI scheme used for the request.
 /// 
-/// Reads the 'X-Forwarded-Proto' header. In the future, it may also
-/// read the 'Forwarded' header (it does not at the moment)
+/// Uses the `FORWARDED_PROTO` config value if set, otherwise reads the
+/// 'X-Forwarded-Proto' header, then the `proto` parameter of the 
+/// 'Forwarded' header (RFC 7239). Defaults to `http`.
 #[derive(Clone,Debug)]
 pub struct Scheme(pub String);
 
@@ -26,6 +27,11 @@ impl FromRequestParts<VerishdaState> for Scheme
                 detected_scheme = x_forwarded_proto.to_str().ok().map(|s| s.to_string());
             }
         }
+        if detected_scheme.is_none() {
+            if let Some(forwarded) = parts.headers.get("Forwarded") {
+                detected_scheme = forwarded.to_str().ok().and_then(proto_of_forwarded);
+            }
+        }
 
         let scheme = match detected_scheme {
             Some(s) => s.to_string(),
@@ -34,4 +40,26 @@ impl FromRequestParts<VerishdaState> for Scheme
         
         Ok(Self(scheme))
     }
-}
\ No newline at end of file
+}
+
+/// Extract the `proto` parameter of the first element of a 'Forwarded' 
+/// header value, like `for=192.0.2.60;proto=https, for=198.51.100.17`
+fn proto_of_forwarded(forwarded: &str) -> Option<String> {
+    let first_element = forwarded.split(',').next()?;
+    first_element
+    .split(';')
+    .filter_map(|pair| pair.split_once('='))
+    .find(|(name, _)| name.trim().eq_ignore_ascii_case("proto"))
+    .map(|(_, value)| value.trim().trim_matches('"').to_string())
+    .filter(|value| !value.is_empty())
+}
+
+#[test]
+fn test_proto_of_forwarded() {
+    assert_eq!(proto_of_forwarded("proto=https"), Some("https".to_string()));
+    assert_eq!(proto_of_forwarded("for=192.0.2.60;Proto=\"https\";by=203.0.113.43"), Some("https".to_string()));
+    assert_eq!(proto_of_forwarded("for=192.0.2.60;proto=https, for=198.51.100.17;proto=http"), Some("https".to_string()));
+    assert_eq!(proto_of_forwarded("for=192.0.2.60, for=198.51.100.17;proto=https"), None);
+    assert_eq!(proto_of_forwarded("for=192.0.2.60"), None);
+    assert_eq!(proto_of_forwarded(""), None);
+}
@@ -1,21 +1,50 @@
-use std::{collections::HashMap, ops::Range};
+use std::{collections::HashMap, ops::Range, time::Duration};
 
 use anyhow::{anyhow,Result};
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+use futures_util::TryStreamExt;
+use serde_json::json;
 use sqlx::{Connection, Postgres, PgConnection, postgres::PgRow, Row};
+use tokio::sync::mpsc;
 
-use crate::verishda_dto::types::{Presence, PresenceAnnouncement, PresenceAnnouncementKind, Site};
+use crate::csv_field;
+use crate::verishda_dto::types::{AnnouncementValidation, AuditEntry, FavoritePresence, GeoPoint, Presence, PresenceAnnouncement, PresenceAnnouncementKind, Site, UserAnnouncement};
 
-pub(super) async fn get_sites(pg: &mut PgConnection) -> Result<Vec<Site>> 
+/// geofence radius (in meters) used for sites that don't define their own
+const DEFAULT_SITE_RADIUS: f32 = 100.;
+
+/// Sites ordered by name, filtered by the optional search term and limited
+/// to the given range
+#[tracing::instrument(skip(pg))]
+pub(super) async fn get_sites(pg: &mut PgConnection, range: Range<i32>, term: Option<&str>) -> Result<Vec<Site>> 
 where Result<Vec<Site>>: Send + Sync
 {
+    if range.is_empty() {
+        return Ok(Vec::new())
+    }
+    let (offset, limit) = range_to_sql_offset_limit(range, false);
+    let term = term.unwrap_or("");
 
-    let sites = sqlx::query("SELECT id, name, longitude, latitude FROM sites")
+    let sites = sqlx::query(
+        "
+        SELECT id, name, longitude, latitude, COALESCE(radius, $1), polygon FROM sites
+        WHERE ($2='' OR lower(name) LIKE concat('%',lower($2),'%'))
+        ORDER BY name
+        OFFSET $3 LIMIT $4
+        "
+    )
+    .bind(DEFAULT_SITE_RADIUS)
+    .bind(term)
+    .bind(offset)
+    .bind(limit)
     .map(|r: PgRow|Site {
         id: r.get(0),
         name: r.get(1), 
         longitude: r.get(2), 
         latitude: r.get(3),
+        radius: r.get(4),
+        polygon: polygon_from_sql(r.get(5)),
     })
     .fetch_all(pg).await?
     ;
@@ -24,24 +53,379 @@ where Result<Vec<Site>>: Send + Sync
 }
 
 
-pub(super) async fn hello_site(pg: &mut PgConnection, user_id: &str, logged_as_name: &str, site_id: &str) -> Result<()>{
+/// Polygons are stored as a flat array of alternating latitudes and 
+/// longitudes, NULL if the site has none
+fn polygon_to_sql(polygon: &[GeoPoint]) -> Option<Vec<f32>> {
+    if polygon.is_empty() {
+        return None;
+    }
+    Some(polygon.iter().flat_map(|p|[p.latitude, p.longitude]).collect())
+}
 
-    update_userinfo(pg, user_id, logged_as_name).await?;
+fn polygon_from_sql(polygon: Option<Vec<f32>>) -> Vec<GeoPoint> {
+    polygon.unwrap_or_default()
+    .chunks_exact(2)
+    .map(|c|GeoPoint { latitude: c[0], longitude: c[1] })
+    .collect()
+}
+
+/// Create the given site, or update it if a site with its id exists
+#[tracing::instrument(skip(pg))]
+pub(super) async fn upsert_site(pg: &mut PgConnection, site: &Site) -> Result<()> {
+    sqlx::query("INSERT INTO sites (id, name, longitude, latitude, radius, polygon) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (id) 
+    DO UPDATE SET name=$2, longitude=$3, latitude=$4, radius=$5, polygon=$6")
+    .bind(&site.id)
+    .bind(&site.name)
+    .bind(site.longitude)
+    .bind(site.latitude)
+    .bind(site.radius)
+    .bind(polygon_to_sql(&site.polygon))
+    .execute(pg)
+    .await?;
+
+    Ok(())
+}
+
+/// Delete the site with the given id along with all presences and 
+/// announcements for it. Returns `false` if there is no such site.
+#[tracing::instrument(skip(pg))]
+pub(super) async fn delete_site(pg: &mut PgConnection, site_id: &str) -> Result<bool> {
+    let mut tr = pg.begin().await?;
+
+    for stmt in [
+        "DELETE FROM logged_into_site WHERE site_id=$1",
+        "DELETE FROM user_announcements WHERE site_id=$1",
+        "DELETE FROM presence_history WHERE site_id=$1",
+        "DELETE FROM presence_updates WHERE site_id=$1",
+    ] {
+        sqlx::query(stmt)
+        .bind(site_id)
+        .execute(&mut *tr)
+        .await?;
+    }
+
+    let deleted = sqlx::query("DELETE FROM sites WHERE id=$1")
+    .bind(site_id)
+    .execute(&mut *tr)
+    .await?
+    .rows_affected() > 0;
+
+    // don't purge anything if the site didn't exist
+    if !deleted {
+        tr.rollback().await?;
+        return Ok(false);
+    }
+    tr.commit().await?;
+
+    Ok(true)
+}
+
+/// Source of a hello that was sent because the user's device is within the
+/// site's geofence
+pub(super) const PRESENCE_SOURCE_GEOFENCE: &str = "geofence";
+
+/// Source reported for hellos that didn't declare a known source
+const PRESENCE_SOURCE_HELLO: &str = "hello";
+
+/// Maximum length of the platform a client reports on hello
+const MAX_PLATFORM_LEN: usize = 31;
+
+#[tracing::instrument(skip(pg, email))]
+pub(super) async fn hello_site(pg: &mut PgConnection, user_id: &str, logged_as_name: &str, email: Option<&str>, site_id: &str, source: Option<&str>, platform: Option<&str>, session_merge_gap: Duration, presence_ttl: Duration) -> Result<()>{
+
+    let mut tr = pg.begin().await?;
+
+    update_userinfo(&mut tr, user_id, logged_as_name, email).await?;
+
+    let (previous_site_id, previous_last_seen) = sqlx::query("SELECT site_id, last_seen FROM logged_into_site WHERE user_id=$1")
+    .bind(user_id)
+    .map(|r: PgRow|(r.get::<Option<String>,_>(0), r.get::<Option<NaiveDateTime>,_>(1)))
+    .fetch_optional(&mut *tr)
+    .await?
+    .unwrap_or_default();
+    let previous_site_id = previous_site_id.as_deref().map(str::trim_end);
+
+    // the user leaves the site they were at before, which is a change there, too
+    let changed_site = previous_site_id != Some(site_id);
+    if let Some(previous_site_id) = previous_site_id.filter(|_|changed_site) {
+        touch_presence(&mut tr, user_id, previous_site_id).await?;
+    }
+    // keepalive hellos of a user already present aren't a change, otherwise
+    // polling for changes would return everyone present every time
+    let arrived = changed_site || !is_currently_present(previous_last_seen, Utc::now().naive_utc(), presence_ttl);
+    if arrived {
+        touch_presence(&mut tr, user_id, site_id).await?;
+    }
 
     let stmt = String::new() +
-    "INSERT INTO logged_into_site (user_id, logged_as_name, site_id, last_seen) VALUES ($1, $2, $3, now()) ON CONFLICT (user_id) 
-    DO UPDATE SET logged_as_name=$2, site_id=$3, last_seen=now()";
+    "INSERT INTO logged_into_site (user_id, logged_as_name, site_id, last_seen, source, platform) VALUES ($1, $2, $3, now(), $4, $5) ON CONFLICT (user_id) 
+    DO UPDATE SET logged_as_name=$2, site_id=$3, last_seen=now(), source=$4, platform=$5";
 
+    let source = source.filter(|s|*s == PRESENCE_SOURCE_GEOFENCE);
+    let platform = platform
+    .map(str::trim)
+    .filter(|p|!p.is_empty())
+    .map(|p|p.chars().take(MAX_PLATFORM_LEN).collect::<String>());
     sqlx::query(&stmt)
     .bind(&user_id.to_string())
     .bind(&logged_as_name.to_string())
     .bind(&site_id.to_string())
+    .bind(source)
+    .bind(&platform)
+    .execute(&mut *tr)
+    .await?;
+
+    record_presence_history(&mut tr, user_id, site_id, Utc::now(), session_merge_gap).await?;
+
+    // only check-ins are audited, not every keepalive
+    if arrived {
+        let detail = hello_audit_detail(source, platform.as_deref());
+        record_audit(&mut tr, user_id, AUDIT_ACTION_HELLO, site_id, detail.as_deref()).await?;
+    }
+
+    tr.commit().await?;
+    Ok(())
+}
+
+/// Check the user out of the given site, so that they are no longer shown
+/// as present there. Does nothing if the user isn't logged into the site.
+#[tracing::instrument(skip(pg))]
+pub(super) async fn goodbye_site(pg: &mut PgConnection, user_id: &str, site_id: &str) -> Result<()> {
+    let result = sqlx::query("DELETE FROM logged_into_site WHERE user_id=$1 AND site_id=$2")
+    .bind(user_id)
+    .bind(site_id)
+    .execute(&mut *pg)
+    .await?;
+
+    if result.rows_affected() > 0 {
+        touch_presence(pg, user_id, site_id).await?;
+    }
+
+    Ok(())
+}
+
+/// Say hello at several sites at once, e.g. when the user is within the
+/// geofences of overlapping sites. As a user can only be logged into one
+/// site at a time, the site they are already logged into is kept if it is
+/// among the given ones, so that the user doesn't alternate between sites.
+#[tracing::instrument(skip(pg, email))]
+pub(super) async fn hello_sites(pg: &mut PgConnection, user_id: &str, logged_as_name: &str, email: Option<&str>, site_ids: &[String], source: Option<&str>, platform: Option<&str>, session_merge_gap: Duration, presence_ttl: Duration) -> Result<()> {
+    let mut tr = pg.begin().await?;
+
+    let current_site_id = sqlx::query("SELECT site_id FROM logged_into_site WHERE user_id=$1")
+    .bind(user_id)
+    .map(|r: PgRow|r.get::<Option<String>,_>(0))
+    .fetch_optional(&mut *tr)
+    .await?
+    .flatten();
+
+    if let Some(site_id) = choose_hello_site(current_site_id.as_deref().map(str::trim_end), site_ids) {
+        hello_site(&mut *tr, user_id, logged_as_name, email, site_id, source, platform, session_merge_gap, presence_ttl).await?;
+    }
+
+    tr.commit().await?;
+    Ok(())
+}
+
+/// The site to log into out of the given ones, preferring the current one
+fn choose_hello_site<'a>(current_site_id: Option<&str>, site_ids: &'a [String]) -> Option<&'a str> {
+    site_ids.iter()
+    .find(|site_id| Some(site_id.as_str()) == current_site_id)
+    .or(site_ids.first())
+    .map(String::as_str)
+}
+
+/// Whether a hello at `now` continues a presence history session last seen
+/// at `last_seen`, given the maximum gap within a session
+fn extends_session(last_seen: DateTime<Utc>, now: DateTime<Utc>, session_merge_gap: Duration) -> bool {
+    let gap = TimeDelta::from_std(session_merge_gap).unwrap_or(TimeDelta::MAX);
+    now - last_seen <= gap
+}
+
+/// Extend the user's latest presence history session if it is at the same
+/// site and within the merge gap, otherwise open a new session
+pub(super) async fn record_presence_history(pg: &mut PgConnection, user_id: &str, site_id: &str, now: DateTime<Utc>, session_merge_gap: Duration) -> Result<()> {
+    let latest = sqlx::query("SELECT id, site_id, seen_at FROM presence_history WHERE user_id=$1 ORDER BY seen_at DESC LIMIT 1")
+    .bind(user_id)
+    .map(|r: PgRow|(r.get::<i64,_>(0), r.get::<String,_>(1), r.get::<DateTime<Utc>,_>(2)))
+    .fetch_optional(&mut *pg)
+    .await?;
+
+    match latest {
+        Some((id, latest_site_id, last_seen)) if latest_site_id.trim_end() == site_id && extends_session(last_seen, now, session_merge_gap) => {
+            sqlx::query("UPDATE presence_history SET seen_at=$2 WHERE id=$1")
+            .bind(id)
+            .bind(now)
+            .execute(pg)
+            .await?;
+        }
+        _ => {
+            sqlx::query("INSERT INTO presence_history (user_id, site_id, started_at, seen_at) VALUES ($1, $2, $3, $3)")
+            .bind(user_id)
+            .bind(site_id)
+            .bind(now)
+            .execute(pg)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Record that the presence or announcements of the given user at the
+/// given site have changed
+async fn touch_presence(pg: &mut PgConnection, user_id: &str, site_id: &str) -> Result<()> {
+    sqlx::query("INSERT INTO presence_updates (user_id, site_id, updated_at) VALUES ($1, $2, now()) ON CONFLICT (user_id, site_id) 
+    DO UPDATE SET updated_at=now()")
+    .bind(user_id)
+    .bind(site_id)
     .execute(pg)
     .await?;
 
     Ok(())
 }
 
+/// An entry of the list of users currently present at a site
+pub(super) struct MusterEntry {
+    pub user_id: String,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub last_seen: NaiveDateTime,
+    pub source: String,
+}
+
+/// All users currently present at the given site, i.e. who said hello 
+/// there within the presence TTL, ordered by name
+#[tracing::instrument(skip(pg))]
+pub(super) async fn get_muster(pg: &mut PgConnection, site_id: &str, presence_ttl: Duration) -> Result<Vec<MusterEntry>> {
+    let entries = sqlx::query(
+        "
+        SELECT l.user_id, COALESCE(u.preferred_name, l.logged_as_name) AS name, u.email, l.last_seen, l.source
+        FROM logged_into_site AS l
+        LEFT JOIN user_info AS u ON u.user_id=l.user_id
+        WHERE l.site_id=$1 AND l.last_seen > now() - make_interval(secs => $2)
+        ORDER BY name
+        "
+    )
+    .bind(site_id)
+    .bind(presence_ttl.as_secs_f64())
+    .map(|r: PgRow|MusterEntry {
+        user_id: r.get::<String,_>(0).trim_end().to_string(),
+        name: r.get(1),
+        email: r.get(2),
+        last_seen: r.get(3),
+        source: r.get::<Option<String>,_>(4).unwrap_or(PRESENCE_SOURCE_HELLO.to_string()),
+    })
+    .fetch_all(pg)
+    .await?;
+
+    Ok(entries)
+}
+
+/// Check each of the given announcements, in order. Singular announcements
+/// must not be before `today`, while recurring ones may start in the past as
+/// they repeat from there, but must not end before they start. A date may
+/// only be announced once per kind.
+pub(super) fn validate_announcements(announcements: &[PresenceAnnouncement], today: NaiveDate) -> Vec<AnnouncementValidation> {
+    announcements.iter()
+    .enumerate()
+    .map(|(index, a)|{
+        let recurring = a.kind == PresenceAnnouncementKind::RecurringAnnouncement;
+        let error = if !recurring && a.date < today {
+            Some(format!("cannot announce presence for {}, which is in the past", a.date))
+        } else if let Some(until) = a.recurring_until.filter(|until|recurring && *until < a.date) {
+            Some(format!("recurring announcement starting {} cannot end before, on {until}", a.date))
+        } else if announcements[..index].iter().any(|b|b.date == a.date && b.kind == a.kind) {
+            Some(format!("presence for {} is announced more than once", a.date))
+        } else {
+            None
+        };
+        AnnouncementValidation {
+            date: a.date,
+            valid: error.is_none(),
+            error,
+        }
+    })
+    .collect()
+}
+
+/// Delete yesterday's singular announcements (yesterday being relative to
+/// the given timezone) of users that have no presence history session at
+/// the announced site overlapping that day. Recurring announcements are kept. Returns the number of
+/// deleted announcements.
+#[tracing::instrument(skip(pg))]
+pub(super) async fn clear_noshow_announcements(pg: &mut PgConnection, timezone: &str) -> Result<u64> {
+    let result = sqlx::query(
+        "DELETE FROM user_announcements a 
+        WHERE NOT a.recurring 
+        AND a.present_on = (now() AT TIME ZONE $1)::date - 1
+        AND NOT EXISTS (
+            SELECT 1 FROM presence_history p 
+            WHERE p.user_id = a.user_id 
+            AND p.site_id = a.site_id 
+            AND (p.started_at AT TIME ZONE $1)::date <= a.present_on
+            AND (p.seen_at AT TIME ZONE $1)::date >= a.present_on
+        )")
+    .bind(timezone)
+    .execute(pg)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Delete singular announcements for days more than `retention_days` ago 
+/// (relative to the given timezone), and recurring announcements that ended
+/// that long ago. Returns the number of deleted announcements.
+#[tracing::instrument(skip(pg))]
+pub(super) async fn prune_old_announcements(pg: &mut PgConnection, timezone: &str, retention_days: i32) -> Result<u64> {
+    let result = sqlx::query(
+        "DELETE FROM user_announcements
+        WHERE (CASE WHEN recurring THEN recurring_until ELSE present_on END) < (now() AT TIME ZONE $1)::date - $2")
+    .bind(timezone)
+    .bind(retention_days)
+    .execute(pg)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Delete the check-ins of users that haven't said hello within the
+/// presence TTL. Returns the number of deleted rows.
+#[tracing::instrument(skip(pg))]
+pub(super) async fn prune_stale_presences(pg: &mut PgConnection, presence_ttl: Duration) -> Result<u64> {
+    let result = sqlx::query("DELETE FROM logged_into_site WHERE last_seen < now() - make_interval(secs => $1)")
+    .bind(presence_ttl.as_secs_f64())
+    .execute(pg)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Number of users that said hello at any site within the presence TTL
+#[tracing::instrument(skip(pg))]
+pub(super) async fn count_active_presences(pg: &mut PgConnection, presence_ttl: Duration) -> Result<i64> {
+    let count = sqlx::query("SELECT count(*) FROM logged_into_site WHERE last_seen > now() - make_interval(secs => $1)")
+    .bind(presence_ttl.as_secs_f64())
+    .map(|r: PgRow|r.get::<i64,_>(0))
+    .fetch_one(pg)
+    .await?;
+
+    Ok(count)
+}
+
+/// Number of users that said hello at the given site within the presence TTL
+#[tracing::instrument(skip(pg))]
+pub(super) async fn count_present_on_site(pg: &mut PgConnection, site_id: &str, presence_ttl: Duration) -> Result<i64> {
+    let count = sqlx::query("SELECT count(*) FROM logged_into_site WHERE site_id=$1 AND last_seen > now() - make_interval(secs => $2)")
+    .bind(site_id)
+    .bind(presence_ttl.as_secs_f64())
+    .map(|r: PgRow|r.get::<i64,_>(0))
+    .fetch_one(pg)
+    .await?;
+
+    Ok(count)
+}
+
 fn range_to_sql_offset_limit(range: Range<i32>, reserve_first: bool) -> (i32, i32) {
     let offset;
     let limit;
@@ -90,19 +474,43 @@ fn test_range_to_offset_limit() {
 
 }
 
-fn pgrow_to_userid_presence(r: &PgRow, self_user_id: &str) -> (String, Presence) {
+/// A user counts as currently present if they were last seen within the
+/// presence TTL
+fn is_currently_present(last_seen: Option<NaiveDateTime>, now: NaiveDateTime, presence_ttl: Duration) -> bool {
+    let ttl = TimeDelta::from_std(presence_ttl).unwrap_or(TimeDelta::MAX);
+    match now.checked_sub_signed(ttl) {
+        Some(threshold) => last_seen.filter(|d|threshold < *d).is_some(),
+        None => last_seen.is_some(),
+    }
+}
+
+#[test]
+fn test_is_currently_present() {
+    let now = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap().and_hms_opt(12, 0, 0).unwrap();
+    let ttl = Duration::from_secs(300);
+    assert!(!is_currently_present(None, now, ttl));
+    assert!(is_currently_present(Some(now), now, ttl));
+    assert!(is_currently_present(Some(now - TimeDelta::seconds(299)), now, ttl));
+    assert!(!is_currently_present(Some(now - TimeDelta::seconds(300)), now, ttl));
+    assert!(!is_currently_present(Some(now - TimeDelta::seconds(301)), now, ttl));
+    assert!(is_currently_present(Some(now - TimeDelta::seconds(599)), now, Duration::from_secs(600)));
+}
+
+fn pgrow_to_userid_presence(r: &PgRow, self_user_id: &str, presence_ttl: Duration) -> (String, Presence) {
     let last_seen: Option<NaiveDateTime> = r.get(2);
     let presence_user_id: String = r.get::<Option<String>,_>(0).unwrap();
     let is_self = presence_user_id == self_user_id;
-    let five_minutes_ago = Utc::now().naive_local().checked_sub_signed(TimeDelta::minutes(5)).unwrap();
     let is_favorite = r.get::<Option<bool>,_>(3).unwrap();
+    let currently_present = is_currently_present(last_seen, Utc::now().naive_utc(), presence_ttl);
     let presence = Presence{
         user_id: presence_user_id.clone(),
         announcements: Vec::new(),
-        currently_present: last_seen.filter(|d|five_minutes_ago < *d).is_some(),
+        currently_present,
         is_self,
         logged_as_name: r.get::<Option<String>,_>(1).unwrap(),
         is_favorite,
+        platform: r.get::<Option<String>,_>(4).filter(|_|currently_present),
+        last_seen: last_seen.map(|t|t.and_utc()),
     };
 
     (presence_user_id, presence)
@@ -116,9 +524,12 @@ fn self_presence_from_name(user_id: &str, logged_as_name: &str) -> Presence {
         logged_as_name: logged_as_name.to_string(),
         announcements: Vec::new(),
         is_self: true,
+        platform: None,
+        last_seen: None,
     }
 }
 
+#[tracing::instrument(skip(pg))]
 pub async fn add_favorite(pg: &mut PgConnection, user_id: &str, favorite_user_id: &str) -> Result<()> {
     sqlx::query("
         INSERT INTO favorite_users (owner_user_id,favorite_user_id) SELECT u.user_id, $2 FROM user_info AS u WHERE u.user_id=$1;
@@ -131,6 +542,7 @@ pub async fn add_favorite(pg: &mut PgConnection, user_id: &str, favorite_user_id
     Ok(())
 }
 
+#[tracing::instrument(skip(pg))]
 pub async fn remove_favorite(pg: &mut PgConnection, user_id: &str, favorite_user_id: &str) -> Result<()> {
     if user_id == favorite_user_id {
         return Err(anyhow!("cannot add yourself as favorite"));
@@ -146,7 +558,52 @@ pub async fn remove_favorite(pg: &mut PgConnection, user_id: &str, favorite_user
     Ok(())
 }
 
-pub async fn get_presence_on_site(pg: &mut PgConnection, user_id: &str, logged_as_name: &str, site_id: &str, range: Range<i32>, term: Option<&str>, favorites_only: bool) -> Result<Vec<Presence>> {
+/// Whether `favorite_user_id` is among the favorites of `owner_user_id`
+#[tracing::instrument(skip(pg))]
+pub(super) async fn is_favorite(pg: &mut PgConnection, owner_user_id: &str, favorite_user_id: &str) -> Result<bool> {
+    let favorite = sqlx::query("SELECT 1 FROM favorite_users WHERE owner_user_id=$1 AND favorite_user_id=$2")
+    .bind(owner_user_id)
+    .bind(favorite_user_id)
+    .fetch_optional(pg)
+    .await?
+    .is_some();
+
+    Ok(favorite)
+}
+
+/// The given user's announcements across all sites, ordered by date.
+/// Recurring announcements that ended are left out.
+#[tracing::instrument(skip(pg))]
+pub(super) async fn get_user_announcements(pg: &mut PgConnection, user_id: &str) -> Result<Vec<UserAnnouncement>> {
+    let announcements = sqlx::query("
+        SELECT a.site_id, a.present_on, a.recurring, a.recurring_until
+        FROM user_announcements AS a
+        WHERE a.user_id=$1
+        AND (NOT a.recurring OR a.recurring_until IS NULL OR a.recurring_until >= current_date)
+        ORDER BY a.present_on, a.site_id
+    ")
+    .bind(user_id)
+    .map(|r: PgRow|{
+        let recurring = r.get::<bool,_>(2);
+        UserAnnouncement {
+            site_id: r.get::<String,_>(0).trim_end().to_string(),
+            date: r.get(1),
+            kind: if recurring {
+                PresenceAnnouncementKind::RecurringAnnouncement
+            } else {
+                PresenceAnnouncementKind::SingularAnnouncement
+            },
+            recurring_until: r.get::<Option<NaiveDate>,_>(3).filter(|_|recurring),
+        }
+    })
+    .fetch_all(pg)
+    .await?;
+
+    Ok(announcements)
+}
+
+#[tracing::instrument(skip(pg))]
+pub async fn get_presence_on_site(pg: &mut PgConnection, user_id: &str, logged_as_name: &str, site_id: &str, range: Range<i32>, term: Option<&str>, favorites_only: bool, present_only: bool, on_date: Option<NaiveDate>, presence_ttl: Duration) -> Result<Vec<Presence>> {
 
     let mut tr = pg.begin().await?;
 
@@ -157,7 +614,7 @@ pub async fn get_presence_on_site(pg: &mut PgConnection, user_id: &str, logged_a
 
     log::debug!("fetching user infos..");
 
-    let self_user_at_start = term.is_none();
+    let self_user_at_start = self_user_at_start(term, present_only, on_date);
     let term = term.map(&str::to_string).unwrap_or(String::new());
 
     // get user infos with presence info, but without announcements, 
@@ -166,7 +623,7 @@ pub async fn get_presence_on_site(pg: &mut PgConnection, user_id: &str, logged_a
     if self_user_at_start && range.start == 0 {
         let row = sqlx::query(
             "
-            SELECT u.user_id, u.logged_as_name, l.last_seen, FALSE
+            SELECT u.user_id, COALESCE(u.preferred_name, u.logged_as_name) AS logged_as_name, l.last_seen, FALSE, l.platform
             FROM user_info AS u
             LEFT JOIN logged_into_site AS l ON l.user_id=u.user_id AND l.site_id=$1
             WHERE u.user_id = $2
@@ -179,7 +636,7 @@ pub async fn get_presence_on_site(pg: &mut PgConnection, user_id: &str, logged_a
         // map existing self user to Presence, or if not found
         // update userinfo and return synthetic presence
         self_user_infos = row
-        .map(|row|pgrow_to_userid_presence(&row, user_id))
+        .map(|row|pgrow_to_userid_presence(&row, user_id, presence_ttl))
         .or_else(||{
             Some((user_id.to_owned(), self_presence_from_name(user_id, logged_as_name)))
         })
@@ -194,13 +651,21 @@ pub async fn get_presence_on_site(pg: &mut PgConnection, user_id: &str, logged_a
 
     let user_infos = sqlx::query(
         "
-        SELECT u.user_id, u.logged_as_name, l.last_seen, f.owner_user_id IS NOT NULL
+        SELECT u.user_id, COALESCE(u.preferred_name, u.logged_as_name) AS logged_as_name, l.last_seen, f.owner_user_id IS NOT NULL, l.platform
         FROM user_info AS u
         LEFT JOIN logged_into_site AS l ON l.user_id=u.user_id AND l.site_id=$2
         LEFT JOIN favorite_users AS f ON f.owner_user_id=$5 AND u.user_id=f.favorite_user_id
-        WHERE ($1='' OR lower(u.logged_as_name) LIKE concat('%',lower($1),'%')) 
+        WHERE ($1='' OR lower(COALESCE(u.preferred_name, u.logged_as_name)) LIKE concat('%',lower($1),'%')) 
         AND ($6 IS FALSE OR u.user_id <> $5)
         AND ($7 IS FALSE OR f.owner_user_id IS NOT NULL)
+        AND ($8 IS FALSE OR l.last_seen > now() - make_interval(secs => $9))
+        AND ($10::DATE IS NULL OR EXISTS (
+            SELECT 1 FROM user_announcements AS a
+            WHERE a.user_id=u.user_id AND a.site_id=$2 AND (CASE WHEN a.recurring
+                THEN a.present_on <= $10 AND (a.recurring_until IS NULL OR a.recurring_until >= $10) AND ($10 - a.present_on) % 7 = 0
+                ELSE a.present_on = $10
+            END)
+        ))
         ORDER BY logged_as_name
         OFFSET $3 LIMIT $4
         "
@@ -212,11 +677,14 @@ pub async fn get_presence_on_site(pg: &mut PgConnection, user_id: &str, logged_a
     .bind(user_id)
     .bind(exclude_user_id)
     .bind(favorites_only)
+    .bind(present_only)
+    .bind(presence_ttl.as_secs_f64())
+    .bind(on_date)
     .fetch_all(&mut *tr).await?;
 
     let user_infos = user_infos
     .iter()
-    .map(|r|pgrow_to_userid_presence(r, user_id))
+    .map(|r|pgrow_to_userid_presence(r, user_id, presence_ttl))
     .collect::<Vec<(String,Presence)>>()
     ;
 
@@ -227,23 +695,148 @@ pub async fn get_presence_on_site(pg: &mut PgConnection, user_id: &str, logged_a
     .chain(user_infos.iter())
     .collect::<Vec<&(String,Presence)>>();
 
+    with_announcements(&mut tr, site_id, &presences).await
+}
+
+/// The given user's favorites across all sites, with the site each of
+/// them is currently present at, ordered by name
+#[tracing::instrument(skip(pg))]
+pub(super) async fn get_favorites_presence(pg: &mut PgConnection, owner_user_id: &str, presence_ttl: Duration) -> Result<Vec<FavoritePresence>> {
+    let now = Utc::now().naive_utc();
+    let favorites = sqlx::query(
+        "
+        SELECT u.user_id, COALESCE(u.preferred_name, u.logged_as_name) AS logged_as_name, l.site_id, l.last_seen
+        FROM favorite_users AS f
+        JOIN user_info AS u ON u.user_id=f.favorite_user_id
+        LEFT JOIN logged_into_site AS l ON l.user_id=u.user_id
+        WHERE f.owner_user_id=$1
+        ORDER BY logged_as_name
+        "
+    )
+    .bind(owner_user_id)
+    .map(|r: PgRow|{
+        let currently_present = is_currently_present(r.get(3), now, presence_ttl);
+        FavoritePresence {
+            user_id: r.get::<String,_>(0).trim_end().to_string(),
+            logged_as_name: r.get::<Option<String>,_>(1).unwrap_or_default(),
+            currently_present,
+            site_id: r.get::<Option<String>,_>(2)
+                .filter(|_|currently_present)
+                .map(|site_id|site_id.trim_end().to_string()),
+        }
+    })
+    .fetch_all(pg)
+    .await?;
+
+    Ok(favorites)
+}
+
+/// Whether the self user is listed at the start of presences regardless of
+/// filters, which is the case unless searching, listing only present
+/// users or listing users announced for a date. Otherwise, the self user is
+/// only listed if matching the filters.
+fn self_user_at_start(term: Option<&str>, present_only: bool, on_date: Option<NaiveDate>) -> bool {
+    term.is_none() && !present_only && on_date.is_none()
+}
+
+/// Total number of presences `get_presence_on_site` returns for the 
+/// given filters across all pages
+#[tracing::instrument(skip(pg))]
+pub async fn count_presence_on_site(pg: &mut PgConnection, user_id: &str, site_id: &str, term: Option<&str>, favorites_only: bool, present_only: bool, on_date: Option<NaiveDate>, presence_ttl: Duration) -> Result<i64> {
+
+    let self_user_at_start = self_user_at_start(term, present_only, on_date);
+    let term = term.map(&str::to_string).unwrap_or(String::new());
+
+    let count = sqlx::query(
+        "
+        SELECT count(*)
+        FROM user_info AS u
+        LEFT JOIN logged_into_site AS l ON l.user_id=u.user_id AND l.site_id=$5
+        LEFT JOIN favorite_users AS f ON f.owner_user_id=$2 AND u.user_id=f.favorite_user_id
+        WHERE ($1='' OR lower(COALESCE(u.preferred_name, u.logged_as_name)) LIKE concat('%',lower($1),'%')) 
+        AND ($3 IS FALSE OR u.user_id <> $2)
+        AND ($4 IS FALSE OR f.owner_user_id IS NOT NULL)
+        AND ($6 IS FALSE OR l.last_seen > now() - make_interval(secs => $7))
+        AND ($8::DATE IS NULL OR EXISTS (
+            SELECT 1 FROM user_announcements AS a
+            WHERE a.user_id=u.user_id AND a.site_id=$5 AND (CASE WHEN a.recurring
+                THEN a.present_on <= $8 AND (a.recurring_until IS NULL OR a.recurring_until >= $8) AND ($8 - a.present_on) % 7 = 0
+                ELSE a.present_on = $8
+            END)
+        ))
+        "
+    )
+    .bind(term)
+    .bind(user_id)
+    .bind(self_user_at_start)
+    .bind(favorites_only)
+    .bind(site_id)
+    .bind(present_only)
+    .bind(presence_ttl.as_secs_f64())
+    .bind(on_date)
+    .map(|r: PgRow|r.get::<i64,_>(0))
+    .fetch_one(pg).await?;
+
+    Ok(if self_user_at_start { count + 1 } else { count })
+}
+
+/// Presences of all users whose presence or announcements at the given site 
+/// changed after `since`, along with the server time to pass as `since` 
+/// when polling for the next changes
+#[tracing::instrument(skip(pg))]
+pub(super) async fn get_presence_changes_on_site(pg: &mut PgConnection, user_id: &str, site_id: &str, since: DateTime<Utc>, presence_ttl: Duration) -> Result<(DateTime<Utc>, Vec<Presence>)> {
+    let mut tr = pg.begin().await?;
+
+    let timestamp: DateTime<Utc> = sqlx::query("SELECT now()")
+    .map(|r: PgRow|r.get(0))
+    .fetch_one(&mut *tr).await?;
+
+    let user_infos = sqlx::query(
+        "
+        SELECT u.user_id, COALESCE(u.preferred_name, u.logged_as_name) AS logged_as_name, l.last_seen, f.owner_user_id IS NOT NULL, l.platform
+        FROM presence_updates AS p
+        JOIN user_info AS u ON u.user_id=p.user_id
+        LEFT JOIN logged_into_site AS l ON l.user_id=u.user_id AND l.site_id=$1
+        LEFT JOIN favorite_users AS f ON f.owner_user_id=$2 AND u.user_id=f.favorite_user_id
+        WHERE p.site_id=$1 AND p.updated_at > $3
+        ORDER BY logged_as_name
+        "
+    )
+    .bind(site_id)
+    .bind(user_id)
+    .bind(since)
+    .fetch_all(&mut *tr).await?
+    .iter()
+    .map(|r|pgrow_to_userid_presence(r, user_id, presence_ttl))
+    .collect::<Vec<(String,Presence)>>();
+
+    let presences = with_announcements(&mut tr, site_id, &user_infos.iter().collect::<Vec<_>>()).await?;
+
+    Ok((timestamp, presences))
+}
+
+/// Fill in the announcements at the given site for the given presences
+async fn with_announcements(pg: &mut PgConnection, site_id: &str, presences: &[&(String,Presence)]) -> Result<Vec<Presence>> {
+
     // query announcements for all user_ids in presences and build a map, mapping 
     // user_ids to Vecs of Announcements
     let user_ids = (&presences).iter().map(|p|p.0.clone()).collect::<Vec<_>>();
     let mut user_announcements = sqlx::query("
-        SELECT a.user_id, a.present_on, a.recurring
+        SELECT a.user_id, a.present_on, a.recurring, a.recurring_until
         FROM user_announcements AS a
         WHERE a.site_id=$1 AND a.user_id = ANY($2)
+        AND (NOT a.recurring OR a.recurring_until IS NULL OR a.recurring_until >= current_date)
     ")
     .bind(site_id)
     .bind(&user_ids)
-    .fetch_all(&mut *tr).await.expect("cannot fetch announcements")
+    .fetch_all(&mut *pg).await.expect("cannot fetch announcements")
     .iter()
     .fold(HashMap::<String,Vec<PresenceAnnouncement>>::new(), |mut m, r|{
         let user_id: String = r.get::<String,_>(0);
         let present_on: NaiveDate = r.get::<NaiveDate,_>(1);
 
         let recurring = r.get::<bool,_>(2);
+        let recurring_until = r.get::<Option<NaiveDate>,_>(3);
         if !m.contains_key(&user_id) {
             m.insert(user_id.clone(), Vec::new());
         }
@@ -255,7 +848,8 @@ pub async fn get_presence_on_site(pg: &mut PgConnection, user_id: &str, logged_a
                 PresenceAnnouncementKind::RecurringAnnouncement
             } else {
                 PresenceAnnouncementKind::SingularAnnouncement
-            }
+            },
+            recurring_until,
         });
 
         m
@@ -277,22 +871,55 @@ pub async fn get_presence_on_site(pg: &mut PgConnection, user_id: &str, logged_a
 }
 
 
-async fn update_userinfo(pg: &mut PgConnection, user_id: &str, logged_as_name: &str) -> Result<()> {
+async fn update_userinfo(pg: &mut PgConnection, user_id: &str, logged_as_name: &str, email: Option<&str>) -> Result<()> {
     
-    let stmt = "INSERT INTO user_info (user_id, logged_as_name, last_seen) VALUES ($1, $2, now()) ON CONFLICT (user_id) 
-    DO UPDATE SET logged_as_name=$2, last_seen=now()";
+    let stmt = "INSERT INTO user_info (user_id, logged_as_name, last_seen, email) VALUES ($1, $2, now(), $3) ON CONFLICT (user_id) 
+    DO UPDATE SET logged_as_name=$2, last_seen=now(), email=COALESCE($3, user_info.email)";
     
     sqlx::query(stmt)
     .bind(&user_id.to_string())
     .bind(&logged_as_name.to_string())
+    .bind(email)
     .execute(pg).await?;
     
     Ok(())
 }
 
+/// Maximum length of a preferred name, as given by the `user_info` column
+pub(super) const MAX_PREFERRED_NAME_LEN: usize = 127;
+
+/// Set the name the user is shown with instead of the one from their 
+/// token, or remove it with `None`. Unlike the token's name, it isn't
+/// overwritten by hellos and announcements.
+#[tracing::instrument(skip(pg))]
+pub(super) async fn set_preferred_name(pg: &mut PgConnection, user_id: &str, logged_as_name: &str, preferred_name: Option<&str>) -> Result<()> {
+    sqlx::query("INSERT INTO user_info (user_id, logged_as_name, last_seen, preferred_name) VALUES ($1, $2, now(), $3) ON CONFLICT (user_id) 
+    DO UPDATE SET preferred_name=$3")
+    .bind(user_id)
+    .bind(logged_as_name)
+    .bind(preferred_name)
+    .execute(pg)
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(pg))]
+pub(super) async fn get_preferred_name(pg: &mut PgConnection, user_id: &str) -> Result<Option<String>> {
+    let preferred_name = sqlx::query("SELECT preferred_name FROM user_info WHERE user_id=$1")
+    .bind(user_id)
+    .map(|r: PgRow|r.get::<Option<String>,_>(0))
+    .fetch_optional(pg)
+    .await?
+    .flatten();
+
+    Ok(preferred_name)
+}
+
+#[tracing::instrument(skip(pg))]
 pub(super) async fn announce_presence_on_site(pg: &mut PgConnection, user_id: &str, site_id: &str, logged_as_name: &str, announcements: &[PresenceAnnouncement]) -> Result<()> {
 
-    update_userinfo(pg, user_id, logged_as_name).await?;
+    update_userinfo(pg, user_id, logged_as_name, None).await?;
 
     let mut tr: sqlx::Transaction<'_, Postgres> = pg.begin().await?;
     sqlx::query("DELETE FROM user_announcements WHERE user_id=$1 AND site_id=$2")
@@ -302,16 +929,384 @@ pub(super) async fn announce_presence_on_site(pg: &mut PgConnection, user_id: &s
         .await?;
 
     for a in announcements {
-        let sql_date = a.date.format("%Y/%m/%d").to_string();
         let recurring = a.kind == PresenceAnnouncementKind::RecurringAnnouncement;
 
-        let stmt = format!("INSERT INTO user_announcements (user_id, site_id, present_on, recurring) VALUES ($1, $2, '{}', $3)", sql_date);
-        sqlx::query(&stmt)
+        let recurring_until = a.recurring_until.filter(|_|recurring);
+
+        sqlx::query("INSERT INTO user_announcements (user_id, site_id, present_on, recurring, recurring_until) VALUES ($1, $2, $3, $4, $5)")
         .bind(&user_id.to_string())
         .bind(&site_id.to_string())
+        .bind(a.date)
         .bind(recurring)
+        .bind(recurring_until)
         .execute(&mut *tr)
         .await?;
     }
+    touch_presence(&mut tr, user_id, site_id).await?;
+
+    let detail = announce_audit_detail(announcements);
+    record_audit(&mut tr, user_id, AUDIT_ACTION_ANNOUNCE, site_id, detail.as_deref()).await?;
     Ok(tr.commit().await?)
-}
\ No newline at end of file
+}
+
+/// Audit log action of a check-in at a site, keepalive hellos aren't logged
+pub(super) const AUDIT_ACTION_HELLO: &str = "hello";
+/// Audit log action of (re)placing the announcements at a site
+pub(super) const AUDIT_ACTION_ANNOUNCE: &str = "announce";
+
+/// Append an entry to the audit log. Call it within the transaction of the
+/// audited action, so that the log can't diverge from what happened.
+#[tracing::instrument(skip(pg))]
+pub(super) async fn record_audit(pg: &mut PgConnection, user_id: &str, action: &str, site_id: &str, detail: Option<&str>) -> Result<()> {
+    sqlx::query("INSERT INTO audit_log (recorded_at, user_id, action, site_id, detail) VALUES (now(), $1, $2, $3, $4)")
+    .bind(user_id)
+    .bind(action)
+    .bind(site_id)
+    .bind(detail)
+    .execute(pg)
+    .await?;
+
+    Ok(())
+}
+
+/// Delete audit log entries recorded more than `retention_days` ago.
+/// Returns the number of deleted entries.
+#[tracing::instrument(skip(pg))]
+pub(super) async fn prune_audit_log(pg: &mut PgConnection, retention_days: i32) -> Result<u64> {
+    let result = sqlx::query("DELETE FROM audit_log WHERE recorded_at < now() - make_interval(days => $1)")
+    .bind(retention_days)
+    .execute(pg)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+fn hello_audit_detail(source: Option<&str>, platform: Option<&str>) -> Option<String> {
+    let detail = [("source", source), ("platform", platform)]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|v|format!("{name}: {v}")))
+    .collect::<Vec<_>>()
+    .join(", ");
+    Some(detail).filter(|d|!d.is_empty())
+}
+
+/// The announced dates, with recurring ones marked as weekly
+fn announce_audit_detail(announcements: &[PresenceAnnouncement]) -> Option<String> {
+    let detail = announcements.iter()
+    .map(|a| match (&a.kind, a.recurring_until) {
+        (PresenceAnnouncementKind::SingularAnnouncement, _) => a.date.to_string(),
+        (PresenceAnnouncementKind::RecurringAnnouncement, None) => format!("{} weekly", a.date),
+        (PresenceAnnouncementKind::RecurringAnnouncement, Some(until)) => format!("{} weekly until {until}", a.date),
+    })
+    .collect::<Vec<_>>()
+    .join(", ");
+    Some(detail).filter(|d|!d.is_empty())
+}
+
+/// Audit log entries recorded since the given time (or all), oldest first
+#[tracing::instrument(skip(pg))]
+pub(super) async fn get_audit_log(pg: &mut PgConnection, since: Option<DateTime<Utc>>, range: Range<i32>) -> Result<Vec<AuditEntry>> {
+    if range.is_empty() {
+        return Ok(Vec::new())
+    }
+    let (offset, limit) = range_to_sql_offset_limit(range, false);
+
+    let entries = sqlx::query("
+        SELECT id, recorded_at, user_id, action, site_id, detail FROM audit_log
+        WHERE ($1::TIMESTAMPTZ IS NULL OR recorded_at >= $1)
+        ORDER BY id
+        OFFSET $2 LIMIT $3
+    ")
+    .bind(since)
+    .bind(offset)
+    .bind(limit)
+    .map(|r: PgRow|AuditEntry {
+        id: r.get(0),
+        timestamp: r.get(1),
+        user_id: r.get::<String,_>(2).trim_end().to_string(),
+        action: r.get(3),
+        site_id: r.get::<Option<String>,_>(4).map(|s|s.trim_end().to_string()),
+        detail: r.get(5),
+    })
+    .fetch_all(pg)
+    .await?;
+
+    Ok(entries)
+}
+
+/// Delete the user's announcements at the given site, or at all sites if
+/// no site is given
+#[tracing::instrument(skip(pg))]
+pub(super) async fn clear_announcements(pg: &mut PgConnection, user_id: &str, site_id: Option<&str>) -> Result<()> {
+    let mut tr = pg.begin().await?;
+
+    let mut site_ids = sqlx::query("DELETE FROM user_announcements WHERE user_id=$1 AND ($2::CHAR(36) IS NULL OR site_id=$2) RETURNING site_id")
+    .bind(user_id)
+    .bind(site_id)
+    .map(|r: PgRow|r.get::<String,_>(0).trim_end().to_string())
+    .fetch_all(&mut *tr)
+    .await?;
+    site_ids.sort();
+    site_ids.dedup();
+
+    for site_id in site_ids {
+        touch_presence(&mut tr, user_id, &site_id).await?;
+    }
+
+    tr.commit().await?;
+    Ok(())
+}
+
+async fn send_chunk(out: &mpsc::Sender<Bytes>, chunk: impl Into<String>) -> Result<()> {
+    out.send(Bytes::from(chunk.into())).await
+    .map_err(|_|anyhow!("export receiver closed"))
+}
+
+/// A user's row in the presence CSV, collected from the query's rows
+struct PresenceCsvRow {
+    user_id: String,
+    logged_as_name: String,
+    currently_present: bool,
+    announced_dates: Vec<NaiveDate>,
+    recurring_since: Vec<NaiveDate>,
+}
+
+impl PresenceCsvRow {
+    fn to_csv_line(&self) -> String {
+        let dates = |dates: &[NaiveDate]| dates.iter().map(NaiveDate::to_string).collect::<Vec<_>>().join(" ");
+        format!("{},{},{},{},{}\r\n",
+            csv_field(&self.user_id),
+            csv_field(&self.logged_as_name),
+            self.currently_present,
+            dates(&self.announced_dates),
+            dates(&self.recurring_since),
+        )
+    }
+}
+
+/// Write the users present or announced at the given site as CSV to `out`,
+/// one line per user with their upcoming singular announcements and the
+/// start dates of their weekly announcements. The CSV is sent in chunks,
+/// so that large sites don't need to be held in memory.
+#[tracing::instrument(skip(pg, out))]
+pub(super) async fn export_presence_csv(pg: &mut PgConnection, site_id: &str, today: NaiveDate, presence_ttl: Duration, out: &mpsc::Sender<Bytes>) -> Result<()> {
+    send_chunk(out, "user_id,logged_as_name,currently_present,announced_dates,recurring_since\r\n").await?;
+
+    let mut rows = sqlx::query(
+        "
+        SELECT u.user_id, COALESCE(u.preferred_name, u.logged_as_name) AS logged_as_name, l.last_seen, a.present_on, a.recurring
+        FROM user_info AS u
+        LEFT JOIN logged_into_site AS l ON l.user_id=u.user_id AND l.site_id=$1
+        LEFT JOIN user_announcements AS a ON a.user_id=u.user_id AND a.site_id=$1
+            AND (CASE WHEN a.recurring THEN a.recurring_until IS NULL OR a.recurring_until >= $2 ELSE a.present_on >= $2 END)
+        WHERE l.user_id IS NOT NULL OR a.user_id IS NOT NULL
+        ORDER BY logged_as_name, u.user_id, a.present_on
+        "
+    )
+    .bind(site_id)
+    .bind(today)
+    .fetch(&mut *pg);
+
+    // rows of the same user are adjacent, they are merged into one line
+    let now = Utc::now().naive_utc();
+    let mut current: Option<PresenceCsvRow> = None;
+    while let Some(r) = rows.try_next().await? {
+        let user_id = r.get::<String,_>(0).trim_end().to_string();
+        if current.as_ref().is_some_and(|row|row.user_id != user_id) {
+            send_chunk(out, current.take().unwrap().to_csv_line()).await?;
+        }
+        let row = current.get_or_insert_with(||PresenceCsvRow {
+            user_id,
+            logged_as_name: r.get(1),
+            currently_present: is_currently_present(r.get(2), now, presence_ttl),
+            announced_dates: Vec::new(),
+            recurring_since: Vec::new(),
+        });
+        if let Some(date) = r.get::<Option<NaiveDate>,_>(3) {
+            if r.get::<Option<bool>,_>(4).unwrap_or(false) {
+                row.recurring_since.push(date);
+            } else {
+                row.announced_dates.push(date);
+            }
+        }
+    }
+    if let Some(row) = current {
+        send_chunk(out, row.to_csv_line()).await?;
+    }
+
+    Ok(())
+}
+
+/// Write all data stored about the given user as a JSON document to `out`. 
+/// The document is sent in chunks, so that long lists don't need to be
+/// held in memory.
+#[tracing::instrument(skip(pg, out))]
+pub(super) async fn export_user_data(pg: &mut PgConnection, user_id: &str, out: &mpsc::Sender<Bytes>) -> Result<()> {
+
+    let user_info = sqlx::query("SELECT user_id, logged_as_name, last_seen, email, preferred_name FROM user_info WHERE user_id=$1")
+    .bind(user_id)
+    .fetch_optional(&mut *pg).await?
+    .map(|r|json!({
+        "user_id": r.get::<String,_>(0).trim_end(),
+        "logged_as_name": r.get::<Option<String>,_>(1),
+        "last_seen": r.get::<Option<NaiveDateTime>,_>(2).map(|d|d.and_utc().to_rfc3339()),
+        "email": r.get::<Option<String>,_>(3),
+        "preferred_name": r.get::<Option<String>,_>(4),
+    }));
+    send_chunk(out, format!("{{\"user_info\":{}", json!(user_info))).await?;
+
+    send_chunk(out, ",\"favorites\":[").await?;
+    let mut rows = sqlx::query("SELECT favorite_user_id FROM favorite_users WHERE owner_user_id=$1 ORDER BY favorite_user_id")
+    .bind(user_id)
+    .fetch(&mut *pg);
+    let mut separator = "";
+    while let Some(r) = rows.try_next().await? {
+        let favorite_user_id = json!(r.get::<String,_>(0).trim_end());
+        send_chunk(out, format!("{separator}{favorite_user_id}")).await?;
+        separator = ",";
+    }
+    drop(rows);
+
+    send_chunk(out, "],\"announcements\":[").await?;
+    let mut rows = sqlx::query("SELECT site_id, present_on, recurring, recurring_until FROM user_announcements WHERE user_id=$1 ORDER BY present_on")
+    .bind(user_id)
+    .fetch(&mut *pg);
+    let mut separator = "";
+    while let Some(r) = rows.try_next().await? {
+        let announcement = json!({
+            "site_id": r.get::<String,_>(0).trim_end(),
+            "date": r.get::<NaiveDate,_>(1).to_string(),
+            "recurring": r.get::<bool,_>(2),
+            "recurring_until": r.get::<Option<NaiveDate>,_>(3).map(|d|d.to_string()),
+        });
+        send_chunk(out, format!("{separator}{announcement}")).await?;
+        separator = ",";
+    }
+    drop(rows);
+
+    send_chunk(out, "],\"presences\":[").await?;
+    let mut rows = sqlx::query("SELECT site_id, logged_as_name, last_seen FROM logged_into_site WHERE user_id=$1")
+    .bind(user_id)
+    .fetch(&mut *pg);
+    let mut separator = "";
+    while let Some(r) = rows.try_next().await? {
+        let presence = json!({
+            "site_id": r.get::<String,_>(0).trim_end(),
+            "logged_as_name": r.get::<Option<String>,_>(1),
+            "last_seen": r.get::<Option<NaiveDateTime>,_>(2).map(|d|d.and_utc().to_rfc3339()),
+        });
+        send_chunk(out, format!("{separator}{presence}")).await?;
+        separator = ",";
+    }
+    drop(rows);
+
+    send_chunk(out, "],\"presence_history\":[").await?;
+    let mut rows = sqlx::query("SELECT site_id, started_at, seen_at FROM presence_history WHERE user_id=$1 ORDER BY started_at")
+    .bind(user_id)
+    .fetch(&mut *pg);
+    let mut separator = "";
+    while let Some(r) = rows.try_next().await? {
+        let entry = json!({
+            "site_id": r.get::<String,_>(0).trim_end(),
+            "started_at": r.get::<DateTime<Utc>,_>(1).to_rfc3339(),
+            "seen_at": r.get::<DateTime<Utc>,_>(2).to_rfc3339(),
+        });
+        send_chunk(out, format!("{separator}{entry}")).await?;
+        separator = ",";
+    }
+    drop(rows);
+
+    send_chunk(out, "]}").await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_extends_session() {
+    let now = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap().and_hms_opt(12, 0, 0).unwrap().and_utc();
+    let gap = Duration::from_secs(600);
+    assert!(extends_session(now, now, gap));
+    assert!(extends_session(now - TimeDelta::seconds(600), now, gap));
+    assert!(!extends_session(now - TimeDelta::seconds(601), now, gap));
+}
+
+#[test]
+fn test_choose_hello_site() {
+    let site_ids = vec!["a".to_string(), "b".to_string()];
+    assert_eq!(choose_hello_site(None, &site_ids), Some("a"));
+    assert_eq!(choose_hello_site(Some("b"), &site_ids), Some("b"));
+    assert_eq!(choose_hello_site(Some("c"), &site_ids), Some("a"));
+    assert_eq!(choose_hello_site(Some("a"), &[]), None);
+}
+
+#[test]
+fn test_validate_announcements() {
+    let today = NaiveDate::from_ymd_opt(2030, 1, 7).unwrap();
+    let yesterday = NaiveDate::from_ymd_opt(2030, 1, 6).unwrap();
+    let announcement = |date, kind| PresenceAnnouncement { date, kind, recurring_until: None };
+    let errors = |announcements: &[PresenceAnnouncement]| validate_announcements(announcements, today).into_iter()
+        .map(|v|v.error)
+        .collect::<Vec<_>>();
+
+    assert!(errors(&[]).is_empty());
+    assert_eq!(errors(&[announcement(today, PresenceAnnouncementKind::SingularAnnouncement)]), vec![None]);
+    assert_eq!(errors(&[announcement(yesterday, PresenceAnnouncementKind::RecurringAnnouncement)]), vec![None]);
+    assert_eq!(errors(&[
+        announcement(today, PresenceAnnouncementKind::SingularAnnouncement),
+        announcement(yesterday, PresenceAnnouncementKind::SingularAnnouncement),
+    ]), vec![None, Some("cannot announce presence for 2030-01-06, which is in the past".to_string())]);
+
+    let ending_before_start = PresenceAnnouncement { 
+        recurring_until: Some(yesterday), 
+        ..announcement(today, PresenceAnnouncementKind::RecurringAnnouncement)
+    };
+    assert_eq!(errors(&[ending_before_start]), vec![Some("recurring announcement starting 2030-01-07 cannot end before, on 2030-01-06".to_string())]);
+
+    let validations = validate_announcements(&[
+        announcement(today, PresenceAnnouncementKind::SingularAnnouncement),
+        announcement(today, PresenceAnnouncementKind::RecurringAnnouncement),
+        announcement(today, PresenceAnnouncementKind::SingularAnnouncement),
+    ], today);
+    assert_eq!(validations.iter().map(|v|v.valid).collect::<Vec<_>>(), vec![true, true, false]);
+    assert_eq!(validations[2].date, today);
+}
+
+#[test]
+fn test_presence_csv_line() {
+    let row = PresenceCsvRow {
+        user_id: "00000000-0000-0000-0000-000000000001".to_string(),
+        logged_as_name: "Mustermax, \"Maxi\"".to_string(),
+        currently_present: true,
+        announced_dates: vec![NaiveDate::from_ymd_opt(2030, 1, 7).unwrap(), NaiveDate::from_ymd_opt(2030, 1, 9).unwrap()],
+        recurring_since: vec![],
+    };
+    assert_eq!(row.to_csv_line(), "00000000-0000-0000-0000-000000000001,\"Mustermax, \"\"Maxi\"\"\",true,2030-01-07 2030-01-09,\r\n");
+}
+
+#[test]
+fn test_polygon_sql_roundtrip() {
+    assert_eq!(polygon_to_sql(&[]), None);
+    assert!(polygon_from_sql(None).is_empty());
+
+    let polygon = [(48.5, 9.2), (48.6, 9.2), (48.6, 9.3)].map(|(latitude, longitude)|GeoPoint { latitude, longitude });
+    let stored = polygon_to_sql(&polygon);
+    assert_eq!(stored, Some(vec![48.5, 9.2, 48.6, 9.2, 48.6, 9.3]));
+    let loaded = polygon_from_sql(stored).iter().map(|p|(p.latitude, p.longitude)).collect::<Vec<_>>();
+    assert_eq!(loaded, vec![(48.5, 9.2), (48.6, 9.2), (48.6, 9.3)]);
+}
+
+#[test]
+fn test_audit_details() {
+    assert_eq!(hello_audit_detail(None, None), None);
+    assert_eq!(hello_audit_detail(Some("geofence"), Some("Windows")).as_deref(), Some("source: geofence, platform: Windows"));
+    assert_eq!(hello_audit_detail(None, Some("Android")).as_deref(), Some("platform: Android"));
+
+    let monday = NaiveDate::from_ymd_opt(2030, 1, 7).unwrap();
+    assert_eq!(announce_audit_detail(&[]), None);
+    let detail = announce_audit_detail(&[
+        PresenceAnnouncement { date: monday, kind: PresenceAnnouncementKind::SingularAnnouncement, recurring_until: None },
+        PresenceAnnouncement { date: monday, kind: PresenceAnnouncementKind::RecurringAnnouncement, recurring_until: None },
+        PresenceAnnouncement { date: monday, kind: PresenceAnnouncementKind::RecurringAnnouncement, recurring_until: Some(monday + TimeDelta::days(28)) },
+    ]);
+    assert_eq!(detail.as_deref(), Some("2030-01-07, 2030-01-07 weekly, 2030-01-07 weekly until 2030-02-04"));
+}
@@ -1,22 +1,38 @@
 
+use std::time::Duration;
+
+use axum::async_trait;
+
+use crate::memory_store::MemoryStore;
+use crate::redis_store::RedisStore;
+
 /// A very simple definition of a cache. 
 /// 
 /// Implementations may provide special behaviour, and may use more complex structures like
 /// a `KeyByteValueStore` (e.g. as a storage layer).
+#[async_trait]
 pub trait Cache<K,V> 
-where K: ?Sized
+where K: ?Sized, V: Send + 'static
 {
-    fn get(&self, key: &str) -> Option<V>;
-    fn set(&mut self, key: &str, v: V) -> anyhow::Result<()>;
-    fn try_get_or_else(&mut self, key: &str, f: impl FnOnce(&str)->Result<V, anyhow::Error>) -> Result<V,anyhow::Error> 
-    where V: Clone
+    async fn get(&self, key: &str) -> Option<V>;
+    /// Store a value. `max_age` is a hint by the value's source (like a HTTP 
+    /// `Cache-Control` header) for how long the value may be cached; if `None`, 
+    /// the implementation's default applies.
+    async fn set(&mut self, key: &str, v: V, max_age: Option<Duration>) -> anyhow::Result<()>;
+    /// A value that may have expired already, for when a fresh one can't 
+    /// be obtained. Implementations not keeping expired values return `None`.
+    async fn get_stale(&self, _key: &str) -> Option<V> {
+        None
+    }
+    async fn try_get_or_else<F>(&mut self, key: &str, f: F) -> Result<V,anyhow::Error> 
+    where V: Clone, F: FnOnce(&str)->Result<V, anyhow::Error> + Send
       {
-        if let Some(v) = self.get(key) {
+        if let Some(v) = self.get(key).await {
             return Ok(v);
         }
         match f(key) {
             Ok(v) => {
-                self.set(key, v.clone())?;
+                self.set(key, v.clone(), None).await?;
                 Ok(v)
             },
             Err(e) => Err(e),
@@ -29,9 +45,56 @@ where K: ?Sized
 /// values. 
 /// 
 /// The goal is to decouple KVS implementations (e.g. spin) from the places
-/// where it's used (e.g. OIDC metadata caching logic).
+/// where it's used (e.g. OIDC metadata caching logic). The methods are async, 
+/// so that stores reached over the network don't block the runtime's threads.
+#[async_trait]
 pub trait KeyByteValueStore {
-    fn get(&self, key: &str) -> Result<Vec<u8>, anyhow::Error>;
-    fn set(&mut self, key: &str, value: Vec<u8>) -> Result<(), anyhow::Error>;
-    fn delete(&mut self, key: &str) -> Result<(), anyhow::Error>;
-}
\ No newline at end of file
+    async fn get(&self, key: &str) -> Result<Vec<u8>, anyhow::Error>;
+    async fn set(&mut self, key: &str, value: Vec<u8>) -> Result<(), anyhow::Error>;
+    /// Store a value that `get` no longer returns once `ttl` has elapsed.
+    /// The default implementation ignores the TTL and keeps the value 
+    /// until it is overwritten or deleted.
+    async fn set_with_ttl(&mut self, key: &str, value: Vec<u8>, _ttl: Duration) -> Result<(), anyhow::Error> {
+        self.set(key, value).await
+    }
+    async fn delete(&mut self, key: &str) -> Result<(), anyhow::Error>;
+}
+
+/// The `KeyByteValueStore` used by the server: a Redis server shared by
+/// all instances if configured, otherwise a store local to this instance
+#[derive(Clone)]
+pub enum SharedStore {
+    Memory(MemoryStore),
+    Redis(RedisStore),
+}
+
+#[async_trait]
+impl KeyByteValueStore for SharedStore {
+    async fn get(&self, key: &str) -> Result<Vec<u8>, anyhow::Error> {
+        match self {
+            Self::Memory(store) => store.get(key).await,
+            Self::Redis(store) => store.get(key).await,
+        }
+    }
+
+    async fn set(&mut self, key: &str, value: Vec<u8>) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Memory(store) => store.set(key, value).await,
+            Self::Redis(store) => store.set(key, value).await,
+        }
+    }
+
+    async fn set_with_ttl(&mut self, key: &str, value: Vec<u8>, ttl: Duration) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Memory(store) => store.set_with_ttl(key, value, ttl).await,
+            Self::Redis(store) => store.set_with_ttl(key, value, ttl).await,
+        }
+    }
+
+    async fn delete(&mut self, key: &str) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Memory(store) => store.delete(key).await,
+            Self::Redis(store) => store.delete(key).await,
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use anyhow::Result;
+use keyring::Entry;
+
+/// Service name under which credentials are kept in the OS keyring
+/// (Keychain on macOS, Credential Manager on Windows, Secret Service on Linux)
+const KEYRING_SERVICE: &str = "com.pachler.verishda";
+const REFRESH_TOKEN_USER: &str = "refresh_token";
+
+fn refresh_token_entry() -> keyring::Result<Entry> {
+    Entry::new(KEYRING_SERVICE, REFRESH_TOKEN_USER)
+}
+
+/// Load the refresh token stored by a previous session, if there is any
+pub(super) fn load_refresh_token() -> Option<String> {
+    match refresh_token_entry().and_then(|entry| entry.get_password()) {
+        Ok(refresh_token) => Some(refresh_token),
+        Err(keyring::Error::NoEntry) => None,
+        Err(e) => {
+            log::error!("cannot read refresh token from keyring: {e}");
+            None
+        }
+    }
+}
+
+/// Store the refresh token so that the next session can log in silently
+pub(super) fn store_refresh_token(refresh_token: &str) -> Result<()> {
+    refresh_token_entry()?.set_password(refresh_token)?;
+    Ok(())
+}
+
+/// Remove a stored refresh token
+pub(super) fn clear_refresh_token() -> Result<()> {
+    match refresh_token_entry()?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
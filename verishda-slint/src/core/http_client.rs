@@ -0,0 +1,93 @@
+use anyhow::{anyhow, Result};
+use openidconnect::{http::{HeaderMap, HeaderName, HeaderValue, StatusCode}, HttpRequest, HttpResponse};
+use reqwest::Certificate;
+
+use verishda_config::Config;
+
+/// Error of requests made by `oidc_http_client`
+pub(super) type OidcHttpError = openidconnect::reqwest::Error<reqwest::Error>;
+
+/// Load the root certificates from the PEM bundle at `CA_CERT_PATH`, which
+/// are trusted in addition to the system's root certificates. Returns no
+/// certificates if `CA_CERT_PATH` is not configured.
+pub(super) fn load_root_certificates(config: &dyn Config) -> Result<Vec<Certificate>> {
+    let Ok(path) = config.get("CA_CERT_PATH") else {
+        return Ok(Vec::new());
+    };
+    let pem_bundle = std::fs::read(&path)
+        .map_err(|e|anyhow!("cannot read CA_CERT_PATH {path}: {e}"))?;
+    let certificates = Certificate::from_pem_bundle(&pem_bundle)
+        .map_err(|e|anyhow!("cannot load certificates from CA_CERT_PATH {path}: {e}"))?;
+    if certificates.is_empty() {
+        return Err(anyhow!("no certificates found in CA_CERT_PATH {path}"));
+    }
+    log::info!("trusting {} additional root certificate(s) from {path}", certificates.len());
+    Ok(certificates)
+}
+
+/// Client builder trusting the given root certificates in addition to the
+/// system's root certificates
+pub(super) fn client_builder(root_certificates: &[Certificate]) -> reqwest::ClientBuilder {
+    root_certificates.iter()
+    .fold(reqwest::Client::builder(), |builder, certificate|builder.add_root_certificate(certificate.clone()))
+}
+
+/// Client for OIDC requests, built like the one `openidconnect`'s
+/// `async_http_client` uses, but trusting the given root certificates
+pub(super) fn oidc_client(root_certificates: &[Certificate]) -> reqwest::Client {
+    client_builder(root_certificates)
+        // Following redirects opens the client up to SSRF vulnerabilities.
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("client creation failed")
+}
+
+/// Drop-in replacement for `openidconnect`'s `async_http_client`, sending
+/// the request with the given client. Use like
+/// `.request_async(|request|oidc_http_client(client, request))`.
+pub(super) async fn oidc_http_client(client: reqwest::Client, request: HttpRequest) -> Result<HttpResponse, OidcHttpError> {
+    // openidconnect uses an older version of the http crate than reqwest,
+    // so methods, headers and status codes are converted via their raw values
+    let method = reqwest::Method::from_bytes(request.method.as_str().as_bytes())
+        .map_err(|e|OidcHttpError::Other(e.to_string()))?;
+    let mut request_builder = client
+        .request(method, request.url.as_str())
+        .body(request.body);
+    for (name, value) in &request.headers {
+        request_builder = request_builder.header(name.as_str(), value.as_bytes());
+    }
+    let request = request_builder.build().map_err(OidcHttpError::Reqwest)?;
+
+    let response = client.execute(request).await.map_err(OidcHttpError::Reqwest)?;
+
+    let status_code = StatusCode::from_u16(response.status().as_u16())
+        .map_err(|e|OidcHttpError::Other(e.to_string()))?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in response.headers() {
+        let name = HeaderName::from_bytes(name.as_str().as_bytes())
+            .map_err(|e|OidcHttpError::Other(e.to_string()))?;
+        let value = HeaderValue::from_bytes(value.as_bytes())
+            .map_err(|e|OidcHttpError::Other(e.to_string()))?;
+        headers.append(name, value);
+    }
+    let body = response.bytes().await.map_err(OidcHttpError::Reqwest)?;
+    Ok(HttpResponse {
+        status_code,
+        headers,
+        body: body.to_vec(),
+    })
+}
+
+#[test]
+fn test_load_root_certificates_without_ca_cert_path() {
+    use std::collections::HashMap;
+    use verishda_config::HashMapConfig;
+
+    let config = HashMapConfig::from(HashMap::new());
+    assert!(load_root_certificates(&config).unwrap().is_empty());
+
+    let config = HashMapConfig::from(HashMap::from([
+        ("CA_CERT_PATH".to_string(), "/nonexistent/ca.pem".to_string()),
+    ]));
+    assert!(load_root_certificates(&config).is_err());
+}
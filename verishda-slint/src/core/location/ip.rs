@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+use super::Location;
+
+/// Default for `IP_GEOLOCATION_URL`
+pub(crate) const DEFAULT_IP_GEOLOCATION_URL: &str = "https://ipapi.co/json/";
+
+/// Radius of uncertainty (in meters) assumed for locations derived from the
+/// public IP address, which are city-scale at best
+const IP_GEOLOCATION_ACCURACY: f64 = 5000.;
+
+/// The public IP rarely changes, so providers are not asked more often than 
+/// this. After a failed lookup, we also wait this long before trying again.
+const IP_GEOLOCATION_MAX_AGE: Duration = Duration::from_secs(10*60);
+
+/// Time after which a provider that hasn't responded is given up on
+const IP_GEOLOCATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Approximate location from the public IP address, as determined by a
+/// web service responding with JSON containing `latitude` and `longitude`
+/// (or `lat` and `lon`) fields.
+/// 
+/// Looking up the location is split into `cached`, `fetch` and `record`, so
+/// that callers needn't hold a lock on this while waiting for the provider.
+#[derive(Debug)]
+pub(super) struct IpGeolocation {
+    url: String,
+    last_fix: Option<(Instant, Location)>,
+    last_failure: Option<Instant>,
+}
+
+impl IpGeolocation {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            last_fix: None,
+            last_failure: None,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The result of the last lookup, or `None` if the provider is due to 
+    /// be asked again
+    pub fn cached(&self, now: Instant) -> Option<Result<Location>> {
+        if let Some((fetched, location)) = &self.last_fix {
+            if now.saturating_duration_since(*fetched) < IP_GEOLOCATION_MAX_AGE {
+                return Some(Ok(location.clone()));
+            }
+        }
+        if let Some(failed) = self.last_failure {
+            if now.saturating_duration_since(failed) < IP_GEOLOCATION_MAX_AGE {
+                return Some(Err(anyhow!("IP geolocation failed recently, not retrying yet")));
+            }
+        }
+        None
+    }
+
+    /// Remember the result of a lookup started at `now`
+    pub fn record(&mut self, result: &Result<Location>, now: Instant) {
+        match result {
+            Ok(location) => {
+                self.last_fix = Some((now, location.clone()));
+                self.last_failure = None;
+            }
+            Err(_) => self.last_failure = Some(now),
+        }
+    }
+}
+
+/// Ask the provider at `url` for the location
+pub(super) async fn fetch(url: &str) -> Result<Location> {
+    let response: serde_json::Value = reqwest::Client::new()
+        .get(url)
+        .header(reqwest::header::USER_AGENT, concat!("Verishda/", env!("CARGO_PKG_VERSION")))
+        .timeout(IP_GEOLOCATION_TIMEOUT)
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+    let location = location_from_response(&response)?;
+    log::debug!("IP geolocation: {location:?}");
+    Ok(location)
+}
+
+fn location_from_response(response: &serde_json::Value) -> Result<Location> {
+    let coordinate = |names: [&str; 2]| names.iter()
+        .find_map(|name| response[name].as_f64())
+        .ok_or_else(|| anyhow!("IP geolocation response lacks {}", names[0]));
+    let latitude = coordinate(["latitude", "lat"])?;
+    let longitude = coordinate(["longitude", "lon"])?;
+    Ok(Location::new(latitude, longitude).with_accuracy(IP_GEOLOCATION_ACCURACY))
+}
+
+#[test]
+fn test_location_from_response() {
+    let location = location_from_response(&serde_json::json!({"latitude": 48.49, "longitude": 9.21})).unwrap();
+    assert_eq!((location.latitude, location.longitude), (48.49, 9.21));
+    assert_eq!(location.accuracy, Some(IP_GEOLOCATION_ACCURACY));
+
+    let location = location_from_response(&serde_json::json!({"lat": 48.49, "lon": 9.21})).unwrap();
+    assert_eq!((location.latitude, location.longitude), (48.49, 9.21));
+
+    assert!(location_from_response(&serde_json::json!({"error": true})).is_err());
+}
+
+#[test]
+fn test_cached_lookups() {
+    let mut ip_geolocation = IpGeolocation::new(DEFAULT_IP_GEOLOCATION_URL);
+    let start = Instant::now();
+    assert!(ip_geolocation.cached(start).is_none());
+
+    // failures are remembered, so that the provider isn't asked on every poll
+    ip_geolocation.record(&Err(anyhow!("timed out")), start);
+    assert!(ip_geolocation.cached(start + Duration::from_secs(5)).unwrap().is_err());
+    assert!(ip_geolocation.cached(start + IP_GEOLOCATION_MAX_AGE).is_none());
+
+    let later = start + IP_GEOLOCATION_MAX_AGE;
+    ip_geolocation.record(&Ok(Location::new(48.49, 9.21)), later);
+    assert!(ip_geolocation.cached(later + Duration::from_secs(5)).unwrap().is_ok());
+    assert!(ip_geolocation.cached(later + IP_GEOLOCATION_MAX_AGE).is_none());
+}
@@ -0,0 +1,153 @@
+use anyhow::anyhow;
+use tokio::sync::Mutex;
+use zbus::zvariant::OwnedObjectPath;
+
+use super::Location;
+
+/// desktop file name (without `.desktop`) GeoClue uses to authorize us
+const DESKTOP_ID: &str = "verishda";
+
+/// GeoClue's accuracy level for the most exact location available
+const ACCURACY_LEVEL_EXACT: u32 = 8;
+
+#[zbus::proxy(
+    interface = "org.freedesktop.GeoClue2.Manager",
+    default_service = "org.freedesktop.GeoClue2",
+    default_path = "/org/freedesktop/GeoClue2/Manager"
+)]
+trait GeoClueManager {
+    fn get_client(&self) -> zbus::Result<OwnedObjectPath>;
+    fn delete_client(&self, client: &zbus::zvariant::ObjectPath<'_>) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.GeoClue2.Client",
+    default_service = "org.freedesktop.GeoClue2"
+)]
+trait GeoClueClient {
+    fn start(&self) -> zbus::Result<()>;
+    fn stop(&self) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn location(&self) -> zbus::Result<OwnedObjectPath>;
+    #[zbus(property)]
+    fn set_desktop_id(&self, desktop_id: &str) -> zbus::Result<()>;
+    #[zbus(property)]
+    fn set_requested_accuracy_level(&self, level: u32) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.GeoClue2.Location",
+    default_service = "org.freedesktop.GeoClue2"
+)]
+trait GeoClueLocation {
+    #[zbus(property)]
+    fn latitude(&self) -> zbus::Result<f64>;
+    #[zbus(property)]
+    fn longitude(&self) -> zbus::Result<f64>;
+    #[zbus(property)]
+    fn accuracy(&self) -> zbus::Result<f64>;
+}
+
+/// A started GeoClue client
+#[derive(Debug)]
+struct GeoClueSession {
+    connection: zbus::Connection,
+    client_path: OwnedObjectPath,
+}
+
+impl GeoClueSession {
+    async fn create() -> anyhow::Result<Self> {
+        let connection = zbus::Connection::system().await
+        .map_err(|e|anyhow!("cannot connect to the D-Bus system bus: {e}"))?;
+        let manager = GeoClueManagerProxy::new(&connection).await?;
+        let client_path = manager.get_client().await
+        .map_err(|e|anyhow!("GeoClue location service unavailable: {e}"))?;
+
+        let client = Self::client_proxy(&connection, &client_path).await?;
+        client.set_desktop_id(DESKTOP_ID).await?;
+        client.set_requested_accuracy_level(ACCURACY_LEVEL_EXACT).await?;
+        client.start().await
+        .map_err(|e|anyhow!("GeoClue refused to provide the location: {e}"))?;
+        log::info!("GeoClue client {} started", client_path.as_str());
+
+        Ok(Self { connection, client_path })
+    }
+
+    async fn client_proxy<'a>(connection: &zbus::Connection, client_path: &'a OwnedObjectPath) -> zbus::Result<GeoClueClientProxy<'a>> {
+        GeoClueClientProxy::builder(connection)
+        .path(client_path.as_ref())?
+        .build().await
+    }
+
+    async fn location(&self) -> anyhow::Result<Location> {
+        let client = Self::client_proxy(&self.connection, &self.client_path).await?;
+        let location_path = client.location().await?;
+        // GeoClue reports "/" until it has determined the first location
+        if location_path.as_str() == "/" {
+            return Err(anyhow!("GeoClue has not determined a location yet"));
+        }
+        let location = GeoClueLocationProxy::builder(&self.connection)
+        .path(location_path.as_ref())?
+        .build().await?;
+
+        Ok(Location::new(location.latitude().await?, location.longitude().await?)
+            .with_accuracy(location.accuracy().await?))
+    }
+
+    async fn release(self) -> anyhow::Result<()> {
+        let client = Self::client_proxy(&self.connection, &self.client_path).await?;
+        client.stop().await?;
+        let manager = GeoClueManagerProxy::new(&self.connection).await?;
+        manager.delete_client(&self.client_path.as_ref()).await?;
+        log::info!("GeoClue client {} released", self.client_path.as_str());
+        Ok(())
+    }
+}
+
+/// Locator querying the GeoClue2 D-Bus service available on most Linux 
+/// desktops. The GeoClue client is created on the first poll after 
+/// `start()`, and released on `stop()`.
+#[derive(Debug)]
+pub(crate) struct LinuxPollingLocator {
+    started: bool,
+    session: Mutex<Option<GeoClueSession>>,
+}
+
+impl super::PollingLocator for LinuxPollingLocator {
+    fn new() -> Self {
+        Self {
+            started: false,
+            session: Mutex::new(None),
+        }
+    }
+
+    fn start(&mut self) {
+        self.started = true;
+    }
+
+    fn stop(&mut self) {
+        self.started = false;
+        if let Some(session) = self.session.get_mut().take() {
+            tokio::spawn(async move {
+                if let Err(e) = session.release().await {
+                    log::error!("failed to release GeoClue client: {e}");
+                }
+            });
+        }
+    }
+
+    async fn poll_location(&self) -> anyhow::Result<Location> {
+        if !self.started {
+            return Err(anyhow!("cannot poll if not started"));
+        }
+
+        let mut session = self.session.lock().await;
+        if session.is_none() {
+            *session = Some(GeoClueSession::create().await?);
+        }
+        let location = session.as_ref().unwrap().location().await?;
+        log::debug!("location: {location:?}");
+        Ok(location)
+    }
+}
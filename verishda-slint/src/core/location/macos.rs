@@ -89,12 +89,18 @@ where T: Message + std::fmt::Debug
 impl From<&CLLocation> for Location {
     fn from(value: &CLLocation) -> Self {
         let coordinate;
+        let horizontal_accuracy;
         unsafe {
             coordinate = value.coordinate();
+            horizontal_accuracy = value.horizontalAccuracy();
         }
-        Location {
-            latitude: coordinate.latitude,
-            longitude: coordinate.longitude,
+        let location = Location::new(coordinate.latitude, coordinate.longitude);
+        // a negative accuracy means the location is invalid, which we 
+        // treat like an unknown accuracy
+        if horizontal_accuracy >= 0. {
+            location.with_accuracy(horizontal_accuracy)
+        } else {
+            location
         }
     }
 }
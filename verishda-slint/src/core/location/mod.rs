@@ -1,18 +1,29 @@
-use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::{Duration, Instant}};
 
 use anyhow::Result;
+use futures::future::BoxFuture;
 use tokio::sync::Mutex;
+
+pub use telemetry::GeofenceTelemetry;
+pub(super) use ip::DEFAULT_IP_GEOLOCATION_URL;
+
+mod ip;
+mod telemetry;
 #[cfg(target_os = "windows")]
 mod windows;
 #[cfg(target_os = "macos")]
 mod macos;
-#[cfg(not(any(target_os="windows", target_os="macos")))]
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(not(any(target_os="windows", target_os="macos", target_os="linux")))]
 mod dummy;
 
 #[derive(Clone, Debug, Default)]
 pub struct Location {
     latitude: f64,
     longitude: f64,
+    /// radius of uncertainty in meters, if known
+    accuracy: Option<f64>,
 }
 
 impl Location {
@@ -20,9 +31,29 @@ impl Location {
         Self {
             latitude,
             longitude,
+            accuracy: None,
         }
     }
 
+    pub fn with_accuracy(self, accuracy: f64) -> Self {
+        Self {
+            accuracy: Some(accuracy),
+            ..self
+        }
+    }
+
+    pub fn latitude(&self) -> f64 {
+        self.latitude
+    }
+
+    pub fn longitude(&self) -> f64 {
+        self.longitude
+    }
+
+    pub fn accuracy(&self) -> Option<f64> {
+        self.accuracy
+    }
+
     #[allow(non_snake_case)]
     pub fn squared_distance(&self, location: &Location) -> f64 {
         // https://en.wikipedia.org/wiki/Geographical_distance#Spherical_Earth_projected_to_a_plane
@@ -91,6 +122,84 @@ impl GeoCircle {
     }
 }
 
+#[derive(Debug)]
+struct GeoPolygon {
+    corners: Vec<Location>,
+}
+
+impl GeoPolygon {
+    /// Ray casting test: a location is inside the polygon if a ray going
+    /// east from it crosses the polygon's edges an odd number of times.
+    /// Latitudes and longitudes are treated as planar coordinates, which
+    /// is precise enough for polygons the size of a site.
+    fn is_inside(&self, location: &Location) -> bool {
+        let (x, y) = (location.longitude, location.latitude);
+        let mut inside = false;
+        let mut previous = match self.corners.last() {
+            Some(corner) => corner,
+            None => return false,
+        };
+        for corner in &self.corners {
+            let (x1, y1) = (previous.longitude, previous.latitude);
+            let (x2, y2) = (corner.longitude, corner.latitude);
+            if (y1 > y) != (y2 > y) && x < x1 + (y - y1) * (x2 - x1) / (y2 - y1) {
+                inside = !inside;
+            }
+            previous = corner;
+        }
+        inside
+    }
+
+    /// The average of the corners
+    fn center(&self) -> Location {
+        let n = self.corners.len().max(1) as f64;
+        Location::new(
+            self.corners.iter().map(|c| c.latitude).sum::<f64>() / n,
+            self.corners.iter().map(|c| c.longitude).sum::<f64>() / n,
+        )
+    }
+
+    /// Distance from the center to the farthest corner, in meters
+    fn radius(&self) -> f64 {
+        let center = self.center();
+        self.corners.iter()
+        .map(|c| c.squared_distance(&center))
+        .fold(0., f64::max)
+        .sqrt()
+    }
+}
+
+/// The shape of a geofence
+#[derive(Debug)]
+enum Geofence {
+    Circle(GeoCircle),
+    Polygon(GeoPolygon),
+}
+
+impl Geofence {
+    fn is_inside(&self, location: &Location) -> bool {
+        match self {
+            Self::Circle(circle) => circle.is_inside(location),
+            Self::Polygon(polygon) => polygon.is_inside(location),
+        }
+    }
+
+    fn center(&self) -> Location {
+        match self {
+            Self::Circle(circle) => circle.center.clone(),
+            Self::Polygon(polygon) => polygon.center(),
+        }
+    }
+
+    /// Radius of a circle around the center containing the whole fence
+    fn radius(&self) -> f64 {
+        match self {
+            Self::Circle(circle) => circle.radius,
+            Self::Polygon(polygon) => polygon.radius(),
+        }
+    }
+}
+
 pub(crate) trait PollingLocator {
     fn new() -> Self;
 
@@ -99,35 +208,265 @@ pub(crate) trait PollingLocator {
     async fn poll_location(&self) -> anyhow::Result<Location>;
 }
 
+/// Wrapper around a platform locator that reports a fixed location instead
+/// if one is configured via `LOCATION_OVERRIDE`. This is a development and
+/// testing aid for checking geofences without being at a site.
+#[derive(Debug)]
+pub(crate) struct OverridablePollingLocator<L> {
+    inner: L,
+    location_override: Option<Location>,
+}
+
+impl<L: PollingLocator> OverridablePollingLocator<L> {
+    fn set_location_override(&mut self, location_override: Option<Location>) {
+        self.location_override = location_override;
+    }
+}
+
+impl<L: PollingLocator> PollingLocator for OverridablePollingLocator<L> {
+    fn new() -> Self {
+        Self {
+            inner: L::new(),
+            location_override: None,
+        }
+    }
+
+    fn start(&mut self) {
+        self.inner.start();
+    }
+
+    fn stop(&mut self) {
+        self.inner.stop();
+    }
+
+    async fn poll_location(&self) -> anyhow::Result<Location> {
+        match &self.location_override {
+            Some(location) => Ok(location.clone()),
+            None => self.inner.poll_location().await,
+        }
+    }
+}
+
+/// Where the `LocationHandler` takes locations from. This is the platform's
+/// locator, which tests replace with scripted locations. Unlike 
+/// `PollingLocator`, this trait can be used as trait object.
+pub(crate) trait ProvidesLocation: Send + Sync + std::fmt::Debug {
+    fn start(&mut self);
+    fn stop(&mut self);
+    fn poll_location(&self) -> BoxFuture<'_, Result<Location>>;
+    /// Report the given location instead of polling, or poll again if `None`
+    fn set_location_override(&mut self, location_override: Option<Location>);
+}
+
+impl ProvidesLocation for OverridablePollingLocator<PollingLocatorImpl> {
+    fn start(&mut self) {
+        PollingLocator::start(self);
+    }
+
+    fn stop(&mut self) {
+        PollingLocator::stop(self);
+    }
+
+    fn poll_location(&self) -> BoxFuture<'_, Result<Location>> {
+        Box::pin(PollingLocator::poll_location(self))
+    }
+
+    fn set_location_override(&mut self, location_override: Option<Location>) {
+        OverridablePollingLocator::set_location_override(self, location_override);
+    }
+}
+
+/// Parse a location given as `<latitude>,<longitude>`
+pub(crate) fn parse_location(location: &str) -> Result<Location> {
+    let (latitude, longitude) = location
+        .split_once(',')
+        .ok_or_else(|| anyhow::anyhow!("expected '<latitude>,<longitude>', got '{location}'"))?;
+    Ok(Location::new(latitude.trim().parse()?, longitude.trim().parse()?))
+}
+
 #[cfg(target_os="windows")]
 type PollingLocatorImpl = windows::WindowsPollingLocator;
 #[cfg(target_os="macos")]
 type PollingLocatorImpl = macos::MacOsPollingLocator;
-#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+#[cfg(target_os="linux")]
+type PollingLocatorImpl = linux::LinuxPollingLocator;
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
 type PollingLocatorImpl = dummy::DummyPollingLocator;
 
+/// Entering a fence within this time after leaving it counts as a flap
+const FLAP_WINDOW: Duration = Duration::from_secs(5*60);
+
+/// Number of consecutive polls inside a fence after which it is entered
+pub(super) const DEFAULT_GEOFENCE_ENTER_POLLS: u32 = 1;
+
+/// Number of consecutive polls outside a fence after which it is exited.
+/// Noisy locations near a fence's boundary would otherwise make the user
+/// enter and leave the fence over and over.
+pub(super) const DEFAULT_GEOFENCE_EXIT_POLLS: u32 = 3;
+
 #[derive(Debug)]
 pub(super) struct LocationHandler {
-    polling_locator: PollingLocatorImpl,
-    shapes: std::collections::HashMap<String, GeoCircle>,
+    polling_locator: Box<dyn ProvidesLocation>,
+    shapes: std::collections::HashMap<String, Geofence>,
     in_fences: std::collections::HashSet<String>,
+    /// number of consecutive polls contradicting the membership of each
+    /// fence, i.e. inside a fence not in `in_fences` or vice versa
+    fence_streaks: HashMap<String, u32>,
+    enter_polls: u32,
+    exit_polls: u32,
     task_handle: Option<tokio::task::JoinHandle<()>>,
     terminate_notify: Arc<tokio::sync::Notify>,
+    telemetry: GeofenceTelemetry,
+    last_exits: HashMap<String, Instant>,
+    last_fix: Option<Location>,
+    transition_tx: Option<tokio::sync::mpsc::UnboundedSender<GeofenceTransition>>,
+    occupancy_tx: Option<tokio::sync::mpsc::UnboundedSender<Vec<String>>>,
+    telemetry_log_interval: Option<Duration>,
+    last_telemetry_log: Option<Instant>,
+    ip_geolocation: Option<ip::IpGeolocation>,
+}
+
+/// Change of geofence membership, identified by the fence id
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeofenceTransition {
+    Entered(String),
+    Exited(String),
+}
+
+impl GeofenceTransition {
+    pub fn fence_id(&self) -> &str {
+        match self {
+            Self::Entered(id) | Self::Exited(id) => id,
+        }
+    }
+}
+
+/// Where to take the location from after polling the platform locator
+#[derive(Debug)]
+enum FixSource {
+    Platform(Location),
+    IpGeolocation,
+    None(anyhow::Error),
+}
+
+/// Use the platform's location if there is one, otherwise fall back to IP
+/// geolocation if enabled
+fn choose_fix_source(platform_fix: Result<Location>, ip_geolocation_enabled: bool) -> FixSource {
+    match platform_fix {
+        Ok(location) => FixSource::Platform(location),
+        Err(e) if ip_geolocation_enabled => {
+            log::debug!("no location from platform ({e}), falling back to IP geolocation");
+            FixSource::IpGeolocation
+        }
+        Err(e) => FixSource::None(e),
+    }
 }
 
 impl LocationHandler {
     
     pub fn new() -> Arc<Mutex<LocationHandler>> {
+        Self::with_locator(Box::new(OverridablePollingLocator::<PollingLocatorImpl>::new()))
+    }
+
+    /// A handler taking locations from the given locator instead of the
+    /// platform's
+    pub(crate) fn with_locator(polling_locator: Box<dyn ProvidesLocation>) -> Arc<Mutex<LocationHandler>> {
         Arc::new(Mutex::new(Self {
-            
-            polling_locator: PollingLocatorImpl::new(),
+            polling_locator,
             shapes: HashMap::new(),
             in_fences: HashSet::new(),
+            fence_streaks: HashMap::new(),
+            enter_polls: DEFAULT_GEOFENCE_ENTER_POLLS,
+            exit_polls: DEFAULT_GEOFENCE_EXIT_POLLS,
             task_handle: None,            
             terminate_notify: Arc::new(tokio::sync::Notify::new()),
+            telemetry: GeofenceTelemetry::default(),
+            last_exits: HashMap::new(),
+            last_fix: None,
+            transition_tx: None,
+            occupancy_tx: None,
+            telemetry_log_interval: None,
+            last_telemetry_log: None,
+            ip_geolocation: None,
         }))
     }
 
+    /// Current geofencing telemetry counters
+    pub fn debug_snapshot(&self) -> GeofenceTelemetry {
+        self.telemetry.clone()
+    }
+
+    /// Log the telemetry counters in the given interval while polling, or
+    /// never if `None`
+    pub fn set_telemetry_log_interval(&mut self, interval: Option<Duration>) {
+        self.telemetry_log_interval = interval;
+    }
+
+    /// Fall back to the location of the public IP, as determined by the
+    /// service at the given URL, when the platform can't provide a location
+    pub fn set_ip_geolocation_url(&mut self, url: Option<&str>) {
+        self.ip_geolocation = url.map(ip::IpGeolocation::new);
+    }
+
+    /// Require the given numbers of consecutive polls inside (outside) a
+    /// fence before entering (exiting) it. Numbers below 1 are treated as 1.
+    pub fn set_geofence_hysteresis(&mut self, enter_polls: u32, exit_polls: u32) {
+        self.enter_polls = enter_polls.max(1);
+        self.exit_polls = exit_polls.max(1);
+        self.fence_streaks.clear();
+    }
+
+    /// Send entering and leaving geofences to the given channel
+    pub fn set_transition_sender(&mut self, transition_tx: Option<tokio::sync::mpsc::UnboundedSender<GeofenceTransition>>) {
+        self.transition_tx = transition_tx;
+    }
+
+    /// Send the ids of the occupied geofences to the given channel whenever
+    /// they change
+    pub fn set_occupancy_sender(&mut self, occupancy_tx: Option<tokio::sync::mpsc::UnboundedSender<Vec<String>>>) {
+        self.occupancy_tx = occupancy_tx;
+    }
+
+    fn report_occupancy(&self) {
+        if let Some(occupancy_tx) = &self.occupancy_tx {
+            let mut occupied = self.get_occupied_geofences();
+            occupied.sort();
+            if let Err(e) = occupancy_tx.send(occupied) {
+                log::debug!("geofence occupancy not delivered: {e}");
+            }
+        }
+    }
+
+    fn report_transition(&self, transition: GeofenceTransition) {
+        if let Some(transition_tx) = &self.transition_tx {
+            if let Err(e) = transition_tx.send(transition) {
+                log::debug!("geofence transition not delivered: {e}");
+            }
+        }
+    }
+
+    /// Report the given location instead of polling the platform, or
+    /// poll the platform again if `None`
+    pub fn set_location_override(&mut self, location_override: Option<Location>) {
+        if let Some(location) = &location_override {
+            log::warn!("overriding platform location with {location:?}");
+        }
+        self.polling_locator.set_location_override(location_override);
+    }
+
+    fn log_telemetry_if_due(&mut self, now: Instant) {
+        let Some(interval) = self.telemetry_log_interval else {
+            return;
+        };
+        match self.last_telemetry_log {
+            Some(last) if now.duration_since(last) < interval => (),
+            _ => {
+                log::info!("geofence telemetry: {:?}", self.telemetry);
+                self.last_telemetry_log = Some(now);
+            }
+        }
+    }
+
     pub async fn start(handler: Arc<Mutex<Self>>, poll_duration: Duration) {
         let mut handler_guard = handler.lock().await;
 
@@ -167,11 +506,21 @@ impl LocationHandler {
     }
 
     pub async fn stop(handler: Arc<Mutex<Self>>) {
-        let mut handler_guard = handler.lock().await;
-        handler_guard.terminate_notify.notify_waiters();
-        handler_guard.polling_locator.stop();
-        match handler_guard.task_handle.as_mut() {
-            Some(task_handle) => {  
+        let task_handle = {
+            let mut handler_guard = handler.lock().await;
+            handler_guard.polling_locator.stop();
+            let task_handle = handler_guard.task_handle.take();
+            if task_handle.is_some() {
+                // the task may be busy polling, in which case the permit
+                // stored by notify_one() ends it once it's done
+                handler_guard.terminate_notify.notify_one();
+            }
+            task_handle
+        };
+        // the task may need the handler to finish its current poll, so it 
+        // must not be locked while waiting for the task
+        match task_handle {
+            Some(task_handle) => {
                 if let Err(e) = task_handle.await {
                     log::error!("PollingLocator task terminated with error {e}");
                 }
@@ -180,38 +529,116 @@ impl LocationHandler {
                 log::error!("attempting to stop PollingLocator task when no task is running");
             }
         }
-        handler_guard.task_handle = None;
         log::info!("location handler stopped");
     }
 
 
-    pub async fn poll(handler: Arc<Mutex<Self>>) {
-        let mut handler = handler.lock().await;
-        match handler.polling_locator.poll_location().await {
+    pub async fn poll(handler_ref: Arc<Mutex<Self>>) {
+        let mut handler = handler_ref.lock().await;
+        let now = Instant::now();
+        let platform_fix = handler.polling_locator.poll_location().await;
+        let fix = match choose_fix_source(platform_fix, handler.ip_geolocation.is_some()) {
+            FixSource::Platform(location) => Ok(location),
+            FixSource::IpGeolocation => {
+                let ip_geolocation = handler.ip_geolocation.as_ref().unwrap();
+                match ip_geolocation.cached(now) {
+                    Some(result) => result,
+                    None => {
+                        // the provider may take a while to respond, during
+                        // which the core must still be able to use the handler
+                        let url = ip_geolocation.url().to_string();
+                        drop(handler);
+                        let result = ip::fetch(&url).await;
+                        handler = handler_ref.lock().await;
+                        if let Some(ip_geolocation) = handler.ip_geolocation.as_mut() {
+                            ip_geolocation.record(&result, now);
+                        }
+                        result
+                    }
+                }
+            }
+            FixSource::None(e) => Err(e),
+        };
+        match fix {
             Ok(location) => {
-                handler.check_geofences(&location);
+                handler.handle_fix(&location, now);
+            }
+            Err(error) => {
+                handler.telemetry.fixes_failed += 1;
+                log::error!("unable to fetch location: {error}")
             }
-            Err(error) => log::error!("unable to fetch location: {error}"),
         }
+        handler.log_telemetry_if_due(now);
     }
 
-    fn check_geofences(&mut self, location: &Location) {
+    /// Check the geofences against a location that didn't come from polling,
+    /// like one entered manually
+    pub fn inject_location(&mut self, location: &Location) {
+        self.handle_fix(location, Instant::now());
+    }
+
+    fn handle_fix(&mut self, location: &Location, now: Instant) {
+        self.telemetry.fixes_received += 1;
+        self.last_fix = Some(location.clone());
+        let checked_fences = self.check_geofences(location, now);
+        if checked_fences == 0 && !self.shapes.is_empty() {
+            log::debug!("ignoring location {location:?}, its accuracy is insufficient for all geofences");
+            self.telemetry.fixes_rejected_for_accuracy += 1;
+        }
+    }
+
+    /// Update fence membership for the given location, returning the number 
+    /// of fences it was accurate enough for. Fences smaller than the 
+    /// location's radius of uncertainty keep their membership, as the
+    /// location can't tell whether the user is inside or not.
+    fn check_geofences(&mut self, location: &Location, now: Instant) -> usize {
         log::debug!("polling geofences against {location:?}");
         log::trace!("installed geofences: {:?}", self.shapes);
+        let mut checked_fences = 0;
+        let mut occupancy_changed = false;
         for (id, shape) in &self.shapes {
-            if shape.is_inside(&location) {
-                if !self.in_fences.contains(id) {
-                    log::info!("Entered geofence: {id}");
-                    self.in_fences.insert(id.to_string());
+            if location.accuracy.is_some_and(|accuracy| accuracy > shape.radius()) {
+                continue;
+            }
+            checked_fences += 1;
+            let inside = shape.is_inside(&location);
+            if inside == self.in_fences.contains(id) {
+                self.fence_streaks.remove(id);
+                continue;
+            }
+            let streak = self.fence_streaks.entry(id.to_string()).or_default();
+            *streak += 1;
+            let required_polls = if inside { self.enter_polls } else { self.exit_polls };
+            if *streak < required_polls {
+                log::debug!("{} geofence {id} for {streak} of {required_polls} polls", if inside {"inside"} else {"outside"});
+                continue;
+            }
+            self.fence_streaks.remove(id);
+            if inside {
+                log::info!("Entered geofence: {id}");
+                self.in_fences.insert(id.to_string());
+                occupancy_changed = true;
+                self.report_transition(GeofenceTransition::Entered(id.to_string()));
+                self.telemetry.fence_entries += 1;
+                if let Some(last_exit) = self.last_exits.get(id) {
+                    if now.duration_since(*last_exit) < FLAP_WINDOW {
+                        self.telemetry.flaps += 1;
+                    }
                 }
             } else {
-                if self.in_fences.contains(id) {
-                    log::info!("Exited geofence: {id}");
-                    self.in_fences.remove(id);
-                }
+                log::info!("Exited geofence: {id}");
+                self.in_fences.remove(id);
+                occupancy_changed = true;
+                self.report_transition(GeofenceTransition::Exited(id.to_string()));
+                self.telemetry.fence_exits += 1;
+                self.last_exits.insert(id.to_string(), now);
             }
         }
         log::debug!("in_fences: {:?}", self.in_fences);
+        if occupancy_changed {
+            self.report_occupancy();
+        }
+        checked_fences
     }
 
     pub fn add_geofence_circle(
@@ -222,10 +649,28 @@ impl LocationHandler {
     ) -> Result<()> {
         self.shapes.insert(
             id.to_string(),
-            GeoCircle {
+            Geofence::Circle(GeoCircle {
                 center: location.clone(),
                 radius,
-            },
+            }),
+        );
+        Ok(())
+    }
+
+    /// Add a geofence with the given corners, which must be at least three
+    pub fn add_geofence_polygon(
+        &mut self,
+        id: &str,
+        corners: &[Location],
+    ) -> Result<()> {
+        if corners.len() < 3 {
+            anyhow::bail!("a geofence polygon needs at least 3 corners, got {}", corners.len());
+        }
+        self.shapes.insert(
+            id.to_string(),
+            Geofence::Polygon(GeoPolygon {
+                corners: corners.to_vec(),
+            }),
         );
         Ok(())
     }
@@ -242,6 +687,21 @@ impl LocationHandler {
     pub fn get_occupied_geofences(&self) -> Vec<String> {
         self.in_fences.iter().cloned().collect()
     }
+
+    /// Of the fences the user is in, the one whose center is closest to
+    /// the most recent location fix
+    pub fn get_nearest_occupied_geofence(&self) -> Option<String> {
+        let location = self.last_fix.as_ref()?;
+        self.in_fences.iter()
+        .filter_map(|id| self.shapes.get(id).map(|shape| (id, shape.center().squared_distance(location))))
+        .min_by(|(_, d1), (_, d2)| d1.total_cmp(d2))
+        .map(|(id, _)| id.clone())
+    }
+
+    /// The most recent location fix, if there was any
+    pub fn last_fix(&self) -> Option<Location> {
+        self.last_fix.clone()
+    }
 }
 
 #[test]
@@ -259,17 +719,276 @@ fn test_geo_circle() {
 }
 
 #[test]
-fn test_distance() {
-    let loc1 = Location {
-        latitude: 48.48870120526846,
-        longitude: 9.218084635543407,
-    };
-    let loc2 = Location {
-        latitude: 48.4901237487793,
-        longitude: 9.21942138671875,
+fn test_geo_polygon() {
+    // an L-shaped building
+    let polygon = GeoPolygon {
+        corners: vec![
+            Location::new(48.4880, 9.2170),
+            Location::new(48.4880, 9.2190),
+            Location::new(48.4885, 9.2190),
+            Location::new(48.4885, 9.2175),
+            Location::new(48.4895, 9.2175),
+            Location::new(48.4895, 9.2170),
+        ],
     };
+
+    assert!(polygon.is_inside(&Location::new(48.4882, 9.2185)));
+    assert!(polygon.is_inside(&Location::new(48.4890, 9.2172)));
+    // in the corner of the L, which is within the bounding box
+    assert!(!polygon.is_inside(&Location::new(48.4890, 9.2185)));
+    assert!(!polygon.is_inside(&Location::new(48.4900, 9.2172)));
+
+    assert!(!GeoPolygon { corners: vec![] }.is_inside(&Location::new(48.4882, 9.2185)));
+}
+
+#[test]
+fn test_distance() {
+    let loc1 = Location::new(48.48870120526846, 9.218084635543407);
+    let loc2 = Location::new(48.4901237487793, 9.21942138671875);
     let D2 = loc1.squared_distance(&loc2);
     let D = D2.sqrt();
     println!("distance betwen {loc1:?} and {loc2:?} is {D}");
     assert!(D < 200.);
 }
+
+#[test]
+fn test_geofence_telemetry() {
+    let handler = LocationHandler::new();
+    let mut handler = handler.blocking_lock();
+    handler.set_geofence_hysteresis(1, 1);
+    let center = Location::new(48.4887, 9.2180);
+    handler.add_geofence_circle("site", &center, 100.).unwrap();
+
+    let outside = Location::new(48.4987, 9.2180);
+    let start = Instant::now();
+    handler.handle_fix(&outside, start);
+    handler.handle_fix(&center, start + Duration::from_secs(60));
+    handler.handle_fix(&outside, start + Duration::from_secs(120));
+    // jittering back in right after leaving is a flap
+    handler.handle_fix(&center, start + Duration::from_secs(180));
+    // inaccurate fixes don't change fence membership
+    handler.handle_fix(&outside.clone().with_accuracy(1000.), start + Duration::from_secs(240));
+    handler.handle_fix(&center.clone().with_accuracy(10.), start + Duration::from_secs(300));
+
+    assert_eq!(handler.debug_snapshot(), GeofenceTelemetry {
+        fixes_received: 6,
+        fixes_failed: 0,
+        fixes_rejected_for_accuracy: 1,
+        fence_entries: 2,
+        fence_exits: 1,
+        flaps: 1,
+    });
+    assert_eq!(handler.get_occupied_geofences(), vec!["site".to_string()]);
+}
+
+#[test]
+fn test_choose_fix_source() {
+    let platform_fix = Location::new(48.4887, 9.2180).with_accuracy(20.);
+    assert!(matches!(choose_fix_source(Ok(platform_fix.clone()), true), FixSource::Platform(_)));
+    assert!(matches!(choose_fix_source(Ok(platform_fix), false), FixSource::Platform(_)));
+    assert!(matches!(choose_fix_source(Err(anyhow::anyhow!("no GPS")), true), FixSource::IpGeolocation));
+    assert!(matches!(choose_fix_source(Err(anyhow::anyhow!("no GPS")), false), FixSource::None(_)));
+}
+
+#[test]
+fn test_inaccurate_fix_only_affects_large_fences() {
+    let handler = LocationHandler::new();
+    let mut handler = handler.blocking_lock();
+    let center = Location::new(48.4887, 9.2180);
+    handler.add_geofence_circle("office", &center, 100.).unwrap();
+    handler.add_geofence_circle("city", &center, 10000.).unwrap();
+
+    // a city-scale fix, like those from IP geolocation
+    handler.handle_fix(&center.clone().with_accuracy(5000.), Instant::now());
+    assert_eq!(handler.get_occupied_geofences(), vec!["city".to_string()]);
+    assert_eq!(handler.debug_snapshot().fixes_rejected_for_accuracy, 0);
+}
+
+#[tokio::test]
+async fn test_location_override() {
+    struct FailingLocator;
+    impl PollingLocator for FailingLocator {
+        fn new() -> Self {
+            Self
+        }
+        fn start(&mut self) {}
+        fn stop(&mut self) {}
+        async fn poll_location(&self) -> anyhow::Result<Location> {
+            Err(anyhow::anyhow!("no location available"))
+        }
+    }
+
+    let mut locator = OverridablePollingLocator::<FailingLocator>::new();
+    assert!(locator.poll_location().await.is_err());
+
+    locator.set_location_override(Some(parse_location("48.4887, 9.2180").unwrap()));
+    let location = locator.poll_location().await.unwrap();
+    assert_eq!((location.latitude(), location.longitude()), (48.4887, 9.2180));
+
+    assert!(parse_location("48.4887").is_err());
+    assert!(parse_location("north,east").is_err());
+}
+
+#[test]
+fn test_geofence_transitions_are_reported() {
+    let handler = LocationHandler::new();
+    let mut handler = handler.blocking_lock();
+    handler.set_geofence_hysteresis(1, 1);
+    let (transition_tx, mut transition_rx) = tokio::sync::mpsc::unbounded_channel();
+    handler.set_transition_sender(Some(transition_tx));
+    let center = Location::new(48.4887, 9.2180);
+    handler.add_geofence_circle("site", &center, 100.).unwrap();
+
+    let now = Instant::now();
+    handler.handle_fix(&center, now);
+    // staying inside is no transition
+    handler.handle_fix(&center, now + Duration::from_secs(5));
+    handler.handle_fix(&Location::new(48.4987, 9.2180), now + Duration::from_secs(10));
+
+    assert_eq!(transition_rx.try_recv().unwrap(), GeofenceTransition::Entered("site".to_string()));
+    assert_eq!(transition_rx.try_recv().unwrap(), GeofenceTransition::Exited("site".to_string()));
+    assert!(transition_rx.try_recv().is_err());
+}
+
+#[test]
+fn test_nearest_occupied_geofence() {
+    let handler = LocationHandler::new();
+    let mut handler = handler.blocking_lock();
+    assert_eq!(handler.get_nearest_occupied_geofence(), None);
+
+    let campus = Location::new(48.4887, 9.2180);
+    let building = Location::new(48.4890, 9.2180);
+    handler.add_geofence_circle("campus", &campus, 500.).unwrap();
+    handler.add_geofence_circle("building", &building, 100.).unwrap();
+
+    handler.handle_fix(&Location::new(48.4891, 9.2180), Instant::now());
+    let mut occupied = handler.get_occupied_geofences();
+    occupied.sort();
+    assert_eq!(occupied, vec!["building".to_string(), "campus".to_string()]);
+    assert_eq!(handler.get_nearest_occupied_geofence(), Some("building".to_string()));
+}
+
+#[test]
+fn test_geofence_occupancy_is_reported_on_change() {
+    let handler = LocationHandler::new();
+    let mut handler = handler.blocking_lock();
+    handler.set_geofence_hysteresis(1, 1);
+    let (occupancy_tx, mut occupancy_rx) = tokio::sync::mpsc::unbounded_channel();
+    handler.set_occupancy_sender(Some(occupancy_tx));
+    let center = Location::new(48.4887, 9.2180);
+    handler.add_geofence_circle("site", &center, 100.).unwrap();
+
+    let now = Instant::now();
+    handler.handle_fix(&center, now);
+    // staying inside doesn't change occupancy
+    handler.handle_fix(&center, now + Duration::from_secs(5));
+    handler.handle_fix(&Location::new(48.4987, 9.2180), now + Duration::from_secs(10));
+
+    assert_eq!(occupancy_rx.try_recv().unwrap(), vec!["site".to_string()]);
+    assert_eq!(occupancy_rx.try_recv().unwrap(), Vec::<String>::new());
+    assert!(occupancy_rx.try_recv().is_err());
+}
+
+#[test]
+fn test_geofence_hysteresis_smoothes_flapping() {
+    let handler = LocationHandler::new();
+    let mut handler = handler.blocking_lock();
+    handler.set_geofence_hysteresis(2, 3);
+    let (transition_tx, mut transition_rx) = tokio::sync::mpsc::unbounded_channel();
+    handler.set_transition_sender(Some(transition_tx));
+    let center = Location::new(48.4887, 9.2180);
+    let outside = Location::new(48.4987, 9.2180);
+    handler.add_geofence_circle("site", &center, 100.).unwrap();
+
+    let start = Instant::now();
+    let fixes = [
+        // a single fix inside isn't enough to enter
+        &center, &outside, &center, &center,
+        // jittering outside now and then doesn't leave
+        &outside, &center, &outside, &outside, &center,
+        // but staying outside does
+        &outside, &outside, &outside,
+    ];
+    for (n, fix) in fixes.into_iter().enumerate() {
+        handler.handle_fix(fix, start + Duration::from_secs(5 * n as u64));
+        let occupied = !handler.get_occupied_geofences().is_empty();
+        assert_eq!(occupied, (3..11).contains(&n), "occupancy after fix {n}");
+    }
+
+    assert_eq!(transition_rx.try_recv().unwrap(), GeofenceTransition::Entered("site".to_string()));
+    assert_eq!(transition_rx.try_recv().unwrap(), GeofenceTransition::Exited("site".to_string()));
+    assert!(transition_rx.try_recv().is_err());
+}
+
+/// Locator returning scripted locations, one per poll, and failing once 
+/// they are used up
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct MockPollingLocator {
+    locations: std::sync::Mutex<std::collections::VecDeque<Location>>,
+}
+
+#[cfg(test)]
+impl MockPollingLocator {
+    fn with_locations(locations: impl IntoIterator<Item = Location>) -> Box<Self> {
+        Box::new(Self {
+            locations: std::sync::Mutex::new(locations.into_iter().collect()),
+        })
+    }
+}
+
+#[cfg(test)]
+impl ProvidesLocation for MockPollingLocator {
+    fn start(&mut self) {}
+
+    fn stop(&mut self) {}
+
+    fn poll_location(&self) -> BoxFuture<'_, Result<Location>> {
+        let location = self.locations.lock().unwrap().pop_front();
+        Box::pin(async move { location.ok_or_else(|| anyhow::anyhow!("no more locations")) })
+    }
+
+    fn set_location_override(&mut self, _location_override: Option<Location>) {}
+}
+
+#[tokio::test]
+async fn test_polling_enters_and_exits_geofences() {
+    let center = Location::new(48.4887, 9.2180);
+    let outside = Location::new(48.4987, 9.2180);
+    let handler = LocationHandler::with_locator(MockPollingLocator::with_locations([
+        outside.clone(), center.clone(), center.clone(), outside.clone(), outside.clone(),
+    ]));
+    let (transition_tx, mut transition_rx) = tokio::sync::mpsc::unbounded_channel();
+    {
+        let mut handler = handler.lock().await;
+        handler.set_geofence_hysteresis(1, 2);
+        handler.set_transition_sender(Some(transition_tx));
+        handler.add_geofence_circle("site", &center, 100.).unwrap();
+    }
+
+    let mut occupancy = Vec::new();
+    for _ in 0..5 {
+        LocationHandler::poll(handler.clone()).await;
+        occupancy.push(!handler.lock().await.get_occupied_geofences().is_empty());
+    }
+    assert_eq!(occupancy, vec![false, true, true, true, false]);
+    assert_eq!(transition_rx.try_recv().unwrap(), GeofenceTransition::Entered("site".to_string()));
+    assert_eq!(transition_rx.try_recv().unwrap(), GeofenceTransition::Exited("site".to_string()));
+    assert!(transition_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_failed_polls_keep_geofence_membership() {
+    let center = Location::new(48.4887, 9.2180);
+    let handler = LocationHandler::with_locator(MockPollingLocator::with_locations([center.clone()]));
+    handler.lock().await.add_geofence_circle("site", &center, 100.).unwrap();
+
+    LocationHandler::poll(handler.clone()).await;
+    // the locator has no more locations
+    LocationHandler::poll(handler.clone()).await;
+
+    let handler = handler.lock().await;
+    assert_eq!(handler.get_occupied_geofences(), vec!["site".to_string()]);
+    assert_eq!(handler.debug_snapshot().fixes_received, 1);
+    assert_eq!(handler.debug_snapshot().fixes_failed, 1);
+}
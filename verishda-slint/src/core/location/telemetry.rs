@@ -0,0 +1,17 @@
+/// Counters describing how geofencing behaves over time, to help choose
+/// sensible radius and filter settings. They are only kept in memory and
+/// logged locally; nothing is sent anywhere.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeofenceTelemetry {
+    /// locations successfully obtained from the platform
+    pub fixes_received: u64,
+    /// failed attempts to obtain a location
+    pub fixes_failed: u64,
+    /// locations ignored because they were too inaccurate
+    pub fixes_rejected_for_accuracy: u64,
+    pub fence_entries: u64,
+    pub fence_exits: u64,
+    /// entries into a fence shortly after leaving it, which usually means
+    /// the location jittered across the fence boundary
+    pub flaps: u64,
+}
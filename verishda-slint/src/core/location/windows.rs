@@ -4,10 +4,7 @@ use super::Location;
 
 impl From<&BasicGeoposition> for Location {
     fn from(pos: &BasicGeoposition) -> Self {
-        Location {
-            latitude: pos.Latitude,
-            longitude: pos.Longitude,
-        }
+        Location::new(pos.Latitude, pos.Longitude)
     }
 }
 
@@ -44,11 +41,13 @@ impl super::PollingLocator for WindowsPollingLocator {
         };
 
         let pos = loc.GetGeopositionAsync()?.await?;
+        let coordinate = pos.Coordinate()?;
         let location = Location::from(
-            &pos.Coordinate()?
+            &coordinate
                 .Point()?
                 .Position()?,
-        );
+        )
+        .with_accuracy(coordinate.Accuracy()?);
         log::debug!("location: {location:?}");
         Ok(location)
     }
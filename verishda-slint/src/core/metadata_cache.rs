@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use openidconnect::{core::CoreProviderMetadata, IssuerUrl};
+
+/// Location of the file caching the provider metadata of the last
+/// successful OIDC discovery
+fn cache_file_path() -> Option<PathBuf> {
+    Some(dirs::cache_dir()?
+        .join("verishda")
+        .join("oidc_metadata.json"))
+}
+
+/// Load the cached provider metadata, if there is any for the given issuer
+pub(super) fn load(issuer_url: &IssuerUrl) -> Option<CoreProviderMetadata> {
+    let path = cache_file_path()?;
+    let content = std::fs::read(&path).ok()?;
+    let metadata = match serde_json::from_slice::<CoreProviderMetadata>(&content) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            log::error!("cannot parse cached provider metadata in {}: {e}", path.display());
+            return None;
+        }
+    };
+    if metadata.issuer() != issuer_url {
+        log::debug!("cached provider metadata is for issuer {}, not {}", metadata.issuer().as_str(), issuer_url.as_str());
+        return None;
+    }
+    Some(metadata)
+}
+
+/// Remove the cached provider metadata, if any
+pub(super) fn clear() -> Result<()> {
+    let path = cache_file_path().ok_or_else(||anyhow!("no cache directory available"))?;
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Cache the given provider metadata for use when discovery fails
+pub(super) fn store(metadata: &CoreProviderMetadata) -> Result<()> {
+    let path = cache_file_path().ok_or_else(||anyhow!("no cache directory available"))?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, serde_json::to_vec(metadata)?)?;
+    Ok(())
+}
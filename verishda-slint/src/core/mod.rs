@@ -1,24 +1,78 @@
-use std::{sync::{mpsc::RecvError, Arc}, time::{Duration, Instant}};
+use std::{collections::HashMap, sync::{mpsc::RecvError, Arc}, time::{Duration, Instant}};
 
-use chrono::Days;
+use chrono::{Days, NaiveDate};
+use chrono_tz::Tz;
 use futures::prelude::*;
 use location::LocationHandler;
-use openidconnect::{core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata}, reqwest::async_http_client, AuthorizationCode, ClientId, CsrfToken, ExtraTokenFields, IssuerUrl, Nonce, OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, Scope, StandardTokenResponse, TokenResponse, TokenType};
+use openidconnect::{core::{CoreAuthenticationFlow, CoreClient, CoreErrorResponseType, CoreProviderMetadata}, AuthorizationCode, ClientId, ClientSecret, CsrfToken, DiscoveryError, ExtraTokenFields, IssuerUrl, Nonce, OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, RequestTokenError, Scope, StandardErrorResponse, StandardTokenResponse, TokenResponse, TokenType};
 use anyhow::Result;
 
 use reqwest::header::HeaderMap;
-use tokio::{sync::{mpsc::Sender, Mutex, Notify}, time::MissedTickBehavior};
+use tokio::sync::{mpsc::Sender, Mutex, Notify};
 use url::Url;
 use log::*;
 
 use verishda_config::Config;
-use verishda_dto::types::{PresenceAnnouncement, PresenceAnnouncementKind, PresenceAnnouncements};
-use crate::core::location::Location;
+use verishda_dto::types::{PresenceAnnouncement, PresenceAnnouncementKind, PresenceAnnouncements, Site};
+pub use crate::core::location::Location;
+use http_client::oidc_http_client;
 
+mod credential_store;
+mod http_client;
 mod location;
+mod metadata_cache;
+mod notification;
+mod presence_cache;
 pub mod startup;
 pub mod verishda_dto;
 
+/// bounds (in meters) that user provided site radius overrides are clamped to
+const MIN_SITE_RADIUS: f64 = 20.;
+const MAX_SITE_RADIUS: f64 = 5000.;
+
+fn site_radius_override_key(site_id: &str) -> String {
+    format!("SITE_RADIUS_OVERRIDE_{site_id}")
+}
+
+/// Determine the geofence radius for the given site. A user override
+/// stored in the config takes precedence over the radius provided by
+/// the server.
+fn effective_site_radius(config: &dyn Config, site: &Site) -> f64 {
+    config.get(&site_radius_override_key(&site.id))
+    .ok()
+    .and_then(|r|r.parse::<f64>().ok())
+    .map(|r|r.clamp(MIN_SITE_RADIUS, MAX_SITE_RADIUS))
+    .unwrap_or(site.radius as f64)
+}
+
+/// config key under which the site last selected by the user is stored
+const LAST_SITE_ID_KEY: &str = "LAST_SITE_ID";
+
+/// config key of the site (given by id or name) to select on first launch
+const DEFAULT_SITE_ID_KEY: &str = "DEFAULT_SITE_ID";
+
+/// Determine the site to select from the given sites: the current selection
+/// if there is one, otherwise the last selected site stored in the config,
+/// otherwise the configured default site, which may be given by id or name.
+/// Each is skipped if the site no longer exists, falling back to the first 
+/// site in the end.
+fn select_site(sites: &[Site], current: Option<&str>, last: Option<&str>, default: Option<&str>) -> Option<(String, usize)> {
+    let position_of = |site_id: &str| sites.iter().position(|site|site.id == site_id);
+    let default_position = || default.and_then(|default| {
+        let position = position_of(default)
+        .or_else(||sites.iter().position(|site|site.name == default));
+        if position.is_none() {
+            log::warn!("default site '{default}' doesn't exist");
+        }
+        position
+    });
+    current.and_then(position_of)
+    .or_else(||last.and_then(position_of))
+    .or_else(default_position)
+    .or((!sites.is_empty()).then_some(0))
+    .map(|i|(sites[i].id.clone(), i))
+}
+
 #[derive(Default, Clone, Debug)]
 pub enum Announcement {
     #[default]
@@ -27,16 +81,119 @@ pub enum Announcement {
     WeeklyPresenceAnnounced,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 struct Credentials {
     access_token: String,
-    refresh_token: String,
+    /// only present if the IdP issued one, which usually requires the 
+    /// `offline_access` scope
+    refresh_token: Option<String>,
     expires_at: Instant,
 }
 
+/// Redacts the tokens, as credentials end up in log files via commands and
+/// log statements
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+        .field("access_token", &redacted(&self.access_token))
+        .field("refresh_token", &self.refresh_token.as_deref().map(redacted))
+        .field("expires_at", &self.expires_at)
+        .finish()
+    }
+}
+
+/// Placeholder for a secret in debug output, only revealing its length
+fn redacted(secret: &str) -> String {
+    format!("<redacted, {} chars>", secret.len())
+}
+
+/// how long an access token passed in via `AppCoreRef::log_in_with_tokens()`
+/// is assumed to be valid, as its actual expiry is unknown
+const ASSUMED_ACCESS_TOKEN_LIFETIME: Duration = Duration::from_secs(5*60);
+
+/// scopes requested during login unless configured otherwise via `OIDC_SCOPES`
+const DEFAULT_OIDC_SCOPES: &str = "openid offline_access";
+
+/// retries of OIDC discovery during initialization unless configured 
+/// otherwise via `OIDC_DISCOVERY_RETRIES`. The delay between retries starts
+/// with `OIDC_DISCOVERY_RETRY_BASE_DELAY` and doubles with each attempt.
+const DEFAULT_OIDC_DISCOVERY_RETRIES: u32 = 3;
+const OIDC_DISCOVERY_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// the access token is refreshed this long before it expires
+const TOKEN_REFRESH_LEAD: Duration = Duration::from_secs(60);
+/// minimum time between refreshes ahead of expiry, so that short lived 
+/// tokens or failing refreshes don't end up in a refresh loop
+const MIN_TOKEN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Delays between token refresh attempts while reconnecting, configurable via
+/// `RECONNECT_BASE_SECS` and `RECONNECT_MAX_SECS`. The delay starts at the 
+/// base, doubles with each failed attempt up to the cap, and is randomly
+/// shortened by up to half so that clients don't retry in lockstep. The
+/// configured base is clamped to the minimum to avoid hammering the IdP.
+const DEFAULT_RECONNECT_BASE_DELAY: Duration = Duration::from_secs(10);
+const MIN_RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5*60);
+
+/// Delay before the given (zero based) reconnect retry. `jitter` is a random
+/// number determining how much the delay is shortened.
+fn reconnect_delay(base: Duration, max: Duration, retry: u32, jitter: u64) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(retry.min(16))).min(max.max(base));
+    let half_millis = (delay.as_millis() / 2) as u64;
+    delay - Duration::from_millis(jitter % (half_millis + 1))
+}
+
+/// A random number, good enough for jittering delays
+fn random_jitter() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new().build_hasher().finish()
+}
+
+/// cap for the delay before retrying a failed initialization on user request
+const MAX_REINITIALIZE_DELAY: Duration = Duration::from_secs(60);
+
+/// Refresh and poll intervals, configurable via `SITE_REFRESH_SECS`,
+/// `PRESENCE_REFRESH_SECS` and `LOCATION_POLL_SECS`. Configured values are
+/// clamped to the minimums to avoid hammering the API.
+const DEFAULT_SITE_REFRESH_INTERVAL: Duration = Duration::from_secs(5*60);
+const MIN_SITE_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_PRESENCE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_PRESENCE_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_LOCATION_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const MIN_LOCATION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Timeouts of API requests, configurable via `API_TIMEOUT_SECS` and
+/// `API_CONNECT_TIMEOUT_SECS`, so that an unresponsive server can't stall
+/// the command loop. A timed out request fails like any other request.
+const DEFAULT_API_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_API_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_API_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Timezone determining which day it is, as configured in `TIMEZONE`.
+/// `None` stands for the system's timezone.
+pub fn configured_timezone(config: &dyn Config) -> Option<Tz> {
+    let name = config.get("TIMEZONE").ok()?;
+    match name.trim().parse::<Tz>() {
+        Ok(timezone) => Some(timezone),
+        Err(e) => {
+            log::warn!("ignoring unknown TIMEZONE '{name}': {e}");
+            None
+        }
+    }
+}
+
+/// Today's date in the given timezone, or in the system's if `None`
+pub fn today_in(timezone: Option<Tz>) -> NaiveDate {
+    match timezone {
+        Some(timezone) => chrono::Utc::now().with_timezone(&timezone).date_naive(),
+        None => chrono::Local::now().date_naive(),
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct PersonFilter {
     pub favorites_only: bool,
+    pub present_only: bool,
     pub term: Option<String>,
 }
 
@@ -76,12 +233,93 @@ pub struct AppCore {
     core_event_tx: tokio::sync::broadcast::Sender<CoreEvent>,
     core_cmd_tx: Sender<AppCoreCommand>,
     login_cancel_notify: Arc<Notify>,
+    /// number of consecutive failed initializations
+    init_failures: u32,
+    /// whether an `Initialize` command is about to be sent after a delay
+    reinitialize_scheduled: bool,
+    /// when the access token was last refreshed ahead of expiry
+    last_token_refresh: Option<Instant>,
+    /// root certificates trusted in addition to the system's, see `CA_CERT_PATH`
+    root_certificates: Vec<reqwest::Certificate>,
+    /// whether the core serves a headless run, which leaves no trace: the
+    /// session, selected site and presences aren't persisted, and the
+    /// location isn't tracked, so presence is only reported when asked to
+    /// say hello
+    headless: bool,
+
+    // sites as last received from the server
+    sites: Vec<Site>,
+    /// unfiltered presences per site as last received from the server,
+    /// loaded from disk when first needed
+    cached_presences: Option<HashMap<String, Vec<verishda_dto::types::Presence>>>,
+    /// ETag of the presences last broadcast, so that the server can tell
+    /// when they didn't change
+    presence_etag: Option<String>,
 
     // filter state
     site: Option<String>,
     filter: PersonFilter,
 }
 
+/// Geofence membership and the location fix it was determined from
+#[derive(Debug, Clone, Default)]
+pub struct GeofenceStatus {
+    /// ids of the sites whose geofences the user is currently in
+    pub occupied_geofences: Vec<String>,
+    pub location: Option<Location>,
+}
+
+/// Why initialization failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitFailureKind {
+    /// the sign-in provider could not be reached, retrying may help
+    ProviderUnreachable,
+    /// the configuration (or what the provider responded) is invalid, so
+    /// retrying won't help
+    Misconfigured,
+}
+
+/// Error during initialization, classified by whether retrying may help
+#[derive(Debug)]
+struct InitError {
+    kind: InitFailureKind,
+    error: anyhow::Error,
+}
+
+impl InitError {
+    fn misconfigured(error: impl Into<anyhow::Error>) -> Self {
+        Self { kind: InitFailureKind::Misconfigured, error: error.into() }
+    }
+}
+
+/// Classify a failed OIDC discovery: network problems and server errors
+/// are transient, anything the provider responded with otherwise is not
+fn discovery_failure_kind<RE: std::error::Error + 'static>(error: &DiscoveryError<RE>) -> InitFailureKind {
+    match error {
+        DiscoveryError::Request(_) => InitFailureKind::ProviderUnreachable,
+        DiscoveryError::Response(status, _, _) if status.is_server_error() => InitFailureKind::ProviderUnreachable,
+        DiscoveryError::Response(..)
+        | DiscoveryError::Parse(_)
+        | DiscoveryError::UrlParse(_)
+        | DiscoveryError::Validation(_) => InitFailureKind::Misconfigured,
+        _ => InitFailureKind::ProviderUnreachable,
+    }
+}
+
+/// Describe why exchanging a code for tokens failed, including the error
+/// the provider responded with
+fn token_exchange_failure_reason<RE: std::error::Error + 'static>(error: &RequestTokenError<RE, StandardErrorResponse<CoreErrorResponseType>>) -> String {
+    match error {
+        RequestTokenError::ServerResponse(response) => match response.error_description() {
+            Some(description) => format!("the sign-in provider rejected the login ({}): {description}", response.error()),
+            None => format!("the sign-in provider rejected the login ({})", response.error()),
+        },
+        RequestTokenError::Request(e) => format!("cannot reach the sign-in provider: {e}"),
+        RequestTokenError::Parse(e, _) => format!("unexpected response from the sign-in provider: {e}"),
+        RequestTokenError::Other(e) => e.clone(),
+    }
+}
+
 #[derive(Clone)]
 pub struct AppCoreRef {
     command_tx: tokio::sync::mpsc::Sender<AppCoreCommand>,
@@ -93,12 +331,18 @@ pub enum CoreEvent
 where Self: Send + Sync
 {
     InitializationFinished,
-    InitializationFailed,
+    InitializationFailed{message: String, kind: InitFailureKind},
     LoggingIn,
     LogginSuccessful,
+    /// Exchanging the code received after logging in for tokens failed
+    LoginFailed{reason: String},
     LoggedOut,
     SitesUpdated{sites: Vec<verishda_dto::types::Site>, selected_index: Option<usize>},
-    PresencesChanged(Vec<verishda_dto::types::Presence>),
+    /// Presences at the selected site. If `stale`, refreshing them failed
+    /// and these are the last known presences.
+    PresencesChanged{presences: Vec<verishda_dto::types::Presence>, stale: bool},
+    /// The ids of the sites whose geofence the user is in changed
+    GeofenceChanged{occupied: Vec<String>},
     Terminating,
 }
 
@@ -114,11 +358,13 @@ enum LoginPipeMessage {
 enum AppCoreCommand {
     StartLogin,
     CancelCurrentOperation,
-    ExchangeCodeForToken(String, PkceCodeVerifier),
+    ExchangeCodeForToken(AuthorizationCode, PkceCodeVerifier),
     StartTokenRefresh,
     ReplaceCredentials(Credentials),
     Logout,
     RefreshPrecences,
+    SayHello,
+    HardRefresh,
     PublishAnnouncements{
         site_id: String,
         announcements: Vec<Announcement>
@@ -127,40 +373,120 @@ enum AppCoreCommand {
         user_id: String,
         favorite: bool
     },
+    SetDisplayName{
+        preferred_name: Option<String>,
+    },
     SetSite{
         site_id: String,
     },
     SetPersonFilter(PersonFilter),
+    Reinitialize,
+    Initialize,
+    SetSiteRadiusOverride{
+        site_id: String,
+        radius: Option<f64>,
+    },
     ApplySettings(Settings),
+    InjectLocation(Location),
+    HandleGeofenceTransition(location::GeofenceTransition),
+    QueryGeofenceStatus(tokio::sync::oneshot::Sender<GeofenceStatus>),
     Quit,
 }
 
 impl AppCore {
-    pub fn new(config: Box<dyn Config>) -> AppCoreRef {
-        let (tx, mut rx) = tokio::sync::mpsc::channel::<AppCoreCommand>(10);
-        let (event_tx, _) = tokio::sync::broadcast::channel::<CoreEvent>(10);
-        let core_ref = AppCoreRef {command_tx: tx.clone(), event_tx: event_tx.clone()};
-        let mut app_core = Self {
+    fn with_channels(config: Box<dyn Config>, cmd_tx: Sender<AppCoreCommand>, event_tx: tokio::sync::broadcast::Sender<CoreEvent>) -> Self {
+        Self {
             config,
             location_handler: location::LocationHandler::new(),
             oidc_metadata: None,
             oidc_client: None,
             credentials: None,
-            core_event_tx: event_tx.clone(),
-            core_cmd_tx: tx,
+            core_event_tx: event_tx,
+            core_cmd_tx: cmd_tx,
+            sites: Vec::new(),
             site: None,
             login_cancel_notify: Arc::new(Notify::new()),
+            init_failures: 0,
+            reinitialize_scheduled: false,
+            last_token_refresh: None,
+            root_certificates: Vec::new(),
+            headless: false,
             filter: PersonFilter::default(),
-        };
+            cached_presences: None,
+            presence_etag: None,
+        }
+    }
+
+    pub fn new(config: Box<dyn Config>) -> AppCoreRef {
+        Self::start(config, false)
+    }
+
+    /// A core for a headless run, see `AppCore::headless`
+    pub fn new_headless(config: Box<dyn Config>) -> AppCoreRef {
+        Self::start(config, true)
+    }
+
+    fn start(config: Box<dyn Config>, headless: bool) -> AppCoreRef {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<AppCoreCommand>(10);
+        let (event_tx, _) = tokio::sync::broadcast::channel::<CoreEvent>(10);
+        let core_ref = AppCoreRef {command_tx: tx.clone(), event_tx: event_tx.clone()};
+        let mut app_core = Self::with_channels(config, tx, event_tx.clone());
+        app_core.headless = headless;
 
         // spawn AppCore event observer task, handling starting and stopping the
         // LocationHandler
         let location_handler = app_core.location_handler.clone();
+        let telemetry_log_interval = app_core.config.get("GEOFENCE_TELEMETRY_LOG_SECS").ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .map(Duration::from_secs);
+        let location_poll_interval = app_core.config.get_as_duration_or("LOCATION_POLL_SECS", DEFAULT_LOCATION_POLL_INTERVAL).max(MIN_LOCATION_POLL_INTERVAL);
+        let geofence_enter_polls = app_core.config.get_as_int_or("GEOFENCE_ENTER_POLLS", location::DEFAULT_GEOFENCE_ENTER_POLLS.into()).clamp(1, u32::MAX.into()) as u32;
+        let geofence_exit_polls = app_core.config.get_as_int_or("GEOFENCE_EXIT_POLLS", location::DEFAULT_GEOFENCE_EXIT_POLLS.into()).clamp(1, u32::MAX.into()) as u32;
+        let ip_geolocation_url = app_core.config.get_as_bool_or("IP_GEOLOCATION", false)
+        .then(|| app_core.config.get("IP_GEOLOCATION_URL").unwrap_or(location::DEFAULT_IP_GEOLOCATION_URL.to_string()));
+        let location_override = app_core.config.get("LOCATION_OVERRIDE").ok()
+        .and_then(|l| match location::parse_location(&l) {
+            Ok(location) => Some(location),
+            Err(e) => {
+                log::error!("ignoring invalid LOCATION_OVERRIDE: {e}");
+                None
+            }
+        });
+        // geofence transitions are forwarded to the core, which knows the 
+        // site names and checks out of sites that were left
+        let (transition_tx, mut transition_rx) = tokio::sync::mpsc::unbounded_channel();
+        let transition_cmd_tx = app_core.core_cmd_tx.clone();
+        tokio::spawn(async move {
+            while let Some(transition) = transition_rx.recv().await {
+                if transition_cmd_tx.send(AppCoreCommand::HandleGeofenceTransition(transition)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        // occupied geofences are broadcast right away, so that the UI shows
+        // a detected presence before the next presence refresh
+        let (occupancy_tx, mut occupancy_rx) = tokio::sync::mpsc::unbounded_channel();
+        let occupancy_event_tx = event_tx.clone();
+        tokio::spawn(async move {
+            while let Some(occupied) = occupancy_rx.recv().await {
+                // no receivers is fine, nobody is interested then
+                let _ = occupancy_event_tx.send(CoreEvent::GeofenceChanged{occupied});
+            }
+        });
         let mut event_rx = event_tx.subscribe();
         tokio::spawn(async move {
+            {
+                let mut location_handler = location_handler.lock().await;
+                location_handler.set_telemetry_log_interval(telemetry_log_interval);
+                location_handler.set_geofence_hysteresis(geofence_enter_polls, geofence_exit_polls);
+                location_handler.set_ip_geolocation_url(ip_geolocation_url.as_deref());
+                location_handler.set_location_override(location_override);
+                location_handler.set_transition_sender(Some(transition_tx));
+                location_handler.set_occupancy_sender(Some(occupancy_tx));
+            }
             while let Ok(event) = event_rx.recv().await {
                 match event {
-                    CoreEvent::LogginSuccessful => LocationHandler::start(location_handler.clone(), Duration::from_secs(5)).await,
+                    CoreEvent::LogginSuccessful if !headless => LocationHandler::start(location_handler.clone(), location_poll_interval).await,
                     CoreEvent::LoggingIn | CoreEvent::Terminating => LocationHandler::stop(location_handler.clone()).await,
                     _ => ()
                 }
@@ -171,25 +497,27 @@ impl AppCore {
         tokio::spawn(async move {
 
             log::info!("AppCore background task started");
-            match app_core.init().await {
-                Ok(_) => app_core.broadcast_core_event(CoreEvent::InitializationFinished).await,
-                Err(e) => {
-                    log::error!("initialization failed: {e}");
-                    app_core.broadcast_core_event(CoreEvent::InitializationFailed).await
-                },
-            }
+            app_core.initialize().await;
 
             // start with refreshing presences
             app_core.refresh_sites().await;
 
             // install interval timer
-            let mut site_refresh_ival = tokio::time::interval(Duration::from_secs(5*60));
+            let site_refresh_interval = app_core.config.get_as_duration_or("SITE_REFRESH_SECS", DEFAULT_SITE_REFRESH_INTERVAL).max(MIN_SITE_REFRESH_INTERVAL);
+            let presence_refresh_interval = app_core.config.get_as_duration_or("PRESENCE_REFRESH_SECS", DEFAULT_PRESENCE_REFRESH_INTERVAL).max(MIN_PRESENCE_REFRESH_INTERVAL);
+            let mut site_refresh_ival = tokio::time::interval(site_refresh_interval);
             site_refresh_ival.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-            let mut presence_refresh_ival = tokio::time::interval(Duration::from_secs(1*60));
+            let mut presence_refresh_ival = tokio::time::interval(presence_refresh_interval);
             presence_refresh_ival.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
             
             loop {
+                // a refresh in flight is awaited within the loop, so it can't fire repeatedly
+                let token_refresh_due_at = app_core.token_refresh_due_at();
+                let token_refresh_deadline = tokio::time::Instant::from_std(token_refresh_due_at.unwrap_or_else(Instant::now));
                 tokio::select! {
+                    _ = tokio::time::sleep_until(token_refresh_deadline), if token_refresh_due_at.is_some() => {
+                        app_core.refresh_token_ahead_of_expiry().await;
+                    }
                     _ = site_refresh_ival.tick() => {
                         app_core.refresh_sites().await;
                     }
@@ -223,12 +551,17 @@ impl AppCore {
                 app_core.login_cancel_notify.notify_waiters();
             }
             ExchangeCodeForToken(code, pkce_verifier) => {
-                if let Ok(()) = Self::exchange_code_for_tokens(app_core, code, pkce_verifier).await {
-                    app_core.broadcast_core_event(CoreEvent::LogginSuccessful).await;
+                match Self::exchange_code_for_tokens(app_core, code, pkce_verifier).await {
+                    Ok(()) => app_core.broadcast_core_event(CoreEvent::LogginSuccessful).await,
+                    Err(reason) => {
+                        log::error!("login failed: {reason}");
+                        app_core.broadcast_core_event(CoreEvent::LoginFailed{reason}).await;
+                    }
                 }
             }
             ReplaceCredentials(credentials) => {
-                app_core.credentials = Some(credentials);
+                app_core.store_credentials(credentials);
+                app_core.refresh_sites().await;
                 app_core.broadcast_core_event(CoreEvent::LogginSuccessful).await;
             }
             StartTokenRefresh => {
@@ -238,18 +571,41 @@ impl AppCore {
             },
             Logout => {
                 app_core.credentials = None;
+                app_core.cached_presences = Some(HashMap::new());
+                app_core.presence_etag = None;
+                // a headless run didn't store anything to remove
+                if !app_core.headless {
+                    if let Err(e) = credential_store::clear_refresh_token() {
+                        log::error!("cannot remove refresh token from keyring: {e}");
+                    }
+                    if let Err(e) = presence_cache::clear() {
+                        log::error!("cannot remove cached presences: {e}");
+                    }
+                }
                 app_core.broadcast_core_event(CoreEvent::LoggedOut).await;
             }
             RefreshPrecences => {
                 app_core.update_own_presence().await;
                 app_core.refresh_presences().await;
             },
+            SayHello => {
+                app_core.say_hello().await;
+                // broadcast the presences even if the hello didn't change them
+                app_core.presence_etag = None;
+                app_core.refresh_presences().await;
+            },
+            HardRefresh => {
+                app_core.hard_refresh().await;
+            },
             PublishAnnouncements{site_id, announcements} => {
                 app_core.publish_own_announcements(site_id, announcements).await;
             },
             ChangeFavorite{user_id, favorite} => {
                 app_core.publish_favorite_change(user_id, favorite).await;
             }
+            SetDisplayName{preferred_name} => {
+                app_core.publish_display_name(preferred_name).await;
+            }
             Quit => {
                 app_core.broadcast_core_event(CoreEvent::Terminating).await;
                 return true;
@@ -257,12 +613,46 @@ impl AppCore {
             SetPersonFilter(filter) => {
                 app_core.set_filter(filter).await;
             }
+            Reinitialize => {
+                app_core.schedule_reinitialize();
+            }
+            Initialize => {
+                app_core.reinitialize_scheduled = false;
+                app_core.initialize().await;
+            }
             SetSite{site_id} => {
                 app_core.set_site_impl(&site_id).await;
             }
+            SetSiteRadiusOverride{site_id, radius} => {
+                app_core.set_site_radius_override_impl(&site_id, radius).await;
+            }
             ApplySettings(settings) => {
                 app_core.apply_settings_impl(settings).await;
             }
+            InjectLocation(location) => {
+                app_core.location_handler.lock().await.inject_location(&location);
+            }
+            HandleGeofenceTransition(transition) => {
+                if app_core.config.get_as_bool_or("NOTIFY_ON_GEOFENCE", false) {
+                    let site_name = app_core.sites.iter()
+                    .find(|site| site.id == transition.fence_id())
+                    .map(|site| site.name.as_str())
+                    .unwrap_or(transition.fence_id());
+                    notification::show_geofence_notification(&transition, site_name);
+                }
+                if let location::GeofenceTransition::Exited(site_id) = &transition {
+                    app_core.leave_site(site_id).await;
+                }
+            }
+            QueryGeofenceStatus(reply_tx) => {
+                let location_handler = app_core.location_handler.lock().await;
+                let status = GeofenceStatus {
+                    occupied_geofences: location_handler.get_occupied_geofences(),
+                    location: location_handler.last_fix(),
+                };
+                // the caller may have given up waiting
+                let _ = reply_tx.send(status);
+            }
         }
 
         false
@@ -288,6 +678,17 @@ impl AppCoreRef {
         self.send_cmd(AppCoreCommand::Logout);
     }
 
+    /// Log in with the given tokens instead of interactively, like when
+    /// running headless. Without a refresh token, the session ends once the
+    /// access token expires.
+    pub fn log_in_with_tokens(&self, access_token: String, refresh_token: Option<String>) {
+        self.send_cmd(AppCoreCommand::ReplaceCredentials(Credentials {
+            access_token,
+            refresh_token,
+            expires_at: Instant::now() + ASSUMED_ACCESS_TOKEN_LIFETIME,
+        }));
+    }
+
     pub fn set_site(&self, site_id: &str) {
         let site_id = site_id.to_owned();
         self.send_cmd(AppCoreCommand::SetSite{site_id});
@@ -301,6 +702,18 @@ impl AppCoreRef {
         self.send_cmd(AppCoreCommand::RefreshPrecences);
     }
 
+    /// Check in at the selected site wherever we are, then refresh the
+    /// presences there
+    pub fn say_hello(&self) {
+        self.send_cmd(AppCoreCommand::SayHello);
+    }
+
+    /// Re-fetch everything from the server and the sign-in provider, 
+    /// discarding cached provider metadata
+    pub fn hard_refresh(&self) {
+        self.send_cmd(AppCoreCommand::HardRefresh);
+    }
+
     pub fn change_favorite(&self, user_id: &str, favorite: bool) {
         let user_id = user_id.to_owned();
         self.send_cmd(AppCoreCommand::ChangeFavorite{user_id, favorite});
@@ -317,14 +730,51 @@ impl AppCoreRef {
         self.send_cmd(AppCoreCommand::ApplySettings(settings));
     }
 
+    /// Set the name other users see us with, or go back to the name from
+    /// the sign-in provider if `name` is empty
+    pub fn set_display_name(&self, name: &str) {
+        let preferred_name = Some(name.trim().to_owned()).filter(|n|!n.is_empty());
+        self.send_cmd(AppCoreCommand::SetDisplayName{preferred_name});
+    }
+
+    /// Override the geofence radius of the given site (or remove the
+    /// override when `None` is passed). The radius is clamped to sane bounds.
+    pub fn set_site_radius_override(&self, site_id: &str, radius: Option<f64>) {
+        let site_id = site_id.to_owned();
+        self.send_cmd(AppCoreCommand::SetSiteRadiusOverride{site_id, radius});
+    }
+
     pub fn quit(&self) {
         self.send_cmd(AppCoreCommand::Quit);
     }
 
+    /// retry initialization after it failed
+    pub fn reinitialize(&self) {
+        self.send_cmd(AppCoreCommand::Reinitialize);
+    }
+
     pub fn cancel_login(&self) {
         self.send_cmd(AppCoreCommand::CancelCurrentOperation);
     }
 
+    /// Check the geofences against the given location as if it had been
+    /// reported by the platform
+    pub fn inject_location(&self, location: Location) {
+        self.send_cmd(AppCoreCommand::InjectLocation(location));
+    }
+
+    /// Query the geofences currently occupied and the latest location fix.
+    /// Blocks until the core has processed all previously sent commands, so
+    /// it must not be called from within the tokio runtime.
+    pub fn geofence_status(&self) -> GeofenceStatus {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        self.send_cmd(AppCoreCommand::QueryGeofenceStatus(reply_tx));
+        reply_rx.blocking_recv().unwrap_or_else(|e| {
+            log::error!("no geofence status received: {e}");
+            GeofenceStatus::default()
+        })
+    }
+
     pub fn on_core_event<F>(&self, f: F)
     where F: Fn(CoreEvent) + Send + 'static
     {
@@ -339,9 +789,30 @@ impl AppCoreRef {
 
 }
 
-impl AppCore {
-    const RECONNECT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+fn build_authorization_url(oidc_client: &CoreClient, scopes: &[String]) -> (Url, PkceCodeVerifier, CsrfToken) {
+    // Generate a PKCE challenge.
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+    // Generate the full authorization URL.
+    let mut auth_request = oidc_client
+        .authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        // Set the PKCE code challenge.
+        .set_pkce_challenge(pkce_challenge);
+
+    // the 'openid' scope is always requested by the client
+    for scope in scopes.iter().filter(|s|s.as_str() != "openid") {
+        auth_request = auth_request.add_scope(Scope::new(scope.clone()));
+    }
+    let (auth_url, csrf_token, _nonce) = auth_request.url();
+
+    (auth_url, pkce_verifier, csrf_token)
+}
 
+impl AppCore {
     async fn set_site_impl(&mut self, site_id: &str) {
         let new_site = if site_id.is_empty() {
            None
@@ -351,6 +822,12 @@ impl AppCore {
         let changed = self.site != new_site;
         self.site = new_site;
         if changed {
+            // remember the selection, so that it is restored after a restart
+            if let Some(site_id) = self.site.as_ref().filter(|_|!self.headless) {
+                if let Err(e) = self.config.set(LAST_SITE_ID_KEY, site_id) {
+                    log::error!("cannot write last selected site: {e}");
+                }
+            }
             self.refresh_presences().await;
         }
     }
@@ -359,7 +836,21 @@ impl AppCore {
         settings.apply_to(&mut (self.config));
     }
 
+    async fn set_site_radius_override_impl(&mut self, site_id: &str, radius: Option<f64>) {
+        // an empty value removes the override
+        let value = radius
+        .map(|r|r.clamp(MIN_SITE_RADIUS, MAX_SITE_RADIUS).to_string())
+        .unwrap_or_default();
+        if let Err(e) = self.config.set(&site_radius_override_key(site_id), &value) {
+            log::error!("cannot write site radius override: {e}");
+            return;
+        }
+        self.sync_geofences().await;
+        self.broadcast_sites_updated().await;
+    }
+
     async fn run_token_refresh(&mut self) -> Result<()> {
+        let http_client = self.oidc_request_client();
         let credentials;
         
         if let Some(c) = self.credentials.as_mut() {
@@ -368,9 +859,17 @@ impl AppCore {
             return Err(anyhow::anyhow!("no refresh token available"));
         }
 
-        let refresh_token = RefreshToken::new(credentials.refresh_token.clone());
+        let refresh_token = match &credentials.refresh_token {
+            Some(refresh_token) => RefreshToken::new(refresh_token.clone()),
+            None => {
+                // without a refresh token, the user needs to log in again
+                self.credentials = None;
+                self.broadcast_core_event(CoreEvent::LoggedOut).await;
+                return Err(anyhow::anyhow!("access token expired and no refresh token available"));
+            }
+        };
         match self.oidc_client.as_ref().unwrap().exchange_refresh_token(&refresh_token)
-            .request_async(async_http_client)
+            .request_async(|request|oidc_http_client(http_client, request))
             .await 
         {
             Ok(resp) => {
@@ -386,17 +885,62 @@ impl AppCore {
         }
     }
 
+    /// When to refresh the access token ahead of its expiry, if there is
+    /// a refresh token to do so
+    fn token_refresh_due_at(&self) -> Option<Instant> {
+        let credentials = self.credentials.as_ref()?;
+        credentials.refresh_token.as_ref()?;
+        let due_at = credentials.expires_at.checked_sub(TOKEN_REFRESH_LEAD).unwrap_or(credentials.expires_at);
+        match self.last_token_refresh {
+            Some(last) => Some(due_at.max(last + MIN_TOKEN_REFRESH_INTERVAL)),
+            None => Some(due_at),
+        }
+    }
+
+    /// Refresh the access token before it expires, so that requests don't
+    /// have to wait for the refresh. Failures are only logged; once the
+    /// token has expired, `create_client` refreshes it again.
+    async fn refresh_token_ahead_of_expiry(&mut self) {
+        self.last_token_refresh = Some(Instant::now());
+        let Some(oidc_client) = &self.oidc_client else {
+            return;
+        };
+        let Some(refresh_token) = self.credentials.as_ref().and_then(|c|c.refresh_token.clone()) else {
+            return;
+        };
+        let http_client = self.oidc_request_client();
+        let refresh_result = oidc_client
+            .exchange_refresh_token(&RefreshToken::new(refresh_token.clone()))
+            .request_async(|request|oidc_http_client(http_client, request))
+            .await;
+        match refresh_result {
+            Ok(token_response) => {
+                log::debug!("access token refreshed ahead of expiry");
+                let credentials = Self::credentials_from_token_response_now(&token_response, Some(refresh_token));
+                self.store_credentials(credentials);
+            }
+            Err(e) => log::warn!("refreshing access token ahead of expiry failed, retrying later: {e}"),
+        }
+    }
+
     async fn create_client(&mut self) -> Result<verishda_dto::Client> {
+        self.create_client_with_headers(HeaderMap::new()).await
+    }
+
+    /// Like `create_client`, with the client sending the given headers in 
+    /// addition to the authorization
+    async fn create_client_with_headers(&mut self, mut headers: HeaderMap) -> Result<verishda_dto::Client> {
         if let Some(credentials) = &self.credentials {
             if Instant::now().cmp(&credentials.expires_at) == std::cmp::Ordering::Greater{
                 self.run_token_refresh().await?;
             }
 
-            let mut headers = HeaderMap::new();
             let access_token = &self.credentials.as_ref().unwrap().access_token;
             headers.insert("Authorization", format!("Bearer {access_token}").parse().unwrap());
-            let inner = reqwest::Client::builder()
+            let inner = http_client::client_builder(&self.root_certificates)
                 .default_headers(headers)
+                .timeout(self.config.get_as_duration_or("API_TIMEOUT_SECS", DEFAULT_API_TIMEOUT).max(MIN_API_TIMEOUT))
+                .connect_timeout(self.config.get_as_duration_or("API_CONNECT_TIMEOUT_SECS", DEFAULT_API_CONNECT_TIMEOUT).max(MIN_API_TIMEOUT))
                 .connection_verbose(true)
                 .build()
                 .expect("client creation failed");
@@ -412,41 +956,23 @@ impl AppCore {
         log::trace!("Refreshing sites");
         if let Ok(client) = self.create_client().await {
             
-            match client.handle_get_sites().await {
+            match client.handle_get_sites(None, None, None).await {
                 Ok(sites_response) => {
                     let sites = sites_response.into_inner();
                     log::debug!("Got sites: {sites:?}", );
-                    let mut location_handler = self.location_handler.lock().await;
-                    location_handler.clear_geofences();
-                    for site in &sites {
-                        let location = Location::new(site.latitude as f64, site.longitude as f64);
-                        let _ = location_handler.add_geofence_circle(&site.id, &location, 100.);
-                    }
-                    drop(location_handler);
+                    self.sites = sites;
+                    self.sync_geofences().await;
 
                     // find out new selected site_id and index after
-                    // filtering the current selection against the
-                    // sites list we just received
-                    let site_index = self.site.as_ref()
-                    .or(sites.get(0).map(|s|&s.id))
-                    .and_then(|selected_id|{
-                        sites.iter()
-                        .position(|site|&site.id == selected_id)
-                        .map(|i|(selected_id.clone(),i))
-                    });
-
-                    let selected_index;
-                    match site_index {
-                        Some((site_id, index)) => {
-                            selected_index = Some(index);
-                            self.site = Some(site_id);
-                        }
-                        None => {
-                            selected_index = None;
-                            self.site = None;
-                        }
-                    }
-                    self.broadcast_core_event(CoreEvent::SitesUpdated{sites, selected_index}).await;
+                    // filtering the current (or last stored) selection 
+                    // against the sites list we just received
+                    let last_site_id = self.config.get(LAST_SITE_ID_KEY).ok();
+                    let default_site_id = self.config.get(DEFAULT_SITE_ID_KEY).ok()
+                    .filter(|id|!id.trim().is_empty());
+                    let site_index = select_site(&self.sites, self.site.as_deref(), last_site_id.as_deref(), default_site_id.as_deref().map(str::trim));
+
+                    self.site = site_index.map(|(site_id,_)|site_id);
+                    self.broadcast_sites_updated().await;
 
                     self.refresh_presences().await;
                 }
@@ -457,20 +983,94 @@ impl AppCore {
         }
     }
 
+    /// Replace the installed geofences with the polygons of the current
+    /// sites, or circles around those without a polygon, honoring user 
+    /// radius overrides
+    async fn sync_geofences(&mut self) {
+        let mut location_handler = self.location_handler.lock().await;
+        location_handler.clear_geofences();
+        for site in &self.sites {
+            if !site.polygon.is_empty() {
+                let corners = site.polygon.iter()
+                .map(|p| Location::new(p.latitude as f64, p.longitude as f64))
+                .collect::<Vec<_>>();
+                match location_handler.add_geofence_polygon(&site.id, &corners) {
+                    Ok(()) => continue,
+                    Err(e) => log::error!("ignoring polygon of site {}: {e}", site.id),
+                }
+            }
+            let location = Location::new(site.latitude as f64, site.longitude as f64);
+            let radius = effective_site_radius(&*self.config, site);
+            let _ = location_handler.add_geofence_circle(&site.id, &location, radius);
+        }
+    }
+
+    /// Publish the current sites (with their effective radius) and the
+    /// index of the selected site
+    async fn broadcast_sites_updated(&self) {
+        let sites = self.sites.iter()
+        .map(|site|Site {
+            radius: effective_site_radius(&*self.config, site) as f32,
+            ..site.clone()
+        })
+        .collect::<Vec<_>>();
+        let selected_index = self.site.as_ref()
+        .and_then(|site_id|sites.iter().position(|site|&site.id == site_id));
+        self.broadcast_core_event(CoreEvent::SitesUpdated{sites, selected_index}).await;
+    }
+
+    /// Check out of the given site, so that others don't see us present 
+    /// there until our presence expires
+    async fn leave_site(&mut self, site_id: &str) {
+        if let Ok(client) = self.create_client().await {
+            if let Err(e) = client.handle_post_sites_siteid_goodbye(site_id).await {
+                log::error!("Failed to leave site {site_id}: {e}")
+            }
+        }
+    }
+
     async fn update_own_presence(&mut self) {
         if let Ok(client) = self.create_client().await {
             // note: the geo fence IDs are are set as the site IDs
-            for site_id in self.location_handler.lock().await.get_occupied_geofences() {
-                if let Err(e) = client.handle_post_sites_siteid_hello(&site_id).await {
-                    log::error!("Failed to update presence for site {site_id}: {e}")
+            let mut site_ids = {
+                let location_handler = self.location_handler.lock().await;
+                if self.config.get_as_bool_or("SINGLE_SITE_PRESENCE", false) {
+                    location_handler.get_nearest_occupied_geofence().into_iter().collect()
+                } else {
+                    location_handler.get_occupied_geofences()
                 }
+            };
+            if site_ids.is_empty() {
+                return;
+            }
+            // keep the order stable, the server logs into the first site if in doubt
+            site_ids.sort();
+            if let Err(e) = client.handle_post_presence_hello(Some(std::env::consts::OS), Some("geofence"), &site_ids).await {
+                log::error!("Failed to update presence for sites {site_ids:?}: {e}")
+            }
+        }
+    }
+
+    /// Check in at the selected site, regardless of the location
+    async fn say_hello(&mut self) {
+        let Some(site_id) = self.site.clone() else {
+            log::warn!("no site selected, not saying hello");
+            return;
+        };
+        if let Ok(client) = self.create_client().await {
+            if let Err(e) = client.handle_post_presence_hello(Some(std::env::consts::OS), None, &vec![site_id.clone()]).await {
+                log::error!("Failed to say hello at site {site_id}: {e}")
             }
         }
     }
 
     async fn refresh_presences(&mut self) {
 
-        let client = match self.create_client().await {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = self.presence_etag.as_ref().and_then(|etag|etag.parse().ok()) {
+            headers.insert(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        let client = match self.create_client_with_headers(headers).await {
             Ok(client) => client,
             Err(error) => {
                 log::error!("failed to create client: {error}");
@@ -491,18 +1091,75 @@ impl AppCore {
             .filter(|t|!t.is_empty())
             .map(|t|t.as_str());
         let favorites_only = Some(self.filter.favorites_only);
-        match client.handle_get_sites_siteid_presence(site, favorites_only, None, None, term).await {
+        let present_only = Some(self.filter.present_only);
+        let filtered = term.is_some() || self.filter.favorites_only || self.filter.present_only;
+        match client.handle_get_sites_siteid_presence(site, favorites_only, None, None, None, present_only, term).await {
             Ok(sites_response) => {
+                self.presence_etag = sites_response.headers()
+                    .get(reqwest::header::ETAG)
+                    .and_then(|etag|etag.to_str().ok())
+                    .map(str::to_string);
                 let presences = sites_response.into_inner();
                 log::debug!("Got presences: {:?}", presences);
-                self.broadcast_core_event(CoreEvent::PresencesChanged(presences)).await;
+                if filtered {
+                    self.broadcast_core_event(CoreEvent::PresencesChanged{presences, stale: false}).await;
+                } else {
+                    let site = site.clone();
+                    self.broadcast_core_event(CoreEvent::PresencesChanged{presences: presences.clone(), stale: false}).await;
+                    self.cache_presences(site, presences);
+                }
+            }
+            Err(verishda_dto::Error::UnexpectedResponse(response)) if response.status() == reqwest::StatusCode::NOT_MODIFIED => {
+                log::trace!("presences at site {site} unchanged");
             }
             Err(e) => {
-                log::error!("Failed to get sites: {}", e);
+                log::error!("Failed to get presences: {}", e);
+                // whatever is shown next must be broadcast
+                self.presence_etag = None;
+                // filtered lists aren't cached, so there is nothing to show
+                // for them
+                if filtered {
+                    return;
+                }
+                let site = site.clone();
+                if let Some(presences) = self.cached_presences().get(&site).cloned() {
+                    log::info!("showing last known presences at site {site}");
+                    self.broadcast_core_event(CoreEvent::PresencesChanged{presences, stale: true}).await;
+                }
             }
         }
     }
 
+    fn cached_presences(&mut self) -> &mut HashMap<String, Vec<verishda_dto::types::Presence>> {
+        self.cached_presences.get_or_insert_with(presence_cache::load)
+    }
+
+    /// Keep the given unfiltered presences at the site, in memory and on
+    /// disk, for when refreshing fails
+    fn cache_presences(&mut self, site_id: String, presences: Vec<verishda_dto::types::Presence>) {
+        self.cached_presences().insert(site_id, presences);
+        if self.headless {
+            return;
+        }
+        if let Err(e) = presence_cache::store(self.cached_presences()) {
+            log::error!("cannot cache presences: {e}");
+        }
+    }
+
+    async fn hard_refresh(&mut self) {
+        log::info!("hard refresh: discarding cached provider metadata");
+        if let Err(e) = metadata_cache::clear() {
+            log::error!("cannot remove cached provider metadata: {e}");
+        }
+        // the current client stays in place if discovery fails
+        if let Err(e) = self.init().await {
+            log::warn!("rediscovering the sign-in provider failed: {}", e.error);
+        }
+        self.update_own_presence().await;
+        // also refreshes presences
+        self.refresh_sites().await;
+    }
+
     async fn set_filter(&mut self, filter: PersonFilter) {
         self.filter = filter;
         self.refresh_presences().await;
@@ -533,9 +1190,25 @@ impl AppCore {
         self.refresh_presences().await;
     }
 
+    async fn publish_display_name(&mut self, preferred_name: Option<String>) {
+        let client: verishda_dto::Client = match self.create_client().await {
+            Err(e) => {
+                log::error!("can't create client: {e}");
+                return
+            }
+            Ok(c) => c,
+        };
+        let display_name = verishda_dto::types::DisplayName { preferred_name };
+        if let Err(e) = client.handle_put_me_display_name(&display_name).await {
+            log::error!("call to set display name failed: {e}");
+        }
+
+        self.refresh_presences().await;
+    }
+
     async fn publish_own_announcements(&mut self, site_id: String, announcements: Vec<Announcement>) {
         if let Ok(client) = self.create_client().await {
-            let now_date = chrono::Utc::now().naive_utc().date();
+            let now_date = today_in(configured_timezone(&*self.config));
             debug!("{announcements:?}");
             let announcements = announcements.iter()
                 .enumerate()
@@ -555,7 +1228,8 @@ impl AppCore {
 
                     Some(PresenceAnnouncement{
                         kind,
-                        date
+                        date,
+                        recurring_until: None,
                     })
                 })
                 .filter_map(|o|o)
@@ -574,6 +1248,11 @@ impl AppCore {
         });
     }
 
+    /// Client for requests to the sign-in provider
+    fn oidc_request_client(&self) -> reqwest::Client {
+        http_client::oidc_client(&self.root_certificates)
+    }
+
     fn api_base_url(&self) -> String{
         self.config.get("API_BASE_URL").unwrap()
     }
@@ -600,28 +1279,32 @@ impl AppCore {
     async fn attempt_reconnect(app_core: &mut AppCore) -> Result<()> {
         // FIXME: need to shut down location manager
         if let Some(credentials) = &app_core.credentials {
+            let refresh_token = match &credentials.refresh_token {
+                Some(refresh_token) => RefreshToken::new(refresh_token.clone()),
+                None => {
+                    log::debug!("no refresh token available, not attempting token refresh");
+                    return Ok(());
+                }
+            };
             app_core.broadcast_core_event(CoreEvent::LoggingIn).await;
-            let refresh_token = RefreshToken::new(credentials.refresh_token.clone());
             let oidc_client = app_core.oidc_client.as_ref().unwrap().clone();
+            let http_client = app_core.oidc_request_client();
             let cmd_tx = app_core.core_cmd_tx.clone();
 
             let shutdown_notify = app_core.login_cancel_notify.clone();
+            let base_delay = app_core.config.get_as_duration_or("RECONNECT_BASE_SECS", DEFAULT_RECONNECT_BASE_DELAY).max(MIN_RECONNECT_BASE_DELAY);
+            let max_delay = app_core.config.get_as_duration_or("RECONNECT_MAX_SECS", DEFAULT_RECONNECT_MAX_DELAY);
 
             tokio::spawn(async move {
 
-                // set retry intverval so that:
-                // we retry connecting every couple of seconds
-                // we skip missed ticks in case program was paused, either by the harware
-                // (laptop) going to sleep, or program begin suspeded in the debugger.
-                let mut retry_interval = tokio::time::interval(Self::RECONNECT_RETRY_INTERVAL);
-                retry_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
-
+                // each reconnect starts over with the base delay
+                let mut retry = 0;
                 loop {
                     log::debug!("attempting token refresh");
 
                     let refresh_result = oidc_client
                     .exchange_refresh_token(&refresh_token)
-                    .request_async(async_http_client).await;
+                    .request_async(|request|oidc_http_client(http_client.clone(), request)).await;
                 
                     match refresh_result {
                         Ok(token_response) => {
@@ -651,13 +1334,15 @@ impl AppCore {
                                 cmd_tx.send(AppCoreCommand::Logout).await.unwrap();
                                 break;
                             } else {
-                                log::debug!("error while token refresh, retrying...");
+                                let delay = reconnect_delay(base_delay, max_delay, retry, random_jitter());
+                                retry += 1;
+                                log::debug!("error while token refresh, retrying in {delay:?}...");
                                 tokio::select! {
                                     _ = shutdown_notify.notified() => {
                                         cmd_tx.send(AppCoreCommand::Logout).await.unwrap();
                                         break
                                     }
-                                    _ = retry_interval.tick() => continue,
+                                    _ = tokio::time::sleep(delay) => continue,
                                 }
                             }
                         }   
@@ -695,26 +1380,41 @@ impl AppCore {
                 }
             };
 
-            let mut cmd = AppCoreCommand::Logout;
-            tokio::select! {
-                _ = shutdown_notify.notified() => {
-                    return;
-                }
-                ws_result = ws_stream.next() => match ws_result {
-                    Some(Ok(tokio_tungstenite::tungstenite::Message::Text(code))) => {
-                        cmd = AppCoreCommand::ExchangeCodeForToken(code, pkce_verifier);
-                    }
-                    Some(Ok(msg)) => {
-                        log::error!("wrong message type received: {msg}");
-                    }
-                    Some(Err(e)) => {
-                        log::error!("error while reading from websocket: {e}");
+            let cmd = loop {
+                tokio::select! {
+                    _ = shutdown_notify.notified() => {
+                        return;
                     }
-                    None => {
-                        log::error!("stream terminated without providing code");
+                    ws_result = ws_stream.next() => match ws_result {
+                        Some(Ok(tokio_tungstenite::tungstenite::Message::Text(code))) => {
+                            break AppCoreCommand::ExchangeCodeForToken(AuthorizationCode::new(code), pkce_verifier);
+                        }
+                        // the server keeps the socket alive while the user logs in;
+                        // pongs are sent by tungstenite while reading
+                        Some(Ok(tokio_tungstenite::tungstenite::Message::Ping(_) | tokio_tungstenite::tungstenite::Message::Pong(_))) => {
+                            log::trace!("login websocket keepalive received");
+                        }
+                        Some(Ok(tokio_tungstenite::tungstenite::Message::Close(frame))) => {
+                            // the server gave up waiting for the login, like after a timeout
+                            let reason = frame.map(|f|f.reason.to_string()).unwrap_or_default();
+                            log::info!("login cancelled by server: {reason}");
+                            break AppCoreCommand::Logout;
+                        }
+                        Some(Ok(msg)) => {
+                            log::error!("wrong message type received: {msg}");
+                            break AppCoreCommand::Logout;
+                        }
+                        Some(Err(e)) => {
+                            log::error!("error while reading from websocket: {e}");
+                            break AppCoreCommand::Logout;
+                        }
+                        None => {
+                            log::error!("stream terminated without providing code");
+                            break AppCoreCommand::Logout;
+                        }
                     }
                 }
-            }
+            };
             cmd_tx.send(cmd).await.unwrap();
         });
         Ok(auth_url)
@@ -729,8 +1429,7 @@ impl AppCore {
         let refresh_token = token_response
         .refresh_token()
         .map(|r|r.secret().clone())
-        .or(fallback_refresh_token)
-        .expect("either a refresh_token must be present in the response, or a fallback token must be given");
+        .or(fallback_refresh_token);
 
         Credentials {
             access_token: token_response.access_token().secret().clone(),
@@ -749,56 +1448,202 @@ impl AppCore {
         Instant::now() + expires_in
     }
 
-    async fn exchange_code_for_tokens(app_core: &mut AppCore, code: String, pkce_verifier: PkceCodeVerifier) -> Result<()> {
-        let client = app_core.oidc_client.as_ref().unwrap();
-        let token_response = client.exchange_code(AuthorizationCode::new(code))
+    /// Exchange the code for tokens and log in with them, failing with the
+    /// reason to show to the user
+    async fn exchange_code_for_tokens(app_core: &mut AppCore, code: AuthorizationCode, pkce_verifier: PkceCodeVerifier) -> std::result::Result<(), String> {
+        let http_client = app_core.oidc_request_client();
+        let client = app_core.oidc_client.as_ref()
+            .ok_or_else(||"not connected to the sign-in provider".to_string())?;
+        let token_response = client.exchange_code(code)
             .set_pkce_verifier(pkce_verifier)
-            .request_async(async_http_client)
-            .await?;
+            .request_async(|request|oidc_http_client(http_client, request))
+            .await
+            .map_err(|e|token_exchange_failure_reason(&e))?;
         let credentials = Self::credentials_from_token_response_now(&token_response, None);
 
         log::info!("Exchanged into access_token {credentials:?}");
-        app_core.credentials = Some(credentials);
+        app_core.store_credentials(credentials);
         app_core.refresh_sites().await;
 
         Ok(())
     }
 
+    fn oidc_scopes(&self) -> Vec<String> {
+        self.config.get("OIDC_SCOPES")
+        .unwrap_or(DEFAULT_OIDC_SCOPES.to_string())
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+    }
+
     fn authorization_url(&self) -> (Url, PkceCodeVerifier, CsrfToken) {
-        // Generate a PKCE challenge.
-        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
-
-        // Generate the full authorization URL.
-        let (auth_url, csrf_token, _nonce) = self.oidc_client.as_ref().unwrap()
-            .authorize_url(
-                CoreAuthenticationFlow::AuthorizationCode,
-                CsrfToken::new_random,
-                Nonce::new_random,
-            )
-            // Set the PKCE code challenge.
-            .set_pkce_challenge(pkce_challenge)
-            .add_scope(Scope::new("offline_access".into()))
-            .url();
-
-        (auth_url, pkce_verifier, csrf_token)
-    }
-
-    async fn init(&mut self) -> Result<()>{
-        let issuer_url = self.config.get("ISSUER_URL")?;
-        let client_id = self.config.get("CLIENT_ID")?;
-        let issuer_url = IssuerUrl::new(issuer_url.to_string()).unwrap();
-        let redirect_url = RedirectUrl::new(self.redirect_url())?;
+        build_authorization_url(self.oidc_client.as_ref().unwrap(), &self.oidc_scopes())
+    }
+
+    /// Keep the given credentials and persist their refresh token in the
+    /// keyring, unless running headless
+    fn store_credentials(&mut self, credentials: Credentials) {
+        if let Some(refresh_token) = credentials.refresh_token.as_ref().filter(|_|!self.headless) {
+            if let Err(e) = credential_store::store_refresh_token(refresh_token) {
+                log::error!("cannot store refresh token in keyring: {e}");
+            }
+        }
+        self.credentials = Some(credentials);
+    }
+
+    /// Attempt a silent login with the refresh token stored by a previous
+    /// session. Returns false if there is no stored token or it can't be
+    /// refreshed, so that the user needs to log in.
+    async fn restore_session(&mut self) -> bool {
+        // a headless run logs in with the tokens it is given
+        if self.headless {
+            return false;
+        }
+        let Some(refresh_token) = credential_store::load_refresh_token() else {
+            return false;
+        };
+        let Some(oidc_client) = self.oidc_client.clone() else {
+            return false;
+        };
+        log::info!("found stored refresh token, attempting silent login");
+        self.broadcast_core_event(CoreEvent::LoggingIn).await;
+        let http_client = self.oidc_request_client();
+        let refresh_result = oidc_client
+            .exchange_refresh_token(&RefreshToken::new(refresh_token.clone()))
+            .request_async(|request|oidc_http_client(http_client, request))
+            .await;
+        match refresh_result {
+            Ok(token_response) => {
+                let credentials = Self::credentials_from_token_response_now(&token_response, Some(refresh_token));
+                self.store_credentials(credentials);
+                self.refresh_sites().await;
+                self.broadcast_core_event(CoreEvent::LogginSuccessful).await;
+                true
+            }
+            Err(e) => {
+                log::error!("silent login failed: {e}");
+                // a rejected token won't work next time either
+                if matches!(&e, RequestTokenError::ServerResponse(r) if matches!(r.error(), CoreErrorResponseType::InvalidGrant)) {
+                    if let Err(e) = credential_store::clear_refresh_token() {
+                        log::error!("cannot remove refresh token from keyring: {e}");
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    async fn initialize(&mut self) {
+        match self.init().await {
+            Ok(_) => {
+                self.init_failures = 0;
+                // only show the welcome view if no silent login is attempted
+                if !self.restore_session().await {
+                    self.broadcast_core_event(CoreEvent::InitializationFinished).await
+                }
+            }
+            Err(InitError{kind, error}) => {
+                log::error!("initialization failed: {error}");
+                self.init_failures += 1;
+                let message = match kind {
+                    InitFailureKind::ProviderUnreachable => format!("Can't reach the sign-in provider, retrying automatically: {error}"),
+                    InitFailureKind::Misconfigured => format!("The sign-in provider is not configured correctly: {error}"),
+                };
+                self.broadcast_core_event(CoreEvent::InitializationFailed{message, kind}).await;
+                if kind == InitFailureKind::ProviderUnreachable {
+                    self.schedule_reinitialize();
+                }
+            },
+        }
+    }
+
+    /// Send an `Initialize` command after a delay that backs off on 
+    /// repeated failures, unless one is already scheduled. The delay is 
+    /// spent in a separate task so that other commands (like quitting) 
+    /// are still processed.
+    fn schedule_reinitialize(&mut self) {
+        if self.reinitialize_scheduled {
+            return;
+        }
+        self.reinitialize_scheduled = true;
+        let delay = self.reinitialize_delay();
+        log::info!("reinitializing in {delay:?}");
+        let cmd_tx = self.core_cmd_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(e) = cmd_tx.send(AppCoreCommand::Initialize).await {
+                log::error!("failed to send command {e}");
+            }
+        });
+    }
+
+    /// Delay before reinitializing, doubling with each consecutive failure
+    fn reinitialize_delay(&self) -> Duration {
+        let exponent = self.init_failures.saturating_sub(1).min(16);
+        (OIDC_DISCOVERY_RETRY_BASE_DELAY * 2u32.pow(exponent)).min(MAX_REINITIALIZE_DELAY)
+    }
+
+    /// Run OIDC discovery, retrying with exponential backoff on transient
+    /// failures
+    async fn discover_with_retries(&self, issuer_url: &IssuerUrl) -> std::result::Result<CoreProviderMetadata, InitError> {
+        let retries = self.config.get("OIDC_DISCOVERY_RETRIES").ok()
+            .and_then(|r|r.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_OIDC_DISCOVERY_RETRIES);
+
+        let http_client = self.oidc_request_client();
+        let mut delay = OIDC_DISCOVERY_RETRY_BASE_DELAY;
+        let mut attempt = 0;
+        loop {
+            match CoreProviderMetadata::discover_async(issuer_url.clone(), |request|oidc_http_client(http_client.clone(), request)).await {
+                Ok(metadata) => return Ok(metadata),
+                Err(e) if attempt < retries && discovery_failure_kind(&e) == InitFailureKind::ProviderUnreachable => {
+                    attempt += 1;
+                    log::warn!("OIDC discovery failed ({e}), retry {attempt}/{retries} in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => return Err(InitError { kind: discovery_failure_kind(&e), error: e.into() }),
+            }
+        }
+    }
+
+    async fn init(&mut self) -> std::result::Result<(), InitError>{
+        let issuer_url = self.config.get("ISSUER_URL").map_err(InitError::misconfigured)?;
+        let client_id = self.config.get("CLIENT_ID").map_err(InitError::misconfigured)?;
+        let issuer_url = IssuerUrl::new(issuer_url.to_string()).map_err(InitError::misconfigured)?;
+        let redirect_url = RedirectUrl::new(self.redirect_url()).map_err(InitError::misconfigured)?;
+        self.root_certificates = http_client::load_root_certificates(&*self.config).map_err(InitError::misconfigured)?;
         
-        self.oidc_metadata = Some(CoreProviderMetadata::discover_async(
-            issuer_url,
-            async_http_client,
-        ).await?);
+        let metadata = match self.discover_with_retries(&issuer_url).await {
+            Ok(metadata) => {
+                if let Err(e) = metadata_cache::store(&metadata) {
+                    log::error!("cannot cache provider metadata: {e}");
+                }
+                metadata
+            }
+            Err(e) => match metadata_cache::load(&issuer_url) {
+                Some(metadata) => {
+                    log::warn!("OIDC discovery failed ({}), using cached provider metadata", e.error);
+                    metadata
+                }
+                None => return Err(e),
+            }
+        };
+        self.oidc_metadata = Some(metadata);
 
         let client_id = ClientId::new(client_id.to_string());
+        // public clients authenticate via PKCE only, confidential clients
+        // additionally with their secret
+        let client_secret = self.config.get("CLIENT_SECRET").ok()
+            .filter(|secret|!secret.is_empty())
+            .map(ClientSecret::new);
+        if client_secret.is_some() {
+            log::info!("authenticating as confidential client");
+        }
         let client = CoreClient::from_provider_metadata(
             self.oidc_metadata.as_ref().unwrap().clone(),
             client_id,
-            None,
+            client_secret,
         )
         // Set the URL the user will be redirected to after the authorization process.
         .set_redirect_uri(redirect_url);
@@ -808,4 +1653,212 @@ impl AppCore {
         Ok(())
     }
 
- }
\ No newline at end of file
+ }
+
+#[test]
+fn test_site_radius_override_takes_precedence() {
+    use verishda_config::HashMapConfig;
+
+    let site = Site {
+        id: "site-a".to_string(),
+        name: "Site A".to_string(),
+        latitude: 48.,
+        longitude: 9.,
+        radius: 100.,
+        polygon: vec![],
+    };
+
+    let config = HashMapConfig::from(HashMap::new());
+    assert_eq!(effective_site_radius(&config, &site), 100.);
+
+    let config = HashMapConfig::from(HashMap::from([
+        (site_radius_override_key("site-a"), "250".to_string()),
+    ]));
+    assert_eq!(effective_site_radius(&config, &site), 250.);
+
+    // overrides are clamped
+    let config = HashMapConfig::from(HashMap::from([
+        (site_radius_override_key("site-a"), "1".to_string()),
+    ]));
+    assert_eq!(effective_site_radius(&config, &site), MIN_SITE_RADIUS);
+
+    // an empty override falls back to the server value
+    let config = HashMapConfig::from(HashMap::from([
+        (site_radius_override_key("site-a"), "".to_string()),
+    ]));
+    assert_eq!(effective_site_radius(&config, &site), 100.);
+}
+
+#[test]
+fn test_select_site_prefers_current_then_last_site() {
+    let sites = ["site-a", "site-b", "site-c"].map(|id| Site {
+        id: id.to_string(),
+        name: id.to_string(),
+        latitude: 48.,
+        longitude: 9.,
+        radius: 100.,
+        polygon: vec![],
+    });
+
+    assert_eq!(select_site(&sites, Some("site-c"), Some("site-b"), None), Some(("site-c".to_string(), 2)));
+    assert_eq!(select_site(&sites, None, Some("site-b"), None), Some(("site-b".to_string(), 1)));
+    // a site that is gone falls back to the first site
+    assert_eq!(select_site(&sites, None, Some("site-x"), None), Some(("site-a".to_string(), 0)));
+    assert_eq!(select_site(&sites, None, None, None), Some(("site-a".to_string(), 0)));
+    assert_eq!(select_site(&[], None, Some("site-b"), None), None);
+}
+
+#[test]
+fn test_select_site_falls_back_to_configured_default() {
+    let sites = [("site-a", "Berlin"), ("site-b", "Munich"), ("site-c", "Reutlingen")].map(|(id, name)| Site {
+        id: id.to_string(),
+        name: name.to_string(),
+        latitude: 48.,
+        longitude: 9.,
+        radius: 100.,
+        polygon: vec![],
+    });
+
+    // the last selected site takes precedence
+    assert_eq!(select_site(&sites, None, Some("site-b"), Some("site-c")), Some(("site-b".to_string(), 1)));
+    assert_eq!(select_site(&sites, None, Some("site-x"), Some("site-c")), Some(("site-c".to_string(), 2)));
+    // the default may be given by name
+    assert_eq!(select_site(&sites, None, None, Some("Reutlingen")), Some(("site-c".to_string(), 2)));
+    // a default that is gone falls back to the first site
+    assert_eq!(select_site(&sites, None, None, Some("Hamburg")), Some(("site-a".to_string(), 0)));
+}
+
+#[test]
+fn test_discovery_failure_kind() {
+    use openidconnect::http::StatusCode;
+
+    type Error = DiscoveryError<std::io::Error>;
+    let unreachable = Error::Request(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused"));
+    assert_eq!(discovery_failure_kind(&unreachable), InitFailureKind::ProviderUnreachable);
+    let unavailable = Error::Response(StatusCode::SERVICE_UNAVAILABLE, Vec::new(), "unavailable".to_string());
+    assert_eq!(discovery_failure_kind(&unavailable), InitFailureKind::ProviderUnreachable);
+    let not_found = Error::Response(StatusCode::NOT_FOUND, Vec::new(), "not found".to_string());
+    assert_eq!(discovery_failure_kind(&not_found), InitFailureKind::Misconfigured);
+    let invalid = Error::Validation("unexpected issuer".to_string());
+    assert_eq!(discovery_failure_kind(&invalid), InitFailureKind::Misconfigured);
+}
+
+#[test]
+fn test_reconnect_delay() {
+    let base = Duration::from_secs(10);
+    let max = Duration::from_secs(60);
+
+    assert_eq!(reconnect_delay(base, max, 0, 0), base);
+    assert_eq!(reconnect_delay(base, max, 2, 0), Duration::from_secs(40));
+    assert_eq!(reconnect_delay(base, max, 3, 0), max);
+    assert_eq!(reconnect_delay(base, max, 100, 0), max);
+
+    // jitter shortens the delay by at most half
+    for jitter in [1, 4_999, 5_000, 123_456_789, u64::MAX] {
+        let delay = reconnect_delay(base, max, 0, jitter);
+        assert!(delay >= base / 2 && delay <= base, "{delay:?}");
+    }
+}
+
+#[test]
+fn test_authorization_url_contains_configured_scopes() {
+    use openidconnect::{AuthUrl, JsonWebKeySet, TokenUrl};
+
+    let oidc_client = CoreClient::new(
+        ClientId::new("test-client".to_string()),
+        None,
+        IssuerUrl::new("https://idp.example.com".to_string()).unwrap(),
+        AuthUrl::new("https://idp.example.com/auth".to_string()).unwrap(),
+        Some(TokenUrl::new("https://idp.example.com/token".to_string()).unwrap()),
+        None,
+        JsonWebKeySet::default(),
+    );
+    let scopes = ["openid", "profile", "email"].map(str::to_string);
+    let (auth_url, _, _) = build_authorization_url(&oidc_client, &scopes);
+
+    let scope_param = auth_url.query_pairs()
+        .find(|(k,_)|k == "scope")
+        .map(|(_,v)|v.to_string())
+        .unwrap();
+    let requested_scopes = scope_param.split_whitespace().collect::<Vec<_>>();
+    assert!(requested_scopes.contains(&"openid"));
+    assert!(requested_scopes.contains(&"profile"));
+    assert!(requested_scopes.contains(&"email"));
+    assert!(!requested_scopes.contains(&"offline_access"));
+}
+
+#[tokio::test]
+async fn test_geofence_status_reports_injected_location() {
+    use verishda_config::HashMapConfig;
+
+    let (cmd_tx, _cmd_rx) = tokio::sync::mpsc::channel(10);
+    let (event_tx, _) = tokio::sync::broadcast::channel(10);
+    let mut app_core = AppCore::with_channels(Box::new(HashMapConfig::from(HashMap::new())), cmd_tx, event_tx);
+    let center = Location::new(48.4887, 9.2180);
+    app_core.location_handler.lock().await.add_geofence_circle("site", &center, 100.).unwrap();
+
+    AppCore::process_command(&mut app_core, AppCoreCommand::InjectLocation(center.clone())).await;
+
+    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    AppCore::process_command(&mut app_core, AppCoreCommand::QueryGeofenceStatus(reply_tx)).await;
+    let status = reply_rx.await.unwrap();
+    assert_eq!(status.occupied_geofences, vec!["site".to_string()]);
+    assert_eq!(status.location.map(|l| (l.latitude(), l.longitude())), Some((center.latitude(), center.longitude())));
+}
+
+#[tokio::test]
+async fn test_token_refresh_due_at() {
+    use verishda_config::HashMapConfig;
+
+    let (cmd_tx, _cmd_rx) = tokio::sync::mpsc::channel(10);
+    let (event_tx, _) = tokio::sync::broadcast::channel(10);
+    let mut app_core = AppCore::with_channels(Box::new(HashMapConfig::from(HashMap::new())), cmd_tx, event_tx);
+    assert_eq!(app_core.token_refresh_due_at(), None);
+
+    let expires_at = Instant::now() + Duration::from_secs(300);
+    app_core.credentials = Some(Credentials {
+        access_token: "access".to_string(),
+        refresh_token: None,
+        expires_at,
+    });
+    // without a refresh token, the token can't be refreshed
+    assert_eq!(app_core.token_refresh_due_at(), None);
+
+    app_core.credentials.as_mut().unwrap().refresh_token = Some("refresh".to_string());
+    assert_eq!(app_core.token_refresh_due_at(), Some(expires_at - TOKEN_REFRESH_LEAD));
+
+    // right after a refresh, the next one is held back
+    let last_refresh = Instant::now();
+    app_core.last_token_refresh = Some(last_refresh);
+    app_core.credentials.as_mut().unwrap().expires_at = last_refresh + Duration::from_secs(30);
+    assert_eq!(app_core.token_refresh_due_at(), Some(last_refresh + MIN_TOKEN_REFRESH_INTERVAL));
+}
+
+#[test]
+fn test_token_exchange_failure_reason() {
+    let error = RequestTokenError::<std::io::Error, _>::ServerResponse(StandardErrorResponse::new(
+        CoreErrorResponseType::InvalidGrant,
+        Some("PKCE verification failed".to_string()),
+        None,
+    ));
+    assert_eq!(token_exchange_failure_reason(&error), "the sign-in provider rejected the login (invalid_grant): PKCE verification failed");
+
+    let error = RequestTokenError::<std::io::Error, StandardErrorResponse<CoreErrorResponseType>>::Other("code expired".to_string());
+    assert_eq!(token_exchange_failure_reason(&error), "code expired");
+}
+
+#[test]
+fn test_credentials_debug_redacts_tokens() {
+    let credentials = Credentials {
+        access_token: "secret-access-token".to_string(),
+        refresh_token: Some("secret-refresh-token".to_string()),
+        expires_at: Instant::now(),
+    };
+    let debug = format!("{credentials:?}");
+    assert!(!debug.contains("secret"), "{debug}");
+    assert!(debug.contains("<redacted, 19 chars>"), "{debug}");
+
+    let cmd = AppCoreCommand::ExchangeCodeForToken(AuthorizationCode::new("secret-code".to_string()), PkceCodeVerifier::new("secret-verifier".to_string()));
+    let debug = format!("{cmd:?}");
+    assert!(!debug.contains("secret"), "{debug}");
+}
@@ -0,0 +1,32 @@
+use super::location::GeofenceTransition;
+
+/// Text of the notification shown for the given geofence transition
+fn geofence_notification_text(transition: &GeofenceTransition, site_name: &str) -> String {
+    match transition {
+        GeofenceTransition::Entered(_) => format!("Checked in at {site_name}"),
+        GeofenceTransition::Exited(_) => format!("Left {site_name}"),
+    }
+}
+
+/// Show a desktop notification about entering or leaving a site. Showing
+/// notifications may block, so this is done on a blocking thread.
+pub(super) fn show_geofence_notification(transition: &GeofenceTransition, site_name: &str) {
+    let body = geofence_notification_text(transition, site_name);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = notify_rust::Notification::new()
+            .summary("Verishda")
+            .body(&body)
+            .show()
+        {
+            log::error!("cannot show notification '{body}': {e}");
+        }
+    });
+}
+
+#[test]
+fn test_geofence_notification_text() {
+    let entered = GeofenceTransition::Entered("site-a".to_string());
+    let exited = GeofenceTransition::Exited("site-a".to_string());
+    assert_eq!(geofence_notification_text(&entered, "Site A"), "Checked in at Site A");
+    assert_eq!(geofence_notification_text(&exited, "Site A"), "Left Site A");
+}
@@ -0,0 +1,50 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use super::verishda_dto::types::Presence;
+
+/// Location of the file caching the presences of the last successful
+/// refresh, per site
+fn cache_file_path() -> Option<PathBuf> {
+    Some(dirs::cache_dir()?
+        .join("verishda")
+        .join("presences.json"))
+}
+
+/// Load the cached presences of all sites
+pub(super) fn load() -> HashMap<String, Vec<Presence>> {
+    let Some(path) = cache_file_path() else {
+        return HashMap::new();
+    };
+    let Ok(content) = std::fs::read(&path) else {
+        return HashMap::new();
+    };
+    match serde_json::from_slice(&content) {
+        Ok(presences) => presences,
+        Err(e) => {
+            log::error!("cannot parse cached presences in {}: {e}", path.display());
+            HashMap::new()
+        }
+    }
+}
+
+/// Remove the cached presences, if any
+pub(super) fn clear() -> Result<()> {
+    let path = cache_file_path().ok_or_else(||anyhow!("no cache directory available"))?;
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Cache the given presences per site, for showing them when refreshing
+/// fails
+pub(super) fn store(presences: &HashMap<String, Vec<Presence>>) -> Result<()> {
+    let path = cache_file_path().ok_or_else(||anyhow!("no cache directory available"))?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, serde_json::to_vec(presences)?)?;
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow,Result};
+
+/// Starts verishda on login via an XDG autostart desktop entry, which
+/// most Linux desktop environments support
+pub(crate) struct LinuxStartupBehaviour;
+
+const DESKTOP_FILE_NAME: &str = "verishda.desktop";
+
+impl LinuxStartupBehaviour {
+    fn desktop_file_path() -> Result<PathBuf> {
+        let config_dir = dirs::config_dir()
+        .ok_or(anyhow!("config directory unavailable"))?;
+        Ok(config_dir.join("autostart").join(DESKTOP_FILE_NAME))
+    }
+
+    fn exec_path() -> Result<String> {
+        let exec_path = std::env::current_exe()
+        .map_err(|_|anyhow!("executable path unavailable"))?;
+        let exec_path = exec_path
+        .to_str()
+        .ok_or(anyhow!("cannot convert PathBuf to String"))?
+        ;
+        Ok(quote_exec_arg(exec_path))
+    }
+}
+
+/// Quote an argument of a desktop entry's `Exec` key, as described in the
+/// desktop entry specification
+fn quote_exec_arg(arg: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        match c {
+            '"' | '`' | '$' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '%' => quoted.push_str("%%"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl super::StartupBehaviour for LinuxStartupBehaviour {
+
+    fn set_run_on_startup_enabled(run_on_startup: bool) -> Result<()> {
+        let desktop_file_path = Self::desktop_file_path()?;
+        if run_on_startup {
+            if let Some(autostart_dir) = desktop_file_path.parent() {
+                std::fs::create_dir_all(autostart_dir)?;
+            }
+            let desktop_entry = format!(
+                "[Desktop Entry]\nType=Application\nName=Verishda\nExec={}\nX-GNOME-Autostart-enabled=true\n",
+                Self::exec_path()?
+            );
+            std::fs::write(&desktop_file_path, desktop_entry)?;
+        } else if desktop_file_path.exists() {
+            std::fs::remove_file(&desktop_file_path)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_run_on_startup_enabled() -> Result<bool> {
+        Ok(Self::desktop_file_path()?.exists())
+    }
+
+    fn run_on_startup_supported() -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_quote_exec_arg() {
+    assert_eq!(quote_exec_arg("/usr/bin/verishda"), "\"/usr/bin/verishda\"");
+    assert_eq!(quote_exec_arg("/home/me/my apps/verishda"), "\"/home/me/my apps/verishda\"");
+    assert_eq!(quote_exec_arg("/opt/$x/100%/\"v\""), "\"/opt/\\$x/100%%/\\\"v\\\"\"");
+}
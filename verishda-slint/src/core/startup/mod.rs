@@ -6,6 +6,8 @@ use std::{collections::HashSet, str::FromStr};
 mod windows;
 #[cfg(target_os="macos")]
 mod macos;
+#[cfg(target_os="linux")]
+mod linux;
 
 pub trait StartupBehaviour {
     fn run_on_startup_supported() -> bool;
@@ -20,6 +22,8 @@ pub trait StartupBehaviour {
 type PlatformStartupBehaviour = windows::WindowsStartupBehaviour;
 #[cfg(target_os="macos")]
 type PlatformStartupBehaviour = macos::MacOSStartupBehaviour;
+#[cfg(target_os="linux")]
+type PlatformStartupBehaviour = linux::LinuxStartupBehaviour;
 
 #[derive(Clone)]
 pub struct StartupConfig;
@@ -0,0 +1,80 @@
+use std::{sync::mpsc, time::{Duration, Instant}};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use verishda_config::Config;
+
+use crate::core::{verishda_dto::types::{Presence, Site}, AppCore, CoreEvent};
+
+/// how long to wait for the server's responses before giving up
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a headless run prints to stdout
+#[derive(Serialize)]
+struct HeadlessResult {
+    site: Site,
+    presences: Vec<Presence>,
+}
+
+/// Run without UI: log in with the `ACCESS_TOKEN` (and optional
+/// `REFRESH_TOKEN`) from the config, fetch the presences at the given site
+/// (or the one selected last), optionally say hello there, and print the
+/// result as JSON. Nothing is persisted, see `AppCore::new_headless`.
+/// Must be called within the tokio runtime, but not from one of its tasks.
+pub fn run(config: impl Config + 'static, site_id: Option<String>, hello: bool) -> Result<()> {
+    let access_token = config.get("ACCESS_TOKEN")
+        .map_err(|_|anyhow!("ACCESS_TOKEN must be configured to run headless"))?;
+    let refresh_token = config.get("REFRESH_TOKEN").ok();
+
+    let app_core = AppCore::new_headless(Box::new(config));
+    let (event_tx, event_rx) = mpsc::channel();
+    app_core.on_core_event(move |event| {
+        // the receiver is gone once the run is over
+        let _ = event_tx.send(event);
+    });
+
+    if let Some(site_id) = &site_id {
+        app_core.set_site(site_id);
+    }
+    // logging in fetches the sites and the presences at the selected site
+    app_core.log_in_with_tokens(access_token, refresh_token);
+    let site = wait_for_event(&event_rx, |event| match event {
+        CoreEvent::SitesUpdated{sites, selected_index} => Some(selected_index.map(|i|sites[i].clone())),
+        _ => None,
+    })?
+    .ok_or_else(||anyhow!("no site found, check the site id"))?;
+    let mut presences = wait_for_presences(&event_rx)?;
+
+    if hello {
+        app_core.say_hello();
+        presences = wait_for_presences(&event_rx)?;
+    }
+
+    app_core.quit();
+    println!("{}", serde_json::to_string_pretty(&HeadlessResult { site, presences })?);
+    Ok(())
+}
+
+fn wait_for_presences(event_rx: &mpsc::Receiver<CoreEvent>) -> Result<Vec<Presence>> {
+    wait_for_event(event_rx, |event| match event {
+        CoreEvent::PresencesChanged{presences, stale: false} => Some(presences),
+        CoreEvent::PresencesChanged{stale: true, ..} => {
+            log::warn!("refreshing presences failed, waiting for the next attempt");
+            None
+        }
+        _ => None,
+    })
+}
+
+/// Wait for the first event `f` maps to a value
+fn wait_for_event<T>(event_rx: &mpsc::Receiver<CoreEvent>, f: impl Fn(CoreEvent) -> Option<T>) -> Result<T> {
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let event = event_rx.recv_timeout(timeout)
+            .map_err(|_|anyhow!("no response from the server within {RESPONSE_TIMEOUT:?}"))?;
+        if let Some(value) = f(event) {
+            return Ok(value);
+        }
+    }
+}
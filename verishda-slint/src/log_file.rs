@@ -0,0 +1,142 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use verishda_config::Config;
+
+/// Size after which the log file is rotated
+const MAX_LOG_FILE_SIZE: u64 = 5 * 1024 * 1024;
+/// Number of rotated log files kept besides the current one
+const ROTATED_LOG_FILES: u32 = 3;
+const LOG_FILE_NAME: &str = "verishda.log";
+
+/// Directory to write the log file to if `LOG_TO_FILE` is set, which is
+/// `LOG_DIR` or a `logs` directory in the platform's local data directory
+pub fn log_dir(config: &impl Config) -> Option<PathBuf> {
+    if !config.get_as_bool_or("LOG_TO_FILE", false) {
+        return None;
+    }
+    match config.get("LOG_DIR") {
+        Ok(dir) if !dir.trim().is_empty() => Some(PathBuf::from(dir.trim())),
+        _ => Some(dirs::data_local_dir()?.join("verishda").join("logs")),
+    }
+}
+
+/// A log file that is rotated once it exceeds `MAX_LOG_FILE_SIZE`, with
+/// `verishda.log.1` being the most recent of the rotated files
+pub struct RotatingLogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl RotatingLogFile {
+    pub fn open(dir: &Path) -> Result<Self> {
+        Self::with_max_size(dir, MAX_LOG_FILE_SIZE)
+    }
+
+    fn with_max_size(dir: &Path, max_size: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE_NAME);
+        let file = File::options().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size, max_size })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        for index in (1..ROTATED_LOG_FILES).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Logger passing records on to the platform's logger and also writing
+/// them to a log file
+pub struct FileLogger {
+    inner: Box<dyn log::Log>,
+    file: Mutex<RotatingLogFile>,
+}
+
+impl FileLogger {
+    pub fn new(inner: Box<dyn log::Log>, file: RotatingLogFile) -> Self {
+        Self { inner, file: Mutex::new(file) }
+    }
+}
+
+impl log::Log for FileLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.inner.log(record);
+        let line = format!("{} {:<5} [{}] {}\n",
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+            record.level(),
+            record.target(),
+            record.args());
+        if let Ok(mut file) = self.file.lock() {
+            // there is nowhere left to report failing to log
+            let _ = file.write_line(&line);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+#[test]
+fn test_log_file_rotation() {
+    let dir = std::env::temp_dir().join(format!("verishda-log-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let mut log_file = RotatingLogFile::with_max_size(&dir, 10).unwrap();
+    for line in ["first\n", "second\n", "third\n", "fourth\n", "fifth\n"] {
+        log_file.write_line(line).unwrap();
+    }
+
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+    assert_eq!(read("verishda.log"), "fifth\n");
+    assert_eq!(read("verishda.log.1"), "fourth\n");
+    assert_eq!(read("verishda.log.2"), "third\n");
+    assert_eq!(read("verishda.log.3"), "second\n");
+    assert!(!dir.join("verishda.log.4").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
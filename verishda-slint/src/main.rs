@@ -2,17 +2,21 @@
 
 use clap::Parser;
 
-use chrono::{Datelike, Days};
-use core::{verishda_dto::types::{Presence, PresenceAnnouncementKind, Site}, Settings};
-use std::{collections::HashMap, env};
+use chrono::{Datelike, Days, NaiveDate};
+use core::{verishda_dto::types::{Presence, PresenceAnnouncement, PresenceAnnouncementKind, Site}, Settings};
+use std::env;
 
 use core::{Announcement, AppCoreRef, CoreEvent, PersonFilter};
 use slint::{Model, ModelRc, VecModel, Weak};
-use verishda_config::{default_config, CompositeConfig, Config, EnvConfig};
+use verishda_config::{default_config, CompositeConfig, Config, EnvConfig, FileConfig, ENV_PREFIX};
 
 slint::include_modules!();
 
 mod core;
+mod headless;
+mod log_file;
+mod map;
+mod tray;
 
 use core::AppCore;
 
@@ -24,36 +28,39 @@ const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 struct Args {
     #[arg(long)]
     redirect_url: Option<String>,
+    /// Run without UI, logging in with ACCESS_TOKEN (and optionally
+    /// REFRESH_TOKEN) from the config, and print the presences as JSON
+    #[arg(long)]
+    headless: bool,
+    /// Site to fetch the presences of when running headless, instead of the
+    /// one selected last
+    #[arg(long, requires = "headless")]
+    site: Option<String>,
+    /// Say hello at the site when running headless
+    #[arg(long, requires = "headless")]
+    hello: bool,
 }
 
 fn main() {
     let args = Args::parse();
 
-    #[cfg(not(target_os = "macos"))]
-    {
-        
-        simple_logger::SimpleLogger::new()
-        .with_level(log::LevelFilter::Info)
-        .env()
-        .init()
-        .unwrap();
-    }
-    #[cfg(target_os = "macos")]
-    {
-        const SUBSYSTEM: &str = "com.pachler.verishda-slint";
-        println!("IMPORTANT: Verishda logging uses os_log. To see log messages, use the 'Console' application and filter by sybsystem '{SUBSYSTEM}'");
-        oslog::OsLogger::new(SUBSYSTEM)
-        .level_filter(log::LevelFilter::Debug)
-        .init()
-        .unwrap();
-    }
+    let config = mk_config();
+    init_logging(&config, args.headless);
 
     log::info!("Starting up Verishda");
 
     let runtime = tokio::runtime::Runtime::new().unwrap();
     let _g = runtime.enter();
 
-    ui_main();
+    if args.headless {
+        if let Err(e) = headless::run(config, args.site, args.hello) {
+            log::error!("headless run failed: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    ui_main(config);
 }
 
 
@@ -74,10 +81,54 @@ impl Into<Settings> for SettingsModel {
     }
 }
 
-fn ui_main() {
-    let inital_config = mk_config();
+/// Log to stderr (or os_log on macOS), and to a rotated log file if 
+/// `LOG_TO_FILE` is set
+fn init_logging(config: &impl Config, headless: bool) {
+    #[cfg(not(target_os = "macos"))]
+    let (logger, level): (Box<dyn log::Log>, _) = {
+        // keep the output of headless runs parseable
+        let level = if headless { log::LevelFilter::Warn } else { log::LevelFilter::Info };
+        let logger = simple_logger::SimpleLogger::new()
+        .with_level(level)
+        .env();
+        let level = logger.max_level();
+        (Box::new(logger), level)
+    };
+    #[cfg(target_os = "macos")]
+    let (logger, level): (Box<dyn log::Log>, _) = {
+        const SUBSYSTEM: &str = "com.pachler.verishda-slint";
+        // stdout is reserved for the output of headless runs
+        if !headless {
+            eprintln!("IMPORTANT: Verishda logging uses os_log. To see log messages, use the 'Console' application and filter by sybsystem '{SUBSYSTEM}'");
+        }
+        let level = log::LevelFilter::Debug;
+        (Box::new(oslog::OsLogger::new(SUBSYSTEM).level_filter(level)), level)
+    };
+
+    let log_file = log_file::log_dir(config)
+    .map(|dir| log_file::RotatingLogFile::open(&dir).map_err(|e|(dir, e)));
+    let logger: Box<dyn log::Log> = match log_file {
+        Some(Ok(log_file)) => {
+            eprintln!("logging to {}", log_file.path().display());
+            Box::new(log_file::FileLogger::new(logger, log_file))
+        }
+        Some(Err((dir, e))) => {
+            eprintln!("cannot log to a file in {}: {e}", dir.display());
+            logger
+        }
+        None => logger,
+    };
+    log::set_boxed_logger(logger).unwrap();
+    log::set_max_level(level);
+}
+
+fn ui_main(inital_config: impl Config + 'static) {
 
     let settings_model: SettingsModel = to_settings_model(&inital_config);
+    let map_settings = map::MapSettings::from_config(&inital_config);
+    let start_minimized = start_minimized(&inital_config, tray::TRAY_SUPPORTED);
+    let days_ahead = announced_days_ahead(&inital_config);
+    let timezone = core::configured_timezone(&inital_config);
     let app_core = AppCore::new(Box::new(inital_config));
 
     let main_window = MainWindow::new().unwrap();
@@ -104,12 +155,30 @@ fn ui_main() {
 
     app_ui.set_settings(settings_model);
 
+    app_ui.set_show_map(map_settings.show_map);
+    app_ui.set_announced_days_ahead(days_ahead as i32);
+    app_ui.set_site_markers(ModelRc::new(VecModel::default()));
+    app_ui.set_map_tiles(ModelRc::new(VecModel::default()));
+
     let main_window_weak = main_window.as_weak();
     let app_core_clone = app_core.clone();
     app_ui.on_login_cancelled(move || {
         cancel_login(app_core_clone.clone(), main_window_weak.clone());
     });
 
+    let app_core_clone = app_core.clone();
+    app_ui.on_retry_initialization_requested(move || {
+        log::info!("retrying initialization");
+        app_core_clone.reinitialize();
+    });
+
+    app_ui.on_quit_requested(|| {
+        log::info!("quit requested");
+        if let Err(e) = slint::quit_event_loop() {
+            log::error!("failed to quit event loop: {e}");
+        }
+    });
+
     let app_core_clone = app_core.clone();
     app_ui.on_site_selected(move |site_id| {
         site_selected(app_core_clone.clone(), &site_id);
@@ -120,19 +189,23 @@ fn ui_main() {
         refresh_requested(app_core_clone.clone());
     });
     let app_core_clone = app_core.clone();
+    app_ui.on_hard_refresh_requested(move || {
+        hard_refresh_requested(app_core_clone.clone());
+    });
+    let app_core_clone = app_core.clone();
     app_ui.on_change_favorite_requested(move |user_id, favorite| {
         change_favorite_requested(app_core_clone.clone(), &user_id, favorite)
     });
     let app_core_clone = app_core.clone();
-    app_ui.on_filter_set(move |term, favorites_only| {
+    app_ui.on_filter_set(move |term, favorites_only, present_only| {
         let term = term.trim();
         let term = if !term.is_empty() {
             Some(term.to_owned())
         } else {
             None
         };
-        log::info!("setting filter to {term:?}, {favorites_only}");
-        set_filter(app_core_clone.clone(), term, favorites_only)
+        log::info!("setting filter to {term:?}, {favorites_only}, {present_only}");
+        set_filter(app_core_clone.clone(), term, favorites_only, present_only)
     });
     
     let app_core_clone = app_core.clone();
@@ -141,40 +214,108 @@ fn ui_main() {
         announce(app_core_clone.clone(), site_id.to_string(), person);
     });
 
+    let app_core_clone = app_core.clone();
+    app_ui.on_site_radius_change_requested(move |site_id, radius| {
+        log::info!("site radius change requested for site {site_id}: {radius}");
+        app_core_clone.set_site_radius_override(&site_id, Some(radius as f64));
+    });
+
+    let app_core_clone = app_core.clone();
+    app_ui.on_site_radius_reset_requested(move |site_id| {
+        log::info!("site radius reset requested for site {site_id}");
+        app_core_clone.set_site_radius_override(&site_id, None);
+    });
+
     let app_core_clone = app_core.clone();
     app_ui.on_apply_settings_requested(move |settings_model|{
         app_core_clone.apply_settings(settings_model.into())
     });
 
+    let app_core_clone = app_core.clone();
+    app_ui.on_display_name_change_requested(move |name|{
+        log::info!("display name change requested");
+        app_core_clone.set_display_name(&name);
+    });
+
     let main_window_weak = main_window.as_weak();
     app_core.on_core_event(move |event| {
         log::debug!("core event received: {event:?}");
-        main_window_weak
-            .upgrade_in_event_loop(|main_window| {
-                let app_ui = main_window.global::<AppUI>();
+        if let CoreEvent::SitesUpdated{sites, ..} = &event {
+            if map_settings.show_map {
+                map::update_site_map(&map_settings, sites, main_window_weak.clone());
+            }
+        }
+        update_main_window(&main_window_weak, |main_window| {
+            let app_ui = main_window.global::<AppUI>();
 
-                process_event(app_ui, event);
-             })
-            .unwrap();
+            process_event(app_ui, event, core::today_in(timezone));
+        });
     });
 
-    main_window.show().unwrap();
+    if start_minimized {
+        log::info!("starting minimized");
+    } else {
+        main_window.show().unwrap();
+    }
 
-    slint::run_event_loop().unwrap();
+    if tray::TRAY_SUPPORTED {
+        // keep running while the window is closed, so that the core keeps 
+        // reporting presence and the window can be reopened from the tray
+        tray::install_tray_icon(app_core.clone(), main_window.as_weak());
+        slint::run_event_loop_until_quit().unwrap();
+    } else {
+        slint::run_event_loop().unwrap();
+    }
 
     app_core.quit();
 }
 
-fn process_event(app_ui: AppUI<'_>, event: CoreEvent) {
+/// Whether to start without showing the main window, as requested by the
+/// `START_MINIMIZED` config flag. Ignored without a tray icon, as there
+/// would be no way to open the window.
+fn start_minimized(config: &impl Config, tray_available: bool) -> bool {
+    let start_minimized = config.get_as_bool_or("START_MINIMIZED", false);
+    if start_minimized && !tray_available {
+        log::warn!("START_MINIMIZED is set, but there is no tray icon to open the window from; showing it");
+        return false;
+    }
+    start_minimized
+}
+
+/// Run `f` with the main window in the event loop thread.
+/// 
+/// Core events are delivered from background tasks, which keep running
+/// while the UI shuts down: when the user closes the window, `run_event_loop()`
+/// returns and `AppCore::quit()` makes the core broadcast `Terminating`, which
+/// arrives after the event loop has ended. Scheduling a UI update then fails,
+/// which is expected and therefore only logged instead of panicking.
+fn update_main_window<F>(main_window_weak: &Weak<MainWindow>, f: F)
+where F: FnOnce(MainWindow) + Send + 'static
+{
+    if let Err(e) = main_window_weak.upgrade_in_event_loop(f) {
+        log::debug!("main window unavailable, dropping UI update: {e}");
+    }
+}
+
+fn process_event(app_ui: AppUI<'_>, event: CoreEvent, today: NaiveDate) {
     match event {
         core::CoreEvent::InitializationFinished => 
             app_ui.set_state(MainWindowState::ShowingWelcomeView),
-        core::CoreEvent::InitializationFailed =>
-            panic!("Failed to fetch provider metadata"),
-        core::CoreEvent::LoggingIn => 
-            app_ui.set_state(MainWindowState::ShowingWaitingForLoginView),
+        core::CoreEvent::InitializationFailed{message, kind} => {
+            app_ui.set_error_message(message.into());
+            app_ui.set_error_retryable(kind == core::InitFailureKind::ProviderUnreachable);
+            app_ui.set_state(MainWindowState::ShowingError)
+        }
+        core::CoreEvent::LoggingIn => {
+            app_ui.set_login_error("".into());
+            app_ui.set_state(MainWindowState::ShowingWaitingForLoginView)
+        }
         core::CoreEvent::LogginSuccessful => 
             app_ui.set_state(MainWindowState::ShowingSitePresenceView),
+        core::CoreEvent::LoginFailed{reason} => {
+            app_ui.set_login_error(format!("Login failed: {reason}").into());
+            app_ui.set_state(MainWindowState::ShowingWelcomeView)
+        }
         core::CoreEvent::LoggedOut => 
             app_ui.set_state(MainWindowState::ShowingWelcomeView),
         core::CoreEvent::SitesUpdated{sites, selected_index} => {
@@ -184,36 +325,80 @@ fn process_event(app_ui: AppUI<'_>, event: CoreEvent) {
                 .downcast_ref::<VecModel<SiteModel>>()
                 .expect("we set VecModel<> earlier");
 
-            let sites_vec: Vec<SiteModel> =
-                sites.iter().map(|site| site.into()).collect();
+            // keep showing which sites the user is at until the next
+            // geofence change
+            let occupied: Vec<String> = sites_model.iter()
+                .filter(|site| site.at_site)
+                .map(|site| site.id.to_string())
+                .collect();
+            let sites_vec: Vec<SiteModel> = sites.iter()
+                .map(|site| SiteModel {
+                    at_site: occupied.contains(&site.id),
+                    ..site.into()
+                })
+                .collect();
         
             sites_model.set_vec(sites_vec);
             app_ui.set_selected_site_index(selected_index.map(|i|i as i32).unwrap_or(-1))
         }
-        core::CoreEvent::PresencesChanged(presences) => {
+        core::CoreEvent::PresencesChanged{presences, stale} => {
             let persons_model = app_ui.get_persons();
             let persons_model = persons_model
                 .as_any()
                 .downcast_ref::<VecModel<PersonModel>>()
                 .expect("we set VecModel<> earlier");
 
+            let days_ahead = app_ui.get_announced_days_ahead().max(0) as u32;
             let persons_vec: Vec<PersonModel> =
-                presences.iter().map(to_person_model).collect();
+                presences.iter().map(|p| to_person_model(p, days_ahead, today)).collect();
 
             persons_model.set_vec(persons_vec);
+            app_ui.set_presences_stale(stale);
 
             let current_day =
-                chrono::Local::now().weekday().num_days_from_monday() as i32;
+                today.weekday().num_days_from_monday() as i32;
             app_ui.set_current_day_index(current_day)
         }
+        core::CoreEvent::GeofenceChanged{occupied} => {
+            let sites_model = app_ui.get_sites();
+            for (index, mut site) in sites_model.iter().enumerate() {
+                let at_site = occupied.iter().any(|id| id.as_str() == site.id.as_str());
+                if site.at_site != at_site {
+                    site.at_site = at_site;
+                    sites_model.set_row_data(index, site);
+                }
+            }
+        }
         core::CoreEvent::Terminating => ()  // no special handling for termination for now
     }
 }
 
+/// Config holding settings made by the user in the client, stored in the
+/// user's config directory
+fn settings_file_config() -> Option<FileConfig> {
+    let path = dirs::config_dir()?
+        .join("verishda")
+        .join("settings.env");
+    match FileConfig::from_path(&path) {
+        Ok(file_config) => Some(file_config),
+        Err(e) => {
+            log::error!("cannot read settings file {}: {e}", path.display());
+            None
+        }
+    }
+}
+
 fn mk_config() -> impl Config {
+    let cfg: Box<dyn Config> = match settings_file_config() {
+        Some(file_config) => Box::new(CompositeConfig::from_configs(
+            Box::new(file_config),
+            Box::new(default_config())
+        )),
+        None => Box::new(default_config()),
+    };
     let cfg = CompositeConfig::from_configs(
-        Box::new(EnvConfig::from_env()), 
-        Box::new(default_config())
+        Box::new(EnvConfig::with_prefix(ENV_PREFIX)), 
+        cfg
     );
     let cfg = CompositeConfig::from_configs(
         Box::new(core::startup::StartupConfig{}), 
@@ -233,12 +418,10 @@ fn start_logout(app_core: &AppCoreRef, _main_window_weak: Weak<MainWindow>) {
 
 fn cancel_login(app_core: AppCoreRef, main_window: Weak<MainWindow>) {
     app_core.cancel_login();
-    main_window
-        .upgrade_in_event_loop(|main_window| {
-            let app_ui = main_window.global::<AppUI>();
-            app_ui.set_state(MainWindowState::ShowingWelcomeView);
-        })
-        .unwrap();
+    update_main_window(&main_window, |main_window| {
+        let app_ui = main_window.global::<AppUI>();
+        app_ui.set_state(MainWindowState::ShowingWelcomeView);
+    });
 }
 
 fn site_selected(app_core: AppCoreRef, site_id: &str) {
@@ -251,14 +434,19 @@ fn refresh_requested(app_core: AppCoreRef) {
     app_core.refresh();
 }
 
+fn hard_refresh_requested(app_core: AppCoreRef) {
+    log::info!("Hard refresh requested");
+    app_core.hard_refresh();
+}
+
 fn change_favorite_requested(app_core: AppCoreRef, user_id: &str, favorite: bool) {
     log::info!("favorite state change requested for user {user_id}: {favorite}");
     app_core.change_favorite(user_id, favorite);
 }
 
-fn set_filter(app_core: AppCoreRef, term: Option<String>, favorites_only: bool) {
-    log::info!("favorit only filter set: {favorites_only}");
-    app_core.filter(PersonFilter{term, favorites_only})
+fn set_filter(app_core: AppCoreRef, term: Option<String>, favorites_only: bool, present_only: bool) {
+    log::info!("favorit only filter set: {favorites_only}, present only filter set: {present_only}");
+    app_core.filter(PersonFilter{term, favorites_only, present_only})
 }
 
 fn announce(app_core: AppCoreRef, site_id: String, person: PersonModel) {
@@ -278,6 +466,8 @@ impl Into<SiteModel> for &Site {
         SiteModel {
             id: self.id.clone().into(),
             name: self.name.clone().into(),
+            radius: self.radius.round() as i32,
+            at_site: false,
         }
     }
 }
@@ -292,49 +482,44 @@ impl Into<Announcement> for AnnouncementModel {
     }
 }
 
-const ANNOUNCED_DAYS_AHEAD: u32 = 7;
+/// number of days (including today) that announcements are shown for,
+/// unless configured otherwise via `ANNOUNCE_DAYS_AHEAD`
+const DEFAULT_ANNOUNCED_DAYS_AHEAD: u32 = 7;
+const MAX_ANNOUNCED_DAYS_AHEAD: u32 = 28;
 
-fn to_person_model(presence: &Presence) -> PersonModel {
-    let now_date = chrono::Local::now().date_naive();
+fn announced_days_ahead(config: &impl Config) -> u32 {
+    let days = config.get_as_int_or("ANNOUNCE_DAYS_AHEAD", DEFAULT_ANNOUNCED_DAYS_AHEAD as i64);
+    days.clamp(1, MAX_ANNOUNCED_DAYS_AHEAD as i64) as u32
+}
 
-    let dates = presence
-        .announcements
-        .iter()
-        .filter_map(|a|{
-            let date;
-            match a.kind {
-                PresenceAnnouncementKind::RecurringAnnouncement => {
-                    let day_offset = a.date.signed_duration_since(now_date).num_days();
-                    if day_offset >= 0 {
-                        date = a.date;
-                    } else {
-                        date = if let Some(adjusted_date) = now_date.checked_add_days(Days::new(((day_offset % 7)+7) as u64)) {
-                            adjusted_date
-                        } else {
-                            return None
-                        }
-                    }
-                }
-                PresenceAnnouncementKind::SingularAnnouncement =>
-                    date = a.date,
-            }
-            Some((date, a.kind))
-        })
-        .collect::<HashMap<_,_>>();
+/// The kind of announcement the given announcements make for the given
+/// date. Recurring announcements apply to their date and every week after,
+/// until their end date.
+fn announcement_kind_on(announcements: &[PresenceAnnouncement], date: NaiveDate) -> Option<PresenceAnnouncementKind> {
+    announcements.iter()
+    .find(|a| match a.kind {
+        PresenceAnnouncementKind::SingularAnnouncement => a.date == date,
+        PresenceAnnouncementKind::RecurringAnnouncement => {
+            let days_since = date.signed_duration_since(a.date).num_days();
+            days_since >= 0 
+            && days_since % 7 == 0 
+            && !a.recurring_until.is_some_and(|until| date > until)
+        }
+    })
+    .map(|a| a.kind)
+}
+
+fn to_person_model(presence: &Presence, days_ahead: u32, now_date: NaiveDate) -> PersonModel {
 
-    let announcements = (0..ANNOUNCED_DAYS_AHEAD)
-        .into_iter()
+    let announcements = (0..days_ahead)
         .map(|n| {
             let announcement = now_date
                 .checked_add_days(Days::new(n as u64))
-                .and_then(|date| dates.get(&date));
+                .and_then(|date| announcement_kind_on(&presence.announcements, date));
             match announcement {
-                Some(kind) => match kind {
-                    &PresenceAnnouncementKind::SingularAnnouncement => AnnouncementModel::PresenceAnnounced,
-                    &PresenceAnnouncementKind::RecurringAnnouncement => AnnouncementModel::RecurringPresenceAnnounced,
-                },
-                None =>           
-                    AnnouncementModel::NotAnnounced
+                Some(PresenceAnnouncementKind::SingularAnnouncement) => AnnouncementModel::PresenceAnnounced,
+                Some(PresenceAnnouncementKind::RecurringAnnouncement) => AnnouncementModel::RecurringPresenceAnnounced,
+                None => AnnouncementModel::NotAnnounced,
             }
         })
         .collect::<Vec<_>>();
@@ -350,3 +535,30 @@ fn to_person_model(presence: &Presence) -> PersonModel {
         is_self: presence.is_self,
     }
 }
+
+#[test]
+fn test_recurring_announcements_repeat_weekly() {
+    let start = NaiveDate::from_ymd_opt(2024, 5, 6).unwrap();
+    let announcements = [
+        PresenceAnnouncement {
+            date: start,
+            kind: PresenceAnnouncementKind::RecurringAnnouncement,
+            recurring_until: NaiveDate::from_ymd_opt(2024, 5, 20),
+        },
+        PresenceAnnouncement {
+            date: start + Days::new(1),
+            kind: PresenceAnnouncementKind::SingularAnnouncement,
+            recurring_until: None,
+        },
+    ];
+
+    let kind_on = |days: u64| announcement_kind_on(&announcements, start + Days::new(days));
+    assert_eq!(kind_on(0), Some(PresenceAnnouncementKind::RecurringAnnouncement));
+    assert_eq!(kind_on(7), Some(PresenceAnnouncementKind::RecurringAnnouncement));
+    assert_eq!(kind_on(14), Some(PresenceAnnouncementKind::RecurringAnnouncement));
+    // past the end date
+    assert_eq!(kind_on(21), None);
+    assert_eq!(kind_on(1), Some(PresenceAnnouncementKind::SingularAnnouncement));
+    assert_eq!(kind_on(8), None);
+    assert_eq!(announcement_kind_on(&announcements, start - Days::new(7)), None);
+}
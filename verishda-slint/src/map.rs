@@ -0,0 +1,198 @@
+use std::{collections::HashMap, f64::consts::PI, sync::{Arc, Mutex}};
+
+use anyhow::{anyhow, Result};
+use slint::{ModelRc, Rgba8Pixel, SharedPixelBuffer, VecModel, Weak};
+use verishda_config::Config;
+
+use crate::{core::verishda_dto::types::Site, update_main_window, AppUI, MainWindow, MapTileModel, SiteMarkerModel};
+
+/// edge length of map tiles in pixels
+const TILE_SIZE: f64 = 256.;
+
+const DEFAULT_ZOOM: u8 = 12;
+const MAX_ZOOM: u8 = 19;
+
+/// Settings for the site map, read from the config:
+/// * `SHOW_MAP`: show the map instead of the site selection box
+/// * `MAP_DEFAULT_CENTER`: map center as `<latitude>,<longitude>`;
+///   defaults to the center of all sites
+/// * `MAP_DEFAULT_ZOOM`: zoom level (0-19) as used by tile servers
+/// * `MAP_TILE_URL`: URL template for map tiles, containing `{z}`, `{x}`
+///   and `{y}` placeholders. Without it, sites are plotted on a blank map.
+#[derive(Clone)]
+pub struct MapSettings {
+    pub show_map: bool,
+    center: Option<(f64, f64)>,
+    zoom: u8,
+    tile_url: Option<String>,
+    tile_cache: Arc<Mutex<HashMap<String, SharedPixelBuffer<Rgba8Pixel>>>>,
+}
+
+impl MapSettings {
+    pub fn from_config(config: &impl Config) -> Self {
+        let center = config.get("MAP_DEFAULT_CENTER").ok()
+        .and_then(|c| match parse_center(&c) {
+            Ok(center) => Some(center),
+            Err(e) => {
+                log::error!("ignoring invalid MAP_DEFAULT_CENTER: {e}");
+                None
+            }
+        });
+        let zoom = config.get("MAP_DEFAULT_ZOOM").ok()
+        .and_then(|z| z.parse::<u8>().ok())
+        .unwrap_or(DEFAULT_ZOOM)
+        .min(MAX_ZOOM);
+
+        Self {
+            show_map: config.get_as_bool_or("SHOW_MAP", false),
+            center,
+            zoom,
+            tile_url: config.get("MAP_TILE_URL").ok(),
+            tile_cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+fn parse_center(center: &str) -> Result<(f64, f64)> {
+    let (latitude, longitude) = center
+        .split_once(',')
+        .ok_or_else(|| anyhow!("expected '<latitude>,<longitude>', got '{center}'"))?;
+    Ok((latitude.trim().parse()?, longitude.trim().parse()?))
+}
+
+/// Position of the given coordinates in pixels on the Web Mercator
+/// projected world map at the given zoom level (the projection used by
+/// common tile servers like OpenStreetMap)
+fn world_pixel(latitude: f64, longitude: f64, zoom: u8) -> (f64, f64) {
+    let scale = TILE_SIZE * 2f64.powi(zoom as i32);
+    let φ = latitude.to_radians();
+    let x = (longitude + 180.) / 360. * scale;
+    let y = (1. - (φ.tan() + 1. / φ.cos()).ln() / PI) / 2. * scale;
+    (x, y)
+}
+
+fn map_center(settings: &MapSettings, sites: &[Site]) -> (f64, f64) {
+    if let Some(center) = settings.center {
+        return center;
+    }
+    if sites.is_empty() {
+        return (0., 0.);
+    }
+    let n = sites.len() as f64;
+    let latitude = sites.iter().map(|s| s.latitude as f64).sum::<f64>() / n;
+    let longitude = sites.iter().map(|s| s.longitude as f64).sum::<f64>() / n;
+    (latitude, longitude)
+}
+
+/// Plot the given sites on the map and load the tiles around the map center
+pub fn update_site_map(settings: &MapSettings, sites: &[Site], main_window_weak: Weak<MainWindow>) {
+    let (center_latitude, center_longitude) = map_center(settings, sites);
+    let (cx, cy) = world_pixel(center_latitude, center_longitude, settings.zoom);
+
+    let markers = sites.iter()
+    .map(|site| {
+        let (x, y) = world_pixel(site.latitude as f64, site.longitude as f64, settings.zoom);
+        SiteMarkerModel {
+            id: site.id.clone().into(),
+            name: site.name.clone().into(),
+            x: (x - cx) as f32,
+            y: (y - cy) as f32,
+        }
+    })
+    .collect::<Vec<_>>();
+
+    update_main_window(&main_window_weak, move |main_window| {
+        let app_ui = main_window.global::<AppUI>();
+        app_ui.set_site_markers(ModelRc::new(VecModel::from(markers)));
+    });
+
+    let tile_url = match &settings.tile_url {
+        Some(tile_url) => tile_url.clone(),
+        None => return,
+    };
+
+    // load the 3x3 tiles surrounding the tile containing the center
+    let tile_count = 1i64 << settings.zoom;
+    let center_tile_x = (cx / TILE_SIZE).floor() as i64;
+    let center_tile_y = (cy / TILE_SIZE).floor() as i64;
+    let mut tiles = Vec::new();
+    for tile_y in center_tile_y - 1..=center_tile_y + 1 {
+        if tile_y < 0 || tile_y >= tile_count {
+            continue;
+        }
+        for tile_x in center_tile_x - 1..=center_tile_x + 1 {
+            let url = tile_url
+                .replace("{z}", &settings.zoom.to_string())
+                .replace("{x}", &tile_x.rem_euclid(tile_count).to_string())
+                .replace("{y}", &tile_y.to_string());
+            let offset = (tile_x as f64 * TILE_SIZE - cx, tile_y as f64 * TILE_SIZE - cy);
+            tiles.push((url, offset));
+        }
+    }
+
+    let tile_cache = settings.tile_cache.clone();
+    tokio::spawn(async move {
+        let mut loaded_tiles = Vec::new();
+        for (url, offset) in tiles {
+            let cached = tile_cache.lock().unwrap().get(&url).cloned();
+            let buffer = match cached {
+                Some(buffer) => buffer,
+                None => match fetch_tile(&url).await {
+                    Ok(buffer) => {
+                        tile_cache.lock().unwrap().insert(url, buffer.clone());
+                        buffer
+                    }
+                    Err(e) => {
+                        log::error!("failed to load map tile {url}: {e}");
+                        continue;
+                    }
+                }
+            };
+            loaded_tiles.push((buffer, offset));
+        }
+
+        update_main_window(&main_window_weak, move |main_window| {
+            let tiles = loaded_tiles.into_iter()
+            .map(|(buffer, (x, y))| MapTileModel {
+                image: slint::Image::from_rgba8(buffer),
+                x: x as f32,
+                y: y as f32,
+            })
+            .collect::<Vec<_>>();
+            let app_ui = main_window.global::<AppUI>();
+            app_ui.set_map_tiles(ModelRc::new(VecModel::from(tiles)));
+        });
+    });
+}
+
+async fn fetch_tile(url: &str) -> Result<SharedPixelBuffer<Rgba8Pixel>> {
+    // tile servers like OpenStreetMap's require clients to identify themselves
+    let bytes = reqwest::Client::new()
+        .get(url)
+        .header(reqwest::header::USER_AGENT, concat!("Verishda/", env!("CARGO_PKG_VERSION")))
+        .send().await?
+        .error_for_status()?
+        .bytes().await?;
+    let image = image::load_from_memory(&bytes)?.into_rgba8();
+    Ok(SharedPixelBuffer::clone_from_slice(image.as_raw(), image.width(), image.height()))
+}
+
+#[test]
+fn test_world_pixel() {
+    // the null island is at the center of the world map
+    let (x, y) = world_pixel(0., 0., 1);
+    assert!((x - TILE_SIZE).abs() < 1e-9);
+    assert!((y - TILE_SIZE).abs() < 1e-9);
+
+    // Reutlingen is on tile 2152/1415 at zoom level 12
+    let (x, y) = world_pixel(48.4883438, 9.2146156, 12);
+    assert_eq!((x / TILE_SIZE).floor(), 2152.);
+    assert_eq!((y / TILE_SIZE).floor(), 1415.);
+}
+
+#[test]
+fn test_parse_center() {
+    assert_eq!(parse_center("48.5, 9.2").unwrap(), (48.5, 9.2));
+    assert!(parse_center("48.5").is_err());
+    assert!(parse_center("north,east").is_err());
+}
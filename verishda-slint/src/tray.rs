@@ -0,0 +1,124 @@
+use slint::{ComponentHandle, Weak};
+
+use crate::{core::AppCoreRef, MainWindow};
+
+/// Whether this platform gets a tray icon. On Linux, `tray-icon` needs a
+/// GTK main loop, which the Slint backend doesn't run.
+pub const TRAY_SUPPORTED: bool = cfg!(any(target_os = "windows", target_os = "macos"));
+
+/// Install the tray icon with its menu once the event loop is running 
+/// (macOS requires the tray icon to be created on the main thread after 
+/// the event loop started). Does nothing where trays are not supported.
+pub fn install_tray_icon(app_core: AppCoreRef, main_window_weak: Weak<MainWindow>) {
+    if !TRAY_SUPPORTED {
+        return;
+    }
+    slint::Timer::single_shot(std::time::Duration::ZERO, move || {
+        if let Err(e) = imp::install(app_core, main_window_weak) {
+            log::error!("failed to create tray icon: {e}");
+        }
+    });
+}
+
+/// Show the main window if it is hidden, hide it otherwise
+fn toggle_main_window(main_window_weak: &Weak<MainWindow>) {
+    let Some(main_window) = main_window_weak.upgrade() else {
+        return;
+    };
+    let result = if main_window.window().is_visible() {
+        main_window.hide()
+    } else {
+        main_window.show()
+    };
+    if let Err(e) = result {
+        log::error!("failed to toggle main window: {e}");
+    }
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+mod imp {
+    use std::{cell::RefCell, time::Duration};
+
+    use anyhow::Result;
+    use slint::{ComponentHandle, Weak};
+    use tray_icon::{menu::{Menu, MenuEvent, MenuItem, PredefinedMenuItem}, Icon, MouseButton, MouseButtonState, TrayIcon, TrayIconBuilder, TrayIconEvent};
+
+    use crate::{core::AppCoreRef, MainWindow};
+
+    /// how often tray and menu events are checked for
+    const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    thread_local! {
+        // the tray icon disappears when dropped, and the timer stops, so
+        // both are kept for the lifetime of the event loop thread
+        static TRAY: RefCell<Option<(TrayIcon, slint::Timer)>> = RefCell::new(None);
+    }
+
+    fn load_icon() -> Result<Icon> {
+        let image = image::load_from_memory(include_bytes!("../ui/icons/logo.png"))?.into_rgba8();
+        let (width, height) = image.dimensions();
+        Ok(Icon::from_rgba(image.into_raw(), width, height)?)
+    }
+
+    pub(super) fn install(app_core: AppCoreRef, main_window_weak: Weak<MainWindow>) -> Result<()> {
+        let toggle_item = MenuItem::new("Show/Hide Window", true, None);
+        let refresh_item = MenuItem::new("Refresh", true, None);
+        let quit_item = MenuItem::new("Quit", true, None);
+        let menu = Menu::new();
+        menu.append_items(&[
+            &toggle_item,
+            &refresh_item,
+            &PredefinedMenuItem::separator(),
+            &quit_item,
+        ])?;
+
+        let tray_icon = TrayIconBuilder::new()
+            .with_menu(Box::new(menu))
+            .with_tooltip("Verishda")
+            .with_icon(load_icon()?)
+            .build()?;
+
+        // tray-icon delivers events through global channels, which we check
+        // for in the event loop so that we can access the window directly
+        let timer = slint::Timer::default();
+        timer.start(slint::TimerMode::Repeated, EVENT_POLL_INTERVAL, move || {
+            while let Ok(event) = MenuEvent::receiver().try_recv() {
+                if event.id == *toggle_item.id() {
+                    super::toggle_main_window(&main_window_weak);
+                } else if event.id == *refresh_item.id() {
+                    log::info!("refresh requested from tray");
+                    app_core.refresh();
+                } else if event.id == *quit_item.id() {
+                    log::info!("quit requested from tray");
+                    if let Err(e) = slint::quit_event_loop() {
+                        log::error!("failed to quit event loop: {e}");
+                    }
+                }
+            }
+            while let Ok(event) = TrayIconEvent::receiver().try_recv() {
+                if let TrayIconEvent::Click { button: MouseButton::Left, button_state: MouseButtonState::Up, .. } = event {
+                    if let Some(main_window) = main_window_weak.upgrade() {
+                        if let Err(e) = main_window.show() {
+                            log::error!("failed to show main window: {e}");
+                        }
+                    }
+                }
+            }
+        });
+
+        TRAY.with(|tray| *tray.borrow_mut() = Some((tray_icon, timer)));
+        log::info!("tray icon installed");
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+mod imp {
+    use slint::Weak;
+
+    use crate::{core::AppCoreRef, MainWindow};
+
+    pub(super) fn install(_app_core: AppCoreRef, _main_window_weak: Weak<MainWindow>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}